@@ -7,6 +7,7 @@
 let
   inherit (builtins)
     storeDir
+    toJSON
     getContext
     tryEval
     trace
@@ -322,6 +323,13 @@ let
     };
 
     flack = {
+      # An error that routes can throw to respond with an HTTP status instead of a 500,
+      # e.g. `throw (httpError 403 "Forbidden")`. flack-serve reads the thrown JSON.
+      httpError = status: message: {
+        inherit status message;
+        __toString = self: toJSON { inherit (self) status message; };
+      };
+
       # Creates a Flack request object from the Flack environment.
       mkReq =
         app: env:
@@ -337,6 +345,8 @@ let
             inherit app env;
             params = { };
             body = env."flack.body" or { };
+            input = env."flack.input" or null;
+            files = env."flack.files" or { };
             host = env.HTTP_HOST;
             method = env.REQUEST_METHOD;
             path = env.PATH_INFO;
@@ -418,6 +428,8 @@ let
             # Flack attributes.
             id = env."flack.request_id";
             timestamp = env."flack.request_timestamp";
+            timestampMs = env."flack.request_timestamp_ms";
+            headers = env."flack.headers" or { };
             system = env."flack.system";
             overrideInput = input: env."flack.override.${input}" or null;
           };
@@ -438,8 +450,12 @@ let
           "rack.url_scheme" = impure;
           "flack.request_id" = impure;
           "flack.request_timestamp" = impure;
+          "flack.request_timestamp_ms" = impure;
+          "flack.headers" = impure;
           "flack.system" = system;
           "flack.body" = impure;
+          "flack.input" = impure;
+          "flack.files" = impure;
         };
 
       # Returns the closure for the given system and store paths without using nixpkgs,
@@ -28,6 +28,10 @@ let
       serverCfg.port
     ]
     ++ lib.optional (!serverCfg.substituteOnPreload) "--no-preload-substitute"
+    ++ lib.optionals (serverCfg.workers > 0) [
+      "--workers"
+      serverCfg.workers
+    ]
     ++ serverCfg.extraArgs;
 
   cfg = config.services.flack;
@@ -77,6 +81,12 @@ in
                 description = "Enable substitution during app preload";
               };
 
+              workers = mkOption {
+                type = types.ints.unsigned;
+                default = 0;
+                description = "Number of sandboxed evaluator processes; 0 evaluates in the server process";
+              };
+
               extraArgs = mkOption {
                 type = with types; listOf str;
                 default = [ ];
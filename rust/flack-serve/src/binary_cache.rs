@@ -0,0 +1,358 @@
+//! A Nix binary cache of what flack-serve has built.
+//!
+//! With `--binary-cache`, flack-serve answers `/nix-cache-info`, `/<hash>.narinfo` and
+//! `/nar/<hash>.nar.zst` for the closures of store paths it has served or preloaded, and
+//! nothing else in the store. NARs are compressed as they're sent, and narinfos are signed
+//! with `--secret-key-file`, so clients can `nix copy --from` the server.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use anyhow::{Context as _, anyhow, bail};
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ed25519_dalek::{Signer as _, SigningKey};
+use log::{debug, warn};
+
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse, web};
+
+use nix_bindings_store::path::StorePath;
+use nix_bindings_store::store::Store;
+
+use crate::{ChannelBody, FlackApp, FlackResponse, build_response};
+
+/// The characters of Nix's base-32 encoding.
+const NIX32: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// The length of a store path's hash part.
+const HASH_LEN: usize = 32;
+
+/// The zstd level to compress NARs at. Higher levels cost more CPU than the bandwidth they save.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Where clients rank us among their substituters. cache.nixos.org is 40, so it comes first.
+const PRIORITY: u32 = 50;
+
+/// A key to sign narinfos with.
+pub struct SecretKey {
+    name: String,
+    key: SigningKey,
+}
+
+/// Implementation for signing keys.
+impl SecretKey {
+    /// Reads a key in the format `nix key generate-secret` writes: `name:base64`.
+    pub fn read(path: &Path) -> anyhow::Result<SecretKey> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read secret key {:?}", path))?;
+        let (name, key) = contents
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| anyhow!("secret key {:?} has no name", path))?;
+        let bytes = BASE64.decode(key).context("secret key is not base64")?;
+        let key = match <[u8; 64]>::try_from(bytes.as_slice()) {
+            Ok(keypair) => SigningKey::from_keypair_bytes(&keypair)?,
+            Err(_) => bail!("secret key is {} bytes, not 64", bytes.len()),
+        };
+        Ok(SecretKey {
+            name: name.to_string(),
+            key,
+        })
+    }
+
+    /// Signs a narinfo fingerprint.
+    fn sign(&self, fingerprint: &str) -> String {
+        let signature = self.key.sign(fingerprint.as_bytes());
+        format!("{}:{}", self.name, BASE64.encode(signature.to_bytes()))
+    }
+}
+
+/// The store paths in the binary cache.
+pub struct BinaryCache {
+    store_dir: String,
+    key: Option<SecretKey>,
+
+    /// Store path base names, like `<hash>-<name>`, by hash part.
+    paths: RwLock<HashMap<String, String>>,
+}
+
+/// Implementation for the binary cache.
+impl BinaryCache {
+    /// Creates an empty binary cache for the given store directory.
+    pub fn new(store_dir: String, key: Option<SecretKey>) -> BinaryCache {
+        BinaryCache {
+            store_dir,
+            key,
+            paths: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a store path and its closure.
+    pub fn add(&self, store: &mut Store, path: &StorePath) -> anyhow::Result<()> {
+        let name = base_name(store, path)?;
+        if self.lookup(&name[..HASH_LEN]).is_some() {
+            return Ok(());
+        }
+
+        let closure = store.get_fs_closure(path, false, false, false)?;
+        let mut names = Vec::with_capacity(closure.len() + 1);
+        names.push(name);
+        for path in &closure {
+            names.push(base_name(store, path)?);
+        }
+
+        let mut paths = self.paths.write().map_err(|err| anyhow!(err.to_string()))?;
+        for name in names {
+            paths.insert(name[..HASH_LEN].to_string(), name);
+        }
+        debug!("Binary cache has {} paths", paths.len());
+        Ok(())
+    }
+
+    /// Returns the base name of the store path with the given hash part, if it's in the cache.
+    fn lookup(&self, hash: &str) -> Option<String> {
+        self.paths.read().ok()?.get(hash).cloned()
+    }
+
+    /// Returns the narinfo for the store path with the given base name.
+    fn narinfo(&self, store: &mut Store, name: &str) -> anyhow::Result<String> {
+        let store_path = format!("{}/{}", self.store_dir, name);
+        let path = store.parse_store_path(&store_path)?;
+        let info = store.query_path_info(&path)?;
+        let nar_hash = nix32_hash(&info.nar_hash)?;
+
+        let mut references = info
+            .references
+            .iter()
+            .map(|path| base_name(store, path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        references.sort();
+
+        let mut narinfo = format!(
+            "StorePath: {}\nURL: nar/{}.nar.zst\nCompression: zstd\nNarHash: {}\nNarSize: {}\nReferences: {}\n",
+            store_path,
+            &name[..HASH_LEN],
+            nar_hash,
+            info.nar_size,
+            references.join(" ")
+        );
+        if let Some(deriver) = &info.deriver {
+            narinfo.push_str(&format!("Deriver: {}\n", base_name(store, deriver)?));
+        }
+        for signature in &info.signatures {
+            narinfo.push_str(&format!("Sig: {}\n", signature));
+        }
+        if let Some(key) = &self.key {
+            // This is what Nix checks signatures against.
+            let fingerprint = format!(
+                "1;{};{};{};{}",
+                store_path,
+                nar_hash,
+                info.nar_size,
+                references
+                    .iter()
+                    .map(|name| format!("{}/{}", self.store_dir, name))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+            narinfo.push_str(&format!("Sig: {}\n", key.sign(&fingerprint)));
+        }
+        if let Some(ca) = &info.ca {
+            narinfo.push_str(&format!("CA: {}\n", ca));
+        }
+        Ok(narinfo)
+    }
+}
+
+/// Returns the `<hash>-<name>` base name of a store path.
+/// The real path is the only way to print one, and for chroot stores it's only the directory that differs.
+fn base_name(store: &mut Store, path: &StorePath) -> anyhow::Result<String> {
+    let real_path = store.real_path(path)?;
+    Path::new(&real_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| name.len() > HASH_LEN)
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("{:?} is not a store path", real_path))
+}
+
+/// Encodes bytes in Nix's base-32, which starts from the end.
+fn nix32(bytes: &[u8]) -> String {
+    let len = (bytes.len() * 8 - 1) / 5 + 1;
+    (0..len)
+        .rev()
+        .map(|n| {
+            let (i, j) = (n * 5 / 8, n * 5 % 8);
+            let low = (bytes[i] as u16) >> j;
+            let high = bytes.get(i + 1).map_or(0, |b| (*b as u16) << (8 - j));
+            NIX32[((low | high) & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+/// Converts an SRI hash, like `sha256-<base64>`, to the `sha256:<nix32>` form narinfos use.
+fn nix32_hash(sri: &str) -> anyhow::Result<String> {
+    let (algo, hash) = sri
+        .split_once('-')
+        .ok_or_else(|| anyhow!("{:?} is not an SRI hash", sri))?;
+    Ok(format!("{}:{}", algo, nix32(&BASE64.decode(hash)?)))
+}
+
+/// Adds store paths and their closures to the binary cache, if it's on.
+pub async fn add(app: &web::Data<FlackApp>, store_paths: Vec<String>) {
+    let Some(cache) = app.binary_cache.clone() else {
+        return;
+    };
+
+    let app = app.clone();
+    let result = web::block(move || -> anyhow::Result<()> {
+        let mut store = app
+            .state
+            .lock()
+            .map_err(|err| anyhow!(err.to_string()))?
+            .store()
+            .clone();
+        for store_path in store_paths {
+            let path = store.parse_store_path(&store_path)?;
+            cache.add(&mut store, &path)?;
+        }
+        Ok(())
+    })
+    .await;
+
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => warn!("Couldn't add to the binary cache: {}", err),
+        Err(err) => warn!("Couldn't add to the binary cache: {}", err),
+    }
+}
+
+/// Serves `/nix-cache-info`.
+pub async fn cache_info(req: HttpRequest) -> HttpResponse {
+    let app = req.app_data::<web::Data<FlackApp>>().unwrap().clone();
+    let Some(cache) = &app.binary_cache else {
+        return build_response(
+            req,
+            &FlackResponse::new().not_found("the binary cache is disabled"),
+        )
+        .await;
+    };
+
+    let mut response = FlackResponse::new();
+    response.add_header(
+        header::CONTENT_TYPE.to_string(),
+        "text/x-nix-cache-info".to_string(),
+    );
+    let response = response.string(
+        200,
+        format!(
+            "StoreDir: {}\nWantMassQuery: 0\nPriority: {}\n",
+            cache.store_dir, PRIORITY
+        ),
+    );
+    build_response(req, &response).await
+}
+
+/// Serves `/<hash>.narinfo`.
+pub async fn narinfo(req: HttpRequest, hash: web::Path<String>) -> HttpResponse {
+    let app = req.app_data::<web::Data<FlackApp>>().unwrap().clone();
+    let Some((cache, name)) = app
+        .binary_cache
+        .clone()
+        .and_then(|cache| cache.lookup(&hash).map(|name| (cache, name)))
+    else {
+        return build_response(req, &FlackResponse::new().not_found("no such store path")).await;
+    };
+
+    let state = app.clone();
+    let result = web::block(move || -> anyhow::Result<String> {
+        let mut store = state
+            .state
+            .lock()
+            .map_err(|err| anyhow!(err.to_string()))?
+            .store()
+            .clone();
+        cache.narinfo(&mut store, &name)
+    })
+    .await;
+
+    let response = match result {
+        Ok(Ok(narinfo)) => {
+            let mut response = FlackResponse::new();
+            response.add_header(
+                header::CONTENT_TYPE.to_string(),
+                "text/x-nix-narinfo".to_string(),
+            );
+            response.string(200, narinfo)
+        }
+        Ok(Err(err)) => FlackResponse::new().server_error(err),
+        Err(err) => FlackResponse::new().server_error(err),
+    };
+    build_response(req, &response).await
+}
+
+/// Sends what's written to it as a response body.
+struct ChannelWriter(tokio::sync::mpsc::Sender<std::io::Result<web::Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(web::Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::other("client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serves `/nar/<hash>.nar.zst`, compressing the NAR as it's dumped.
+pub async fn nar(req: HttpRequest, hash: web::Path<String>) -> HttpResponse {
+    let app = req.app_data::<web::Data<FlackApp>>().unwrap().clone();
+    let Some((cache, name)) = app
+        .binary_cache
+        .clone()
+        .and_then(|cache| cache.lookup(&hash).map(|name| (cache, name)))
+    else {
+        return build_response(req, &FlackResponse::new().not_found("no such store path")).await;
+    };
+
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    actix_web::rt::task::spawn_blocking(move || {
+        let result = (|| -> anyhow::Result<()> {
+            let mut store = app
+                .state
+                .lock()
+                .map_err(|err| anyhow!(err.to_string()))?
+                .store()
+                .clone();
+            let path = store.parse_store_path(&format!("{}/{}", cache.store_dir, name))?;
+            let mut encoder =
+                zstd::stream::write::Encoder::new(ChannelWriter(tx.clone()), ZSTD_LEVEL)?;
+            std::io::copy(&mut store.nar_reader(&path), &mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        })();
+        if let Err(err) = result {
+            warn!("Error sending the NAR of {}: {}", name, err);
+            let _ = tx.blocking_send(Err(std::io::Error::other(err)));
+        }
+    });
+
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "application/x-nix-nar"))
+        .body(ChannelBody { rx, size: None })
+}
+
+/// Reads the secret key, if there is one, and sets up the binary cache.
+pub fn init(store: &mut Store, secret_key_file: Option<&Path>) -> anyhow::Result<Arc<BinaryCache>> {
+    let key = secret_key_file.map(SecretKey::read).transpose()?;
+    if key.is_none() {
+        warn!("The binary cache has no --secret-key-file, so clients must not require signatures");
+    }
+    Ok(Arc::new(BinaryCache::new(store.get_storedir()?, key)))
+}
@@ -0,0 +1,299 @@
+//! The response cache.
+//!
+//! Apps opt routes into caching by setting `Cache-Control` with a `max-age` or `s-maxage`,
+//! and list any request headers the response depends on in `Vary`, like any shared cache
+//! would expect. Entries are keyed on the method, path, query, the varying headers, and
+//! the narHash of the locked flake (or an ID of their own for imported projects), and are
+//! bounded by total body size and a maximum TTL.
+//!
+//! Responses that set cookies are never cached. Responses to requests with credentials
+//! (`Authorization` or `Cookie`) are only cached, and requests with credentials are only
+//! answered from the cache, if the response is marked `public` or has an `s-maxage`, or
+//! varies on those credentials.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use actix_web::HttpRequest;
+use actix_web::http::{Method, header};
+
+use crate::FlackResponse;
+
+/// Request headers that carry credentials.
+const CREDENTIALS: &[&str] = &["authorization", "cookie"];
+
+/// A cached response.
+struct Entry {
+    vary: Vec<(String, Option<String>)>,
+
+    /// Whether the response said it may be shared between users.
+    shared: bool,
+
+    response: FlackResponse,
+    id: u64,
+    stored: Instant,
+    expires: Instant,
+    size: usize,
+}
+
+/// The entries, grouped by key.
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<String, Vec<Entry>>,
+
+    /// The key of every entry, oldest first, so evicting one doesn't mean scanning them all.
+    by_age: BTreeMap<(Instant, u64), String>,
+
+    next_id: u64,
+    size: usize,
+}
+
+/// A size- and TTL-bounded cache of app responses, shared by all Actix workers.
+pub struct ResponseCache {
+    max_size: usize,
+    max_ttl: Duration,
+    nar_hash: String,
+    entries: Mutex<Entries>,
+}
+
+/// Implementation for response caches.
+impl ResponseCache {
+    /// Creates a new response cache for the app with the given narHash, or other unique ID.
+    pub fn new(max_size: usize, max_ttl: Duration, nar_hash: String) -> ResponseCache {
+        ResponseCache {
+            max_size,
+            max_ttl,
+            nar_hash,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Returns the cache key for a request, if it's one we cache.
+    fn key(&self, req: &HttpRequest) -> Option<String> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return None;
+        }
+        Some(format!(
+            "{} {} {}?{}",
+            self.nar_hash,
+            req.method(),
+            req.path(),
+            req.query_string()
+        ))
+    }
+
+    /// Looks up a fresh response for a request.
+    pub fn get(&self, req: &HttpRequest) -> Option<FlackResponse> {
+        let key = self.key(req)?;
+        let now = Instant::now();
+        let entries = self.entries.lock().ok()?;
+        let entry = entries.by_key.get(&key)?.iter().find(|entry| {
+            entry.expires > now
+                && entry
+                    .vary
+                    .iter()
+                    .all(|(name, value)| request_header(req, name) == *value)
+                && may_share(req, entry.shared, &entry.vary)
+        })?;
+
+        debug!("Cache hit for {}", key);
+        let mut response = entry.response.clone();
+        response.start = now;
+        response.add_header(
+            header::AGE.to_string(),
+            now.saturating_duration_since(entry.stored)
+                .as_secs()
+                .to_string(),
+        );
+        Some(response)
+    }
+
+    /// Stores a response to a request, if the app allowed it.
+    pub fn put(&self, req: &HttpRequest, response: &FlackResponse) {
+        let Some(key) = self.key(req) else {
+            return;
+        };
+        if response.code != 200
+            || response.error.is_some()
+            || response.header(header::SET_COOKIE).is_some()
+        {
+            return;
+        }
+        let Some((ttl, shared)) = response
+            .header(header::CACHE_CONTROL)
+            .and_then(parse_cache_control)
+            .map(|(ttl, shared)| (ttl.min(self.max_ttl), shared))
+            .filter(|(ttl, _)| !ttl.is_zero())
+        else {
+            return;
+        };
+
+        let mut vary = Vec::new();
+        if let Some(names) = response.header(header::VARY) {
+            for name in names
+                .split(',')
+                .map(|name| name.trim().to_ascii_lowercase())
+            {
+                if name == "*" {
+                    return;
+                } else if !name.is_empty() {
+                    let value = request_header(req, &name);
+                    vary.push((name, value));
+                }
+            }
+        }
+
+        if !may_share(req, shared, &vary) {
+            debug!("Not caching {}, since the request has credentials", key);
+            return;
+        }
+
+        let size = key.len() + response.body.as_ref().map_or(0, String::len);
+        if size > self.max_size {
+            return;
+        }
+
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        entries.remove(|entry| entry.expires <= now);
+        entries.remove_where(&key, |entry| entry.vary == vary);
+        while entries.size + size > self.max_size {
+            let Some(((_, id), oldest)) = entries.by_age.pop_first() else {
+                break;
+            };
+            entries.remove_where(&oldest, |entry| entry.id == id);
+        }
+
+        debug!("Caching {} for {}s", key, ttl.as_secs());
+        let id = entries.next_id;
+        entries.next_id += 1;
+        entries.size += size;
+        entries.by_age.insert((now, id), key.clone());
+        entries.by_key.entry(key).or_default().push(Entry {
+            vary,
+            shared,
+            response: response.clone(),
+            id,
+            stored: now,
+            expires: now + ttl,
+            size,
+        });
+    }
+
+    /// Purges every entry whose path starts with the given prefix, or everything.
+    /// Returns the number of entries purged.
+    pub fn purge(&self, prefix: Option<&str>) -> usize {
+        let Ok(mut entries) = self.entries.lock() else {
+            return 0;
+        };
+        let keys: Vec<String> = entries
+            .by_key
+            .keys()
+            .filter(|key| {
+                prefix.is_none_or(|prefix| {
+                    key.split(' ')
+                        .nth(2)
+                        .is_some_and(|path| path.starts_with(prefix))
+                })
+            })
+            .cloned()
+            .collect();
+
+        let mut purged = 0;
+        for key in keys {
+            purged += entries.remove_where(&key, |_| true);
+        }
+        purged
+    }
+}
+
+/// Implementation for cache entries.
+impl Entries {
+    /// Removes the entries for a key that match a predicate, returning how many there were.
+    fn remove_where(&mut self, key: &str, pred: impl Fn(&Entry) -> bool) -> usize {
+        let Some(list) = self.by_key.get_mut(key) else {
+            return 0;
+        };
+        let before = list.len();
+        let mut freed = 0;
+        let by_age = &mut self.by_age;
+        list.retain(|entry| {
+            if pred(entry) {
+                freed += entry.size;
+                by_age.remove(&(entry.stored, entry.id));
+                false
+            } else {
+                true
+            }
+        });
+        let removed = before - list.len();
+        if list.is_empty() {
+            self.by_key.remove(key);
+        }
+        self.size -= freed;
+        removed
+    }
+
+    /// Removes all entries that match a predicate.
+    fn remove(&mut self, pred: impl Fn(&Entry) -> bool) {
+        let keys: Vec<String> = self.by_key.keys().cloned().collect();
+        for key in keys {
+            self.remove_where(&key, &pred);
+        }
+    }
+}
+
+/// Returns the value of a request header, joining repeated headers.
+fn request_header(req: &HttpRequest, name: &str) -> Option<String> {
+    let values: Vec<&str> = req
+        .headers()
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+/// Returns true if a response may answer a request, as far as its credentials go:
+/// either the request has none, or the response is shared or varies on each of them.
+fn may_share(req: &HttpRequest, shared: bool, vary: &[(String, Option<String>)]) -> bool {
+    shared
+        || CREDENTIALS.iter().all(|credential| {
+            !req.headers().contains_key(*credential)
+                || vary.iter().any(|(name, _)| name == credential)
+        })
+}
+
+/// Returns how long a response may be cached for, going by its Cache-Control, and whether
+/// it may be shared between users. Responses without an explicit lifetime aren't cached.
+fn parse_cache_control(value: &str) -> Option<(Duration, bool)> {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    let mut public = false;
+    for directive in value.split(',') {
+        let directive = directive.trim().to_ascii_lowercase();
+        let (name, arg) = match directive.split_once('=') {
+            Some((name, arg)) => (name.trim(), Some(arg.trim().trim_matches('"'))),
+            None => (directive.as_str(), None),
+        };
+        match name {
+            "no-store" | "no-cache" | "private" => return None,
+            "max-age" => max_age = arg.and_then(|arg| arg.parse::<u64>().ok()),
+            "s-maxage" => s_maxage = arg.and_then(|arg| arg.parse::<u64>().ok()),
+            "public" => public = true,
+            _ => {}
+        }
+    }
+    let shared = public || s_maxage.is_some();
+    s_maxage
+        .or(max_age)
+        .map(|ttl| (Duration::from_secs(ttl), shared))
+}
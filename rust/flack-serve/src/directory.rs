@@ -0,0 +1,233 @@
+//! Serving store directories.
+//!
+//! Routes control this with the `directory` attribute of their extra attrset:
+//!
+//! ```nix
+//! req.res 200 { } "${pkgs.somedocs}/share/doc" {
+//!   directory = { index = [ "index.html" ]; listing = "html"; redirect = true; };
+//! }
+//! ```
+//!
+//! By default, directories redirect to add a trailing slash and serve their `index.html`,
+//! and there's no listing. Symlinks go through the resolver, so nothing here leaves the closure.
+
+use std::path::{Component, Path};
+
+use log::debug;
+
+use actix_web::http::header;
+
+use crate::resolve::{Resolver, Stat};
+use crate::{EvalRequest, FlackResponse};
+
+/// The kind of listing to render for directories without an index.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Listing {
+    Html,
+    Json,
+}
+
+/// How a route wants directories served.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DirectoryOptions {
+    /// Files to look for in the directory, in order.
+    index: Vec<String>,
+
+    /// The listing to render if none of them exist.
+    listing: Option<Listing>,
+
+    /// Whether to redirect to add a trailing slash.
+    redirect: bool,
+}
+
+impl Default for DirectoryOptions {
+    fn default() -> Self {
+        DirectoryOptions {
+            index: vec!["index.html".to_string()],
+            listing: None,
+            redirect: true,
+        }
+    }
+}
+
+/// An entry in a directory listing.
+#[derive(serde::Serialize)]
+struct ListingEntry {
+    name: String,
+
+    #[serde(rename = "type")]
+    kind: &'static str,
+
+    size: u64,
+}
+
+/// Serves a realised store directory: redirects, then an index file, then a listing.
+/// Takes the directory's resolved logical path.
+pub fn serve_directory(
+    response: &mut FlackResponse,
+    resolver: &mut Resolver,
+    path: &Path,
+    request: &EvalRequest,
+    options: &DirectoryOptions,
+    content_type_set: bool,
+) -> FlackResponse {
+    let request_path = request.input("PATH_INFO").unwrap_or("/");
+    let query = request.input("QUERY_STRING").unwrap_or("");
+    if options.redirect && !request_path.ends_with('/') {
+        // Redirect relative to the last segment, so a path like `//host/dir` can't name another host.
+        let segment = request_path.rsplit('/').next().unwrap_or_default();
+        let location = if query.is_empty() {
+            format!("./{}/", segment)
+        } else {
+            format!("./{}/?{}", segment, query)
+        };
+        debug!("Redirecting to {}", location);
+        response.add_header(header::LOCATION.to_string(), location);
+        return response.string(301, "");
+    }
+
+    for index in &options.index {
+        if !Path::new(index)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            continue;
+        }
+        if let Ok((index_path, Stat::File { .. })) = resolver.resolve(&path.join(index)) {
+            debug!("Serving index {:?}", index_path);
+            if let Some(etag) = resolver.etag(&index_path) {
+                response.add_header(header::ETAG.to_string(), etag);
+            }
+            return resolver
+                .serve(response, &index_path)
+                .unwrap_or_else(|err| response.server_error(err));
+        }
+    }
+
+    let Some(listing) = options.listing else {
+        return response.not_found("store path was a directory without an index");
+    };
+
+    let entries = match list_directory(resolver, path) {
+        Ok(entries) => entries,
+        Err(err) => return response.server_error(err),
+    };
+
+    let (content_type, body) = match listing {
+        Listing::Html => (
+            "text/html; charset=utf-8",
+            render_html(request_path, &entries),
+        ),
+        Listing::Json => (
+            "application/json",
+            serde_json::to_string(&entries).unwrap_or_default(),
+        ),
+    };
+    if !content_type_set {
+        response.add_header(header::CONTENT_TYPE.to_string(), content_type.to_string());
+    }
+    response.string(0, body)
+}
+
+/// Lists a directory, sorted by name, leaving out symlinks that lead out of the closure.
+fn list_directory(resolver: &mut Resolver, path: &Path) -> std::io::Result<Vec<ListingEntry>> {
+    let mut entries = Vec::new();
+    for (name, mut stat) in resolver.read_dir(path)? {
+        if let Stat::Symlink(_) = stat {
+            match resolver.resolve(&path.join(&name)) {
+                Ok((_, target)) => stat = target,
+                Err(_) => continue,
+            }
+        }
+
+        // Describe what the entry resolves to.
+        let (kind, size) = match stat {
+            Stat::File { size } => ("file", size),
+            _ => ("directory", 0),
+        };
+        entries.push(ListingEntry {
+            name: name.to_string_lossy().to_string(),
+            kind,
+            size,
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Renders an HTML directory listing.
+/// Links are absolute, so they work whether or not the request path ends in a slash.
+fn render_html(request_path: &str, entries: &[ListingEntry]) -> String {
+    let title = escape_html(request_path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {}</title></head>\n<body>\n<h1>Index of {}</h1>\n<ul>\n",
+        title, title
+    );
+
+    // Leave out empty segments, so the links can't start with `//` and name another host.
+    let segments: Vec<&str> = request_path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let base = escape_html(&absolute(&segments));
+    if let Some((_, parent)) = segments.split_last() {
+        html.push_str(&format!(
+            "<li><a href=\"{}\">../</a></li>\n",
+            escape_html(&absolute(parent))
+        ));
+    }
+    for entry in entries {
+        let suffix = if entry.kind == "directory" { "/" } else { "" };
+        html.push_str(&format!(
+            "<li><a href=\"{}{}{}\">{}{}</a></li>\n",
+            base,
+            escape_url(&entry.name),
+            suffix,
+            escape_html(&entry.name),
+            suffix
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    html
+}
+
+/// Returns the absolute path of a directory from its segments, which are already percent-encoded.
+fn absolute(segments: &[&str]) -> String {
+    let mut path = String::from("/");
+    for segment in segments {
+        path.push_str(segment);
+        path.push('/');
+    }
+    path
+}
+
+/// Escapes text for HTML.
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Percent-encodes a path component for a link.
+fn escape_url(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
@@ -0,0 +1,211 @@
+//! Garbage collector roots for what flack-serve is serving.
+//!
+//! Nothing stops a concurrent `nix-collect-garbage` from deleting a path we've realised,
+//! even while it's being streamed out. Everything we root is a symlink in a directory of our
+//! own, registered as an indirect root, so deleting the symlink releases it. The preload
+//! closure's roots last until it's preloaded again or flack-serve shuts down, and the paths
+//! a response is served from are rooted until its body has been sent.
+//!
+//! The directory is named after the address we're bound to, so a restart after a crash finds
+//! the roots it left behind and releases them.
+
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::{HttpResponse, web};
+
+use nix_bindings_store::path::StorePath;
+use nix_bindings_store::store::Store;
+
+use crate::FlackApp;
+
+/// The roots flack-serve holds.
+pub struct GcRoots {
+    /// Where the preload closure's symlinks are.
+    preload: PathBuf,
+
+    /// Where the symlinks for responses being sent are.
+    served: PathBuf,
+
+    /// Numbers the symlinks for responses, so each one has its own.
+    next: AtomicU64,
+}
+
+/// Roots for the store paths a response is served from, released when dropped.
+pub struct ServedRoots {
+    links: Vec<PathBuf>,
+}
+
+/// A response body that holds the roots of what it's serving until it's been sent.
+struct RootedBody {
+    body: BoxBody,
+    _roots: ServedRoots,
+}
+
+/// Implementation for GC roots.
+impl GcRoots {
+    /// Opens the directory for the roots of a server bound to the given address,
+    /// releasing any that a previous server there left behind.
+    pub fn new(host: &str, port: u16) -> std::io::Result<GcRoots> {
+        let dir = std::env::temp_dir().join(format!("flack-roots-{}-{}", host, port));
+        let gc_roots = GcRoots {
+            preload: dir.join("preload"),
+            served: dir.join("served"),
+            next: AtomicU64::new(0),
+        };
+        for dir in [&dir, &gc_roots.preload, &gc_roots.served] {
+            std::fs::DirBuilder::new()
+                .mode(0o700)
+                .recursive(true)
+                .create(dir)?;
+            // Anyone can make directories in /tmp, so make sure this one is ours.
+            let metadata = std::fs::symlink_metadata(dir)?;
+            if !metadata.is_dir() || metadata.uid() != unsafe { libc::geteuid() } {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("{:?} isn't a directory of ours", dir),
+                ));
+            }
+        }
+        debug!("Keeping GC roots in {:?}", dir);
+
+        let stale = release_dir(&gc_roots.preload) + release_dir(&gc_roots.served);
+        if stale > 0 {
+            info!("Released {} GC roots left behind by a previous run", stale);
+        }
+        Ok(gc_roots)
+    }
+
+    /// Roots a path in the preload closure, until the closure is released.
+    pub fn add_perm(&self, store: &mut Store, path: &StorePath) -> anyhow::Result<()> {
+        let real_path = store.real_path(path)?;
+        let name = Path::new(&real_path)
+            .file_name()
+            .ok_or_else(|| anyhow!("{:?} is not a store path", real_path))?;
+        let link = store.add_perm_root(path, &self.preload.join(name))?;
+        debug!("Rooted {} at {:?}", real_path, link);
+        Ok(())
+    }
+
+    /// Roots the store paths a response is served from, until the roots are dropped.
+    pub fn add_served(
+        &self,
+        store: &mut Store,
+        store_paths: &[String],
+    ) -> anyhow::Result<ServedRoots> {
+        let mut roots = ServedRoots { links: Vec::new() };
+        for store_path in store_paths {
+            let path = store.parse_store_path(store_path)?;
+            let name = Path::new(store_path)
+                .file_name()
+                .ok_or_else(|| anyhow!("{:?} is not a store path", store_path))?;
+            let mut link_name = self.next.fetch_add(1, Ordering::Relaxed).to_string();
+            link_name.push('-');
+            link_name.push_str(&name.to_string_lossy());
+            roots
+                .links
+                .push(store.add_perm_root(&path, &self.served.join(link_name))?);
+        }
+        Ok(roots)
+    }
+
+    /// Removes the symlinks rooting the preload closure, so it can be collected.
+    pub fn release(&self) {
+        let released = release_dir(&self.preload);
+        info!("Released {} GC roots", released);
+    }
+}
+
+/// Implementation for served roots.
+impl ServedRoots {
+    /// Holds the roots until the response's body has been sent, or the client goes away.
+    pub fn hold(self, res: HttpResponse) -> HttpResponse {
+        res.map_body(|_, body| RootedBody { body, _roots: self })
+            .map_into_boxed_body()
+    }
+}
+
+/// Implementation for dropping served roots.
+impl Drop for ServedRoots {
+    fn drop(&mut self) {
+        for link in &self.links {
+            if let Err(err) = std::fs::remove_file(link) {
+                warn!("Couldn't release GC root {:?}: {}", link, err);
+            }
+        }
+    }
+}
+
+impl MessageBody for RootedBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<web::Bytes, Self::Error>>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
+
+/// Removes the symlinks in a directory of roots, returning how many there were.
+fn release_dir(dir: &Path) -> usize {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Couldn't release GC roots in {:?}: {}", dir, err);
+            return 0;
+        }
+    };
+
+    let mut released = 0;
+    for entry in entries.flatten() {
+        match std::fs::remove_file(entry.path()) {
+            Ok(()) => released += 1,
+            Err(err) => warn!("Couldn't release GC root {:?}: {}", entry.path(), err),
+        }
+    }
+    released
+}
+
+/// Roots the store paths a response is served from, for as long as it takes to send it.
+pub async fn add(app: &web::Data<FlackApp>, store_paths: &[String]) -> Option<ServedRoots> {
+    if store_paths.is_empty() {
+        return None;
+    }
+
+    let app = app.clone();
+    let store_paths = store_paths.to_vec();
+    let result = web::block(move || -> anyhow::Result<ServedRoots> {
+        let mut store = app
+            .state
+            .lock()
+            .map_err(|err| anyhow!(err.to_string()))?
+            .store()
+            .clone();
+        app.gc_roots.add_served(&mut store, &store_paths)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(roots)) => Some(roots),
+        Ok(Err(err)) => {
+            warn!("Couldn't root served paths: {}", err);
+            None
+        }
+        Err(err) => {
+            warn!("Couldn't root served paths: {}", err);
+            None
+        }
+    }
+}
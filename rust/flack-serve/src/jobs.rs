@@ -0,0 +1,504 @@
+//! Builds that outlast their requests.
+//!
+//! A GET or HEAD route can set `async = true` in its extra attrset. If its body is a store path
+//! that isn't built yet, flack-serve queues the build instead of holding the request open for it, and
+//! answers `202 Accepted` with a `Location` of `/_flack/jobs/<id>`. Polling the job reports
+//! whether it's queued, building, done or failed; once it's done, it redirects back to the
+//! original URL. Requests waiting on the same derivations share one build.
+//!
+//! Clients that send `Accept: text/event-stream`, to the route or to the job, get the build's
+//! progress as Server-Sent Events instead: `status`, `activity`, `phase` and `log` events as
+//! the build goes, then `done` with the store path or `failed` with the error.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use tokio::sync::{Semaphore, broadcast};
+use uuid::Uuid;
+
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse, mime, web};
+
+use nix_bindings_store::store::{ActivityType, BuildEvent};
+
+use crate::directory::DirectoryOptions;
+use crate::{ChannelBody, FlackApp, FlackResponse, build_response, realise_options};
+
+/// How long to remember a job after its build finishes.
+const JOB_TTL: Duration = Duration::from_secs(600);
+
+/// How long clients should wait before polling a job again (seconds).
+const RETRY_AFTER: u64 = 2;
+
+/// How many events of a build to keep for clients that start listening late.
+const MAX_EVENTS: usize = 1000;
+
+/// The derivations a response is waiting on.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct PendingBuild {
+    /// The `.drv` paths to realise, sorted and without duplicates.
+    pub drv_paths: Vec<String>,
+
+    /// The store path the response is for, once they're built.
+    pub store_path: String,
+
+    /// Whether to queue the build, rather than build it while the client waits.
+    pub queue: bool,
+
+    /// What to serve once they're built, in or under `store_path`.
+    pub path: String,
+
+    /// How the route wants a directory at `path` served.
+    pub directory: DirectoryOptions,
+
+    /// Whether the route set its own Content-Type.
+    pub content_type_set: bool,
+}
+
+/// How far along a build is.
+#[derive(Clone, Debug)]
+enum Status {
+    Queued,
+    Building,
+    Done,
+    Failed(String),
+}
+
+/// Something a build did, to pass on to clients listening for events.
+#[derive(Clone, Debug)]
+enum Event {
+    Status(&'static str),
+    Activity(String),
+    Phase(String),
+    Log(String),
+    Done,
+    Failed(String),
+}
+
+/// A build that one or more jobs are waiting on.
+struct Build {
+    drv_paths: Vec<String>,
+    status: Mutex<Status>,
+    finished: OnceLock<Instant>,
+
+    /// The most recent events, for clients that start listening partway through.
+    events: Mutex<VecDeque<Event>>,
+    tx: broadcast::Sender<Event>,
+}
+
+/// Implementation for builds.
+impl Build {
+    /// Returns the status of the build.
+    fn status(&self) -> Status {
+        self.status
+            .lock()
+            .map(|status| status.clone())
+            .unwrap_or_else(|err| Status::Failed(err.to_string()))
+    }
+
+    /// Updates the status of the build, and tells anyone listening.
+    fn set(&self, status: Status) {
+        let event = match &status {
+            Status::Queued => Event::Status("queued"),
+            Status::Building => Event::Status("building"),
+            Status::Done => Event::Done,
+            Status::Failed(err) => Event::Failed(err.clone()),
+        };
+        if matches!(status, Status::Done | Status::Failed(_)) {
+            let _ = self.finished.set(Instant::now());
+        }
+        if let Ok(mut current) = self.status.lock() {
+            *current = status;
+        }
+        self.emit(event);
+    }
+
+    /// Passes an event on to anyone listening.
+    fn emit(&self, event: Event) {
+        // Hold the lock while sending, so listeners that subscribe in between don't miss it or see it twice.
+        if let Ok(mut events) = self.events.lock() {
+            if events.len() >= MAX_EVENTS {
+                events.pop_front();
+            }
+            events.push_back(event.clone());
+            let _ = self.tx.send(event);
+        }
+    }
+
+    /// Passes on what Nix reported about the build.
+    fn progress(&self, event: BuildEvent) {
+        match event {
+            BuildEvent::Start {
+                activity_type:
+                    ActivityType::Build | ActivityType::Substitute | ActivityType::FileTransfer,
+                text,
+                ..
+            } if !text.is_empty() => self.emit(Event::Activity(text)),
+            BuildEvent::Phase { phase, .. } => self.emit(Event::Phase(phase)),
+            BuildEvent::LogLine { line, .. } => self.emit(Event::Log(line)),
+            _ => {}
+        }
+    }
+
+    /// Returns the events so far, and a receiver for the rest.
+    fn subscribe(&self) -> Option<(Vec<Event>, broadcast::Receiver<Event>)> {
+        let events = self.events.lock().ok()?;
+        Some((events.iter().cloned().collect(), self.tx.subscribe()))
+    }
+}
+
+/// A request that's waiting on a build.
+struct Job {
+    /// Where to send the client once the build is done.
+    location: String,
+
+    /// The store path the client asked for.
+    store_path: String,
+
+    build: Arc<Build>,
+}
+
+/// A job's status, as reported to clients.
+#[derive(serde::Serialize)]
+struct JobStatus<'a> {
+    id: &'a str,
+    status: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<&'a str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The queue of builds, and the jobs waiting on them.
+pub struct JobQueue {
+    jobs: Mutex<HashMap<String, Job>>,
+
+    /// Builds that haven't finished, by their derivations.
+    builds: Mutex<HashMap<Vec<String>, Arc<Build>>>,
+
+    /// Permits to run a build.
+    permits: Arc<Semaphore>,
+
+    /// The most builds that may be queued or running.
+    capacity: usize,
+}
+
+/// Implementation for job queues.
+impl JobQueue {
+    /// Creates a queue that holds up to `capacity` builds, running `concurrency` at a time.
+    pub fn new(capacity: usize, concurrency: usize) -> JobQueue {
+        JobQueue {
+            jobs: Mutex::new(HashMap::new()),
+            builds: Mutex::new(HashMap::new()),
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            capacity,
+        }
+    }
+
+    /// Adds a job for a build, starting the build unless it's already queued. Returns the job's ID.
+    fn submit(
+        self: &Arc<Self>,
+        app: &web::Data<FlackApp>,
+        pending: PendingBuild,
+        location: String,
+    ) -> Result<String, FlackResponse> {
+        let mut builds = self
+            .builds
+            .lock()
+            .map_err(|err| FlackResponse::new().server_error(err))?;
+        let build = match builds.get(&pending.drv_paths) {
+            Some(build) => {
+                debug!("Joining the build of {:?}", pending.drv_paths);
+                build.clone()
+            }
+            None => {
+                if builds.len() >= self.capacity {
+                    return Err(FlackResponse::new().service_unavailable("the build queue is full"));
+                }
+                debug!("Queueing the build of {:?}", pending.drv_paths);
+                let build = Arc::new(Build {
+                    drv_paths: pending.drv_paths.clone(),
+                    status: Mutex::new(Status::Queued),
+                    finished: OnceLock::new(),
+                    events: Mutex::new(VecDeque::from([Event::Status("queued")])),
+                    tx: broadcast::channel(MAX_EVENTS).0,
+                });
+                builds.insert(pending.drv_paths.clone(), build.clone());
+                actix_web::rt::spawn(run(self.clone(), app.clone(), build.clone()));
+                build
+            }
+        };
+        drop(builds);
+
+        let id = Uuid::now_v7().to_string();
+        let mut jobs = self
+            .jobs
+            .lock()
+            .map_err(|err| FlackResponse::new().server_error(err))?;
+        jobs.retain(|_, job| {
+            job.build
+                .finished
+                .get()
+                .is_none_or(|finished| finished.elapsed() < JOB_TTL)
+        });
+        jobs.insert(
+            id.clone(),
+            Job {
+                location,
+                store_path: pending.store_path,
+                build,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Returns a job's status, or None if there's no such job.
+    fn status<'a>(&self, id: &'a str) -> Option<(JobStatus<'a>, String)> {
+        let jobs = self.jobs.lock().ok()?;
+        let job = jobs.get(id)?;
+        let (status, error) = match job.build.status() {
+            Status::Queued => ("queued", None),
+            Status::Building => ("building", None),
+            Status::Done => ("done", None),
+            Status::Failed(err) => ("failed", Some(err)),
+        };
+        Some((
+            JobStatus {
+                id,
+                status,
+                location: None,
+                error,
+            },
+            job.location.clone(),
+        ))
+    }
+}
+
+/// Runs a build once there's a permit for it.
+async fn run(queue: Arc<JobQueue>, app: web::Data<FlackApp>, build: Arc<Build>) {
+    let _permit = queue.permits.clone().acquire_owned().await;
+    build.set(Status::Building);
+    info!("Building {:?}", build.drv_paths);
+
+    let start = Instant::now();
+    let drv_paths = build.drv_paths.clone();
+    let progress = build.clone();
+    let result = web::block(move || -> anyhow::Result<()> {
+        let mut store = app
+            .state
+            .lock()
+            .map_err(|err| anyhow!(err.to_string()))?
+            .store()
+            .clone();
+        let options = realise_options(&app.args);
+        for drv_path in &drv_paths {
+            let path = store.parse_store_path(drv_path)?;
+            store.realise_with_progress(&path, &options, |event| progress.progress(event))?;
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|err| anyhow!(err.to_string()))
+    .and_then(|result| result);
+
+    match result {
+        Ok(()) => {
+            info!(
+                "Built {:?} in {}ms",
+                build.drv_paths,
+                start.elapsed().as_millis()
+            );
+            build.set(Status::Done);
+        }
+        Err(err) => {
+            warn!("Couldn't build {:?}: {:?}", build.drv_paths, err);
+            build.set(Status::Failed(err.to_string()));
+        }
+    }
+
+    if let Ok(mut builds) = queue.builds.lock() {
+        builds.remove(&build.drv_paths);
+    }
+}
+
+/// Returns a job's status as a response.
+fn status_response(
+    code: u16,
+    status: &JobStatus,
+    headers: Vec<(header::HeaderName, String)>,
+) -> FlackResponse {
+    let mut response = FlackResponse::new();
+    for (key, value) in headers {
+        response.add_header(key.to_string(), value);
+    }
+    response.add_header(
+        header::CONTENT_TYPE.to_string(),
+        mime::APPLICATION_JSON.to_string(),
+    );
+    match serde_json::to_string(status) {
+        Ok(json) => response.string(code, json),
+        Err(err) => response.server_error(err),
+    }
+}
+
+/// Returns true if an Accept header asks for Server-Sent Events.
+pub fn wants_events(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        accept
+            .split(',')
+            .any(|accept| accept.trim().starts_with("text/event-stream"))
+    })
+}
+
+/// Formats an event for a Server-Sent Events stream.
+fn sse(event: &str, data: &str) -> web::Bytes {
+    let mut out = format!("event: {}\n", event);
+    for line in data.split('\n') {
+        out.push_str("data: ");
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push('\n');
+    web::Bytes::from(out)
+}
+
+/// Streams a job's events as Server-Sent Events, ending with `done` or `failed`.
+pub fn stream(req: &HttpRequest, id: &str) -> HttpResponse {
+    let app = req.app_data::<web::Data<FlackApp>>().unwrap().clone();
+    let job = app.jobs.as_ref().and_then(|queue| {
+        let jobs = queue.jobs.lock().ok()?;
+        let job = jobs.get(id)?;
+        let (history, rx) = job.build.subscribe()?;
+        Some((history, rx, job.location.clone(), job.store_path.clone()))
+    });
+    let Some((history, mut rx, location, store_path)) = job else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let (tx, body) = tokio::sync::mpsc::channel(16);
+    actix_web::rt::spawn(async move {
+        let format = |event: &Event| match event {
+            Event::Status(status) => sse("status", status),
+            Event::Activity(text) => sse("activity", text),
+            Event::Phase(phase) => sse("phase", phase),
+            Event::Log(line) => sse("log", line),
+            Event::Done => sse(
+                "done",
+                &serde_json::json!({ "storePath": store_path, "location": location }).to_string(),
+            ),
+            Event::Failed(err) => sse("failed", &serde_json::json!({ "error": err }).to_string()),
+        };
+
+        for event in &history {
+            if tx.send(Ok(format(event))).await.is_err() {
+                return;
+            }
+            if matches!(event, Event::Done | Event::Failed(_)) {
+                return;
+            }
+        }
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    let comment = format!(": skipped {} events\n\n", skipped);
+                    if tx.send(Ok(web::Bytes::from(comment))).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            if tx.send(Ok(format(&event))).await.is_err() {
+                return;
+            }
+            if matches!(event, Event::Done | Event::Failed(_)) {
+                return;
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/event-stream"))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .body(ChannelBody {
+            rx: body,
+            size: None,
+        })
+}
+
+/// Queues the build a response is waiting on, and answers with where to check on it.
+pub fn accept(
+    app: &web::Data<FlackApp>,
+    req: &HttpRequest,
+    pending: PendingBuild,
+) -> Result<FlackResponse, FlackResponse> {
+    let Some(queue) = &app.jobs else {
+        return Err(FlackResponse::new().server_error("async builds are disabled"));
+    };
+
+    // Collapse leading slashes, so `//host/...` can't send the client to another host.
+    let location = req
+        .uri()
+        .path_and_query()
+        .map_or(req.path(), |path_and_query| path_and_query.as_str());
+    let location = format!("/{}", location.trim_start_matches('/'));
+    let id = queue.submit(app, pending, location)?;
+    if wants_events(
+        req.headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok()),
+    ) {
+        return Ok(FlackResponse::new().ok_events(id));
+    }
+
+    let Some((status, _)) = queue.status(&id) else {
+        return Err(FlackResponse::new().server_error("job disappeared"));
+    };
+
+    Ok(status_response(
+        202,
+        &status,
+        vec![
+            (header::LOCATION, format!("/_flack/jobs/{}", id)),
+            (header::RETRY_AFTER, RETRY_AFTER.to_string()),
+        ],
+    ))
+}
+
+/// Serves `/_flack/jobs/<id>`.
+pub async fn job(req: HttpRequest, id: web::Path<String>) -> HttpResponse {
+    let app = req.app_data::<web::Data<FlackApp>>().unwrap().clone();
+    let status = app.jobs.as_ref().and_then(|queue| queue.status(&id));
+
+    let response = match status {
+        None => FlackResponse::new().not_found("no such job"),
+        Some(_)
+            if wants_events(
+                req.headers()
+                    .get(header::ACCEPT)
+                    .and_then(|accept| accept.to_str().ok()),
+            ) =>
+        {
+            return stream(&req, &id);
+        }
+        Some((mut status, location)) => match status.status {
+            "done" => {
+                status.location = Some(&location);
+                status_response(303, &status, vec![(header::LOCATION, location.clone())])
+            }
+            "failed" => status_response(200, &status, Vec::new()),
+            _ => status_response(
+                202,
+                &status,
+                vec![(header::RETRY_AFTER, RETRY_AFTER.to_string())],
+            ),
+        },
+    };
+    build_response(req, &response).await
+}
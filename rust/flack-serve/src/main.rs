@@ -7,15 +7,20 @@
 #![feature(lock_value_accessors)]
 #![feature(normalize_lexically)]
 
+use std::collections::BTreeMap;
 use std::num::NonZero;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex, Once};
+use std::task::{Context as TaskContext, Poll};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use log::{debug, error, info, warn};
+use log::{Level, debug, error, info, log_enabled, warn};
 
 use actix_files::NamedFile;
+use actix_web::body::{BodySize, MessageBody};
 use actix_web::http::header::{HeaderName, HeaderValue, TryIntoHeaderPair};
 use actix_web::http::{StatusCode, header};
 use actix_web::{
@@ -29,12 +34,31 @@ use clap::Parser;
 
 use nix_bindings_expr::{
     eval_state::{EvalState, ThreadRegistrationGuard},
+    value::serde::{from_value, to_value},
     value::{Value, ValueType},
 };
 use nix_bindings_flake::EvalStateBuilderExt as _;
 
 use nix_bindings_store::path::StorePath;
-use nix_bindings_store::store::Store;
+use nix_bindings_store::store::{CopyClosureOptions, RealiseOptions, Store};
+use nix_bindings_util::error::NixError;
+use nix_bindings_util::interrupt::InterruptHandle;
+use nix_bindings_util::settings;
+
+use directory::DirectoryOptions;
+use resolve::{Resolver, Stat};
+
+mod binary_cache;
+mod cache;
+mod directory;
+mod gc_roots;
+mod jobs;
+mod nar;
+mod policy;
+mod resolve;
+mod sandbox;
+mod upload;
+mod worker;
 
 /// Command-line arguments for Flack.
 #[derive(Parser, Clone, Debug)]
@@ -63,6 +87,7 @@ struct FlackArgs {
     no_preload: bool,
 
     /// Pass to disable substitution during preload.
+    /// The closure is then built before the server starts, as Nix only has a global setting for it.
     #[arg(long, action, default_value_t = false)]
     no_preload_substitute: bool,
 
@@ -97,6 +122,92 @@ struct FlackArgs {
     /// GC after evals lasting this long (milliseconds)
     #[arg(short = 'G', long, default_value_t = 15000)]
     gc_after: u32,
+
+    /// Interrupt evals that take longer than this (milliseconds); set to 0 to disable.
+    /// Routes may shorten this with the `timeout` attribute of their extra attrset.
+    #[arg(short = 'T', long, default_value_t = 60000)]
+    timeout: u32,
+
+    /// The number of sandboxed evaluator processes to run; set to 0 to eval in the server process.
+    /// Workers can't build anything themselves, so this needs a daemon store.
+    #[arg(short = 'w', long, default_value_t = 0)]
+    workers: u16,
+
+    /// The most response body data to cache, in bytes; set to 0 to disable the response cache.
+    /// Only responses with a Cache-Control max-age are cached.
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    cache_size: usize,
+
+    /// The longest a cached response may live (seconds), whatever its Cache-Control says.
+    #[arg(long, default_value_t = 3600)]
+    cache_ttl: u64,
+
+    /// A file with a token that allows `POST /_flack/cache/purge` as a bearer token.
+    /// Without one, the response cache can't be purged.
+    #[arg(long)]
+    cache_purge_token_file: Option<PathBuf>,
+
+    /// The largest request body to hold in memory and parse, in bytes.
+    /// Bigger bodies are imported into the store, or rejected if they'd need parsing.
+    #[arg(long, default_value_t = 256 * 1024)]
+    max_body_size: usize,
+
+    /// The most file data a request may import into the store, in bytes; set to 0 to disable uploads.
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    max_upload_size: u64,
+
+    /// A directory under the store's gcroots to root uploaded paths in.
+    /// Without one, uploads are only kept alive by the server's temporary roots.
+    #[arg(long)]
+    upload_roots: Option<PathBuf>,
+
+    /// How long uploaded paths stay rooted in --upload-roots (seconds).
+    #[arg(long, default_value_t = 3600)]
+    upload_ttl: u64,
+
+    /// Serve a Nix binary cache of the closures the app has built, at /nix-cache-info.
+    #[arg(long, action, default_value_t = false)]
+    binary_cache: bool,
+
+    /// The key to sign binary cache narinfos with, as made by `nix key generate-secret`.
+    #[arg(long)]
+    secret_key_file: Option<PathBuf>,
+
+    /// A store URI to copy the preload closure to once it's realised, like `nix copy --to`.
+    #[arg(long)]
+    copy_to: Option<String>,
+
+    /// The most builds routes with `async = true` may have queued or running; set to 0 to build them
+    /// while the client waits, like any other route.
+    #[arg(long, default_value_t = 16)]
+    build_queue: usize,
+
+    /// The number of queued builds to run at once.
+    #[arg(long, default_value_t = 1)]
+    build_jobs: usize,
+
+    /// What requests may realise: anything, only paths that are valid or can be substituted,
+    /// or also builds of the preload closure and of derivations matching --allow-build.
+    #[arg(long, value_enum, default_value_t = policy::RealisePolicy::All)]
+    realise_policy: policy::RealisePolicy,
+
+    /// Derivation names that may be built under `--realise-policy allowlist`, like `hello-*`.
+    #[arg(long)]
+    allow_build: Vec<String>,
+
+    /// The most a request may have substituted, in compressed bytes, as estimated before anything
+    /// is fetched. Requests that would download more get a 403.
+    #[arg(long)]
+    max_download_size: Option<u64>,
+
+    /// The longest a build may run (seconds), separately from --timeout, which then only covers eval.
+    /// Builds with a timeout are done by the server process.
+    #[arg(long)]
+    build_timeout: Option<u64>,
+
+    /// Run as an evaluator process for --workers.
+    #[arg(long, hide = true, action, default_value_t = false)]
+    worker: bool,
 }
 
 /// The Flack application. Contains an initial eval state,
@@ -106,6 +217,13 @@ pub struct FlackApp {
     system: String,
     state: Arc<Mutex<EvalState>>,
     app: Arc<Mutex<Value>>,
+    workers: Option<Arc<worker::WorkerPool>>,
+    cache: Option<Arc<cache::ResponseCache>>,
+    purge_token: Option<Arc<String>>,
+    binary_cache: Option<Arc<binary_cache::BinaryCache>>,
+    gc_roots: Arc<gc_roots::GcRoots>,
+    jobs: Option<Arc<jobs::JobQueue>>,
+    policy: Arc<policy::Policy>,
 }
 
 /// A Flack error. Gets serialized to JSON.
@@ -115,6 +233,10 @@ struct FlackError {
 
     #[serde(skip_serializing)]
     long: String,
+
+    /// The end of the build log, for failed builds in debug mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log: Option<String>,
 }
 
 /// A Flack HTTP response. Unmarshalled from the Nix response.
@@ -125,7 +247,23 @@ struct FlackResponse {
     headers: Vec<(HeaderName, HeaderValue)>,
     body: Option<String>,
     body_path: Option<PathBuf>,
+    body_nar: Option<nar::NarFile>,
+
+    /// The logical store path of the file in body_path or body_nar.
+    /// Workers send this instead, for the server to resolve again.
+    served_path: Option<PathBuf>,
+
     error: Option<FlackError>,
+
+    /// Store paths the response came from, to root and add to the binary cache.
+    store_paths: Vec<String>,
+
+    /// A build that has to happen before the response can be served: queued for routes with
+    /// `async = true` and clients that asked for build events, and checked against --realise-policy.
+    build: Option<jobs::PendingBuild>,
+
+    /// A job whose build events to stream as the response.
+    body_events: Option<String>,
 }
 
 /// Implementation for Flack HTTP responses.
@@ -138,7 +276,12 @@ impl FlackResponse {
             headers: Vec::new(),
             body: None,
             body_path: None,
+            body_nar: None,
+            served_path: None,
             error: None,
+            store_paths: Vec::new(),
+            build: None,
+            body_events: None,
         }
     }
 
@@ -169,6 +312,14 @@ impl FlackResponse {
         self
     }
 
+    /// Returns the first value of a header, if it's valid.
+    fn header(&self, key: HeaderName) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(name, _)| *name == key)
+            .and_then(|(_, value)| value.to_str().ok())
+    }
+
     /// Sets a generic 500 Internal Server Error.
     fn server_error<S: std::fmt::Display>(&mut self, err: S) -> Self {
         self.set(
@@ -176,10 +327,54 @@ impl FlackResponse {
             Either::Left(FlackError {
                 error: "Internal server error".to_string(),
                 long: err.to_string(),
+                log: None,
+            }),
+        )
+    }
+
+    /// Sets a 500 for a failed build, with the end of its log if there is one.
+    fn build_error<S: std::fmt::Display>(&mut self, err: S, log: Option<String>) -> Self {
+        self.set(
+            500,
+            Either::Left(FlackError {
+                error: "Build failed".to_string(),
+                long: err.to_string(),
+                log,
             }),
         )
     }
 
+    /// Sets an error from evaluating the app.
+    /// Apps can throw a JSON object with a 4xx or 5xx `status` (and an optional `message`)
+    /// to respond with that status; anything else is a 500.
+    fn eval_error(&mut self, err: anyhow::Error) -> Self {
+        if let Some(nix_err) = err.downcast_ref::<NixError>()
+            && let Some(thrown) = &nix_err.thrown
+            && let Ok(serde_json::Value::Object(obj)) = serde_json::from_str(thrown)
+            && let Some(status) = obj.get("status").and_then(|status| status.as_u64())
+            && (400..=599).contains(&status)
+        {
+            let code = status as u16;
+            let error = match obj.get("message").and_then(|message| message.as_str()) {
+                Some(message) => message.to_string(),
+                None => StatusCode::from_u16(code)
+                    .ok()
+                    .and_then(|code| code.canonical_reason())
+                    .unwrap_or("Error")
+                    .to_string(),
+            };
+            return self.set(
+                code,
+                Either::Left(FlackError {
+                    error,
+                    long: nix_err.message.clone(),
+                    log: None,
+                }),
+            );
+        }
+        self.server_error(err)
+    }
+
     /// Sets a generic 400 Bad Request.
     fn bad_request<S: std::fmt::Display>(&mut self, err: S) -> Self {
         self.set(
@@ -187,6 +382,31 @@ impl FlackResponse {
             Either::Left(FlackError {
                 error: "Bad request".to_string(),
                 long: err.to_string(),
+                log: None,
+            }),
+        )
+    }
+
+    /// Sets a generic 403 Forbidden.
+    fn forbidden<S: std::fmt::Display>(&mut self, err: S) -> Self {
+        self.set(
+            403,
+            Either::Left(FlackError {
+                error: "Forbidden".to_string(),
+                long: err.to_string(),
+                log: None,
+            }),
+        )
+    }
+
+    /// Sets a generic 413 Payload Too Large.
+    fn payload_too_large<S: std::fmt::Display>(&mut self, err: S) -> Self {
+        self.set(
+            413,
+            Either::Left(FlackError {
+                error: "Payload too large".to_string(),
+                long: err.to_string(),
+                log: None,
             }),
         )
     }
@@ -198,6 +418,31 @@ impl FlackResponse {
             Either::Left(FlackError {
                 error: "Not found".to_string(),
                 long: err.to_string(),
+                log: None,
+            }),
+        )
+    }
+
+    /// Sets a generic 503 Service Unavailable.
+    fn service_unavailable<S: std::fmt::Display>(&mut self, err: S) -> Self {
+        self.set(
+            503,
+            Either::Left(FlackError {
+                error: "Service unavailable".to_string(),
+                long: err.to_string(),
+                log: None,
+            }),
+        )
+    }
+
+    /// Sets a generic 504 Gateway Timeout.
+    fn gateway_timeout<S: std::fmt::Display>(&mut self, err: S) -> Self {
+        self.set(
+            504,
+            Either::Left(FlackError {
+                error: "Gateway timeout".to_string(),
+                long: err.to_string(),
+                log: None,
             }),
         )
     }
@@ -217,6 +462,33 @@ impl FlackResponse {
         self.ok(Either::Right(Either::Right(body)))
     }
 
+    /// Sets a file that has to be read out of its store path's NAR as the response, with a 200 OK.
+    fn ok_nar(&mut self, body: nar::NarFile) -> Self {
+        self.code = 200;
+        self.body_nar = Some(body);
+        self.clone()
+    }
+
+    /// Sets a build that has to happen first as the response, with a 202 Accepted.
+    fn accepted(&mut self, build: jobs::PendingBuild) -> Self {
+        self.code = 202;
+        self.build = Some(build);
+        self.clone()
+    }
+
+    /// Sets a job's build events as the response, with a 200 OK.
+    fn ok_events(&mut self, job: String) -> Self {
+        self.code = 200;
+        self.body_events = Some(job);
+        self.clone()
+    }
+
+    /// Sets a path that the client already has as the response, with a 304 Not Modified.
+    fn not_modified_path(&mut self, body: PathBuf) -> Self {
+        self.served_path = Some(body.clone());
+        self.set(304, Either::Right(Either::Right(body)))
+    }
+
     /// Returns the current duration of this request, resetting the timer.
     fn stopwatch(&mut self) -> Duration {
         let start = self.start;
@@ -245,6 +517,96 @@ impl FlackResponse {
     }
 }
 
+/// The eval budget for a single request.
+/// The deadline counts from the start of the request, and the route may bring it forward.
+#[derive(Clone)]
+struct EvalBudget {
+    start: Instant,
+    timeout_ms: Arc<AtomicU32>,
+    interrupt: InterruptHandle,
+    on_timeout: Option<Arc<dyn Fn(u32) + Send + Sync>>,
+}
+
+/// Implementation for eval budgets.
+impl EvalBudget {
+    /// Creates a new budget. A timeout of 0 means no deadline.
+    fn new(interrupt: InterruptHandle, timeout_ms: u32) -> EvalBudget {
+        EvalBudget {
+            start: Instant::now(),
+            timeout_ms: Arc::new(AtomicU32::new(timeout_ms)),
+            interrupt,
+            on_timeout: None,
+        }
+    }
+
+    /// Calls a function whenever the timeout is replaced.
+    fn on_timeout(mut self, on_timeout: impl Fn(u32) + Send + Sync + 'static) -> EvalBudget {
+        self.on_timeout = Some(Arc::new(on_timeout));
+        self
+    }
+
+    /// Returns the timeout in milliseconds.
+    fn timeout_ms(&self) -> u32 {
+        self.timeout_ms.load(Ordering::Acquire)
+    }
+
+    /// Shortens the timeout. Routes may only tighten the server's limit, so 0 changes nothing.
+    fn shorten(&self, timeout_ms: u32) {
+        if timeout_ms == 0 {
+            return;
+        }
+        let current = self.timeout_ms.load(Ordering::Acquire);
+        let timeout_ms = match current {
+            0 => timeout_ms,
+            current => current.min(timeout_ms),
+        };
+        self.timeout_ms.store(timeout_ms, Ordering::Release);
+        if let Some(on_timeout) = &self.on_timeout {
+            on_timeout(timeout_ms);
+        }
+    }
+
+    /// Returns the deadline, if there is one.
+    fn deadline(&self) -> Option<Instant> {
+        match self.timeout_ms() {
+            0 => None,
+            ms => Some(self.start + Duration::from_millis(ms as u64)),
+        }
+    }
+
+    /// Returns true if the deadline has passed.
+    fn expired(&self) -> bool {
+        self.deadline()
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Returns a 504 for an eval that ran out of time.
+    fn timed_out(&self) -> FlackResponse {
+        FlackResponse::new().gateway_timeout(format!("eval exceeded {}ms", self.timeout_ms()))
+    }
+}
+
+/// Cancels an eval when dropped, unless disarmed.
+/// Actix drops the handler future when the client goes away, which takes this with it.
+struct CancelOnDrop<F: FnOnce()>(Option<F>);
+
+/// Implementation for cancel-on-drop guards.
+impl<F: FnOnce()> CancelOnDrop<F> {
+    /// Disarms the guard once the eval is over.
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl<F: FnOnce()> Drop for CancelOnDrop<F> {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.0.take() {
+            info!("Client went away, cancelling eval");
+            cancel();
+        }
+    }
+}
+
 /// Gets the GC guard for the current thread.
 fn get_gc_guard() -> std::io::Result<ThreadRegistrationGuard> {
     nix_bindings_expr::eval_state::gc_register_my_thread().map_err(std::io::Error::other)
@@ -252,7 +614,7 @@ fn get_gc_guard() -> std::io::Result<ThreadRegistrationGuard> {
 
 /// Initializes the evaluator.
 fn init_get_gc_guard() -> std::io::Result<ThreadRegistrationGuard> {
-        nix_bindings_expr::eval_state::init().map_err(std::io::Error::other)?;
+    nix_bindings_expr::eval_state::init().map_err(std::io::Error::other)?;
 
     get_gc_guard()
 }
@@ -266,27 +628,30 @@ fn init_get_state(
 ) -> std::io::Result<(EvalState, ThreadRegistrationGuard)> {
     let gc_guard = init_get_gc_guard()?;
 
-    if let Err(err) = nix_bindings_util::settings::set("experimental-features", "parallel-eval pipe-operators") {
+    let features = vec!["parallel-eval".to_string(), "pipe-operators".to_string()];
+    if let Err(err) = settings::EXPERIMENTAL_FEATURES.set(&features) {
         warn!("Couldn't enable parallel evaluation: {:?}", err);
     }
 
-    if let Err(err) = nix_bindings_util::settings::set("eval-cores", format!("{}", cores).as_str()) {
-        warn!("Couldn't set eval-cores: {:?}", err);
-    }
-
-    if args.log_level == "debug" {
-        if let Err(err) = nix_bindings_util::settings::set("trace-verbose", "true") {
-            warn!("Couldn't enable verbose tracing: {:?}", err);
-        }
-
-        if let Err(err) = nix_bindings_util::settings::set("show-trace", "true") {
-            warn!("Couldn't enable verbose tracing: {:?}", err);
-        }
+    if args.log_level == "debug"
+        && let Err(err) = settings::SHOW_TRACE.set(&true)
+    {
+        warn!("Couldn't enable verbose tracing: {:?}", err);
     }
 
+    // Evaluator settings go on the state, so they don't leak into other states.
     let mut state_builder = nix_bindings_expr::eval_state::EvalStateBuilder::new(store)
+        .and_then(|builder| builder.setting(settings::EVAL_CORES, &cores))
+        .and_then(|builder| builder.setting(settings::TRACE_VERBOSE, &(args.log_level == "debug")))
         .map_err(std::io::Error::other)?;
 
+    // Building to import from during eval would get around --realise-policy.
+    if args.realise_policy != policy::RealisePolicy::All {
+        state_builder = state_builder
+            .setting(settings::ALLOW_IMPORT_FROM_DERIVATION, &false)
+            .map_err(std::io::Error::other)?;
+    }
+
     if flakes {
         let flake_settings =
             nix_bindings_flake::FlakeSettings::new().map_err(std::io::Error::other)?;
@@ -475,12 +840,10 @@ fn get_safe_path(
 }
 
 /// Evals a string and then calls the result.
-fn call_fn(func: &str, st: &mut EvalState, value: &Value, dir: &str) -> std::io::Result<Value> {
-    let func_val = st
-        .eval_from_string(func, dir)
-        .map_err(std::io::Error::other)?;
+/// Errors stay Nix errors, so thrown HTTP statuses survive.
+fn call_fn(func: &str, st: &mut EvalState, value: &Value, dir: &str) -> anyhow::Result<Value> {
+    let func_val = st.eval_from_string(func, dir)?;
     st.call(func_val, value.clone())
-        .map_err(std::io::Error::other)
 }
 
 /// Evals a string and then calls the result, converting the value to a string.
@@ -490,38 +853,210 @@ fn call_string_fn(
     st: &mut EvalState,
     value: &Value,
     dir: &str,
-) -> std::io::Result<(Value, String)> {
+) -> anyhow::Result<(Value, String)> {
     let to_string_value = call_fn(func, st, value, dir)?;
-    let string_value = st
-        .require_string(&to_string_value)
-        .map_err(std::io::Error::other)?;
+    let string_value = st.require_string(&to_string_value)?;
     Ok((to_string_value, string_value))
 }
 
+/// The value of a parameter, or the values of a `foo[]` parameter.
+enum Param<T> {
+    One(Option<T>),
+    Many(Vec<T>),
+}
+
+/// Adds a parameter the same way `flackLib.queries.parseQuery` does for query strings:
+/// `foo[]` keys collect into lists, leaving out nulls, and other keys take their last value.
+fn insert_param<T>(params: &mut BTreeMap<String, Param<T>>, key: &str, value: Option<T>) {
+    match key
+        .strip_suffix("[]")
+        .filter(|name| !name.is_empty() && !name.contains('['))
+    {
+        Some(name) => {
+            let param = params
+                .entry(name.to_string())
+                .or_insert_with(|| Param::Many(Vec::new()));
+            if let Param::One(_) = param {
+                *param = Param::Many(Vec::new());
+            }
+            if let Param::Many(values) = param
+                && let Some(value) = value
+            {
+                values.push(value);
+            }
+        }
+        None => {
+            params.insert(key.to_string(), Param::One(value));
+        }
+    }
+}
+
+/// Converts parameters to JSON.
+fn params_to_json(
+    params: BTreeMap<String, Param<String>>,
+) -> serde_json::Map<String, serde_json::Value> {
+    params
+        .into_iter()
+        .map(|(key, param)| {
+            let value = match param {
+                Param::One(value) => {
+                    value.map_or(serde_json::Value::Null, serde_json::Value::String)
+                }
+                Param::Many(values) => values.into_iter().map(serde_json::Value::String).collect(),
+            };
+            (key, value)
+        })
+        .collect()
+}
+
+/// Parses an `application/x-www-form-urlencoded` body like a query string.
+/// Keys without a `=` are null.
+fn parse_form(body: &[u8]) -> serde_json::Map<String, serde_json::Value> {
+    let mut params = BTreeMap::new();
+    for param in body.split(|b| *b == b'&') {
+        let Some((key, value)) = url::form_urlencoded::parse(param).next() else {
+            continue;
+        };
+        if key.is_empty() {
+            continue;
+        }
+        let value = param.contains(&b'=').then(|| value.into_owned());
+        insert_param(&mut params, &key, value);
+    }
+    params_to_json(params)
+}
+
+/// Parses a request body into a Nix value, if it's JSON, a form post, TOML, or any kind of text.
+/// Returns None for anything else, which apps can still parse from `flack.input`.
+fn parse_body(
+    st: &mut EvalState,
+    dir: &str,
+    mime_type: Option<&mime::Mime>,
+    body: &[u8],
+) -> anyhow::Result<Option<Value>> {
+    let Some(mime_type) = mime_type else {
+        return Ok(None);
+    };
+    let (type_, subtype) = (mime_type.type_(), mime_type.subtype());
+    if type_ == mime::APPLICATION && subtype == mime::JSON {
+        let json: serde_json::Value = serde_json::from_slice(body)?;
+        Ok(Some(to_value(st, &json)?))
+    } else if type_ == mime::APPLICATION && subtype == mime::WWW_FORM_URLENCODED {
+        Ok(Some(to_value(st, &parse_form(body))?))
+    } else if type_ == mime::APPLICATION && subtype == "toml" {
+        let text = st.new_value_str(std::str::from_utf8(body)?)?;
+        Ok(Some(call_fn("builtins.fromTOML", st, &text, dir)?))
+    } else if type_ == mime::TEXT {
+        Ok(Some(st.new_value_str(std::str::from_utf8(body)?)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns a strong ETag for a file in the store, from its store path hash and subpath.
+fn store_etag(base_path: &Path, path: &Path) -> Option<String> {
+    let (hash, _) = base_path.file_name()?.to_str()?.split_once('-')?;
+    let subpath = path.strip_prefix(base_path).ok()?.to_str()?;
+    let subpath: String = url::form_urlencoded::byte_serialize(subpath.as_bytes()).collect();
+    Some(format!("\"{}/{}\"", hash, subpath))
+}
+
+/// Returns true if an If-None-Match header matches an ETag.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// What a route asked for in its extra attrset, besides its timeout.
+#[derive(Default)]
+struct RouteOptions {
+    directory: DirectoryOptions,
+
+    /// Whether to queue the body's build rather than make the client wait for it.
+    async_build: bool,
+
+    /// Whether to hand the body's build back to the server process, to check it against
+    /// --realise-policy and --max-download-size and build it with --build-timeout.
+    defer_build: bool,
+}
+
 /// Returns a FlackResponse with either a path or text, depending on whether
 /// the given string starts with a store path.
+/// If the client already has the path, answers with a 304 without realising anything.
 fn serve_path_or_text(
     response: &mut FlackResponse,
     st: &mut EvalState,
     dir: &str,
     value: &Value,
     content_type_set: bool,
+    request: &EvalRequest,
+    route: &RouteOptions,
 ) -> Result<FlackResponse, FlackResponse> {
     debug!("Forcing value");
     let (to_string_value, string_value) = call_string_fn("builtins.toString", st, value, dir)
-        .map_err(|err| response.server_error(err))?;
+        .map_err(|err| response.eval_error(err))?;
 
     // Could be a string that represents a store path.
     let mut store = st.store().clone();
     match get_safe_path(&mut store, &string_value) {
         Ok((base_path, path, store_path)) => {
-            debug!("Realising store path {:?}", base_path);
-            st.realise_string(&to_string_value, false)
+            let etag = store_etag(&base_path, &path);
+            if let Some(etag) = &etag
+                && request
+                    .header("if-none-match")
+                    .is_some_and(|if_none_match| etag_matches(if_none_match, etag))
+            {
+                debug!("Store path {:?} not modified", base_path);
+                response.add_header(header::ETAG.to_string(), etag.clone());
+                return Ok(response.not_modified_path(path));
+            }
+
+            // Sources and other opaque paths are already in the store, so only outputs need realising.
+            let context = st
+                .require_string_context(&to_string_value)
                 .map_err(|err| response.server_error(err))?;
-            debug!("Realised {:?}", store_path.name());
+            let mut drv_paths: Vec<String> = context
+                .iter()
+                .filter(|elem| elem.needs_build())
+                .map(|elem| elem.path().to_string())
+                .collect();
+            if !drv_paths.is_empty() {
+                if (route.async_build || route.defer_build)
+                    && !store
+                        .is_valid_path(&store_path)
+                        .map_err(|err| response.server_error(err))?
+                {
+                    drv_paths.sort();
+                    drv_paths.dedup();
+                    debug!("Deferring the build of {:?}", base_path);
+                    return Ok(response.accepted(jobs::PendingBuild {
+                        drv_paths,
+                        store_path: base_path.to_string_lossy().into_owned(),
+                        queue: route.async_build,
+                        path: string_value,
+                        directory: route.directory.clone(),
+                        content_type_set,
+                    }));
+                }
 
-            // Serve the store path.
-            Ok(response.ok_path(path.clone()))
+                debug!("Realising store path {:?}", base_path);
+                st.realise_string(&to_string_value, false).map_err(|err| {
+                    response.build_error(err, build_log_tail(&mut store, &drv_paths))
+                })?;
+                debug!("Realised {:?}", store_path.name());
+            } else {
+                debug!("Store path {:?} needs no build", base_path);
+            }
+            serve_store_path(
+                response,
+                &mut store,
+                &string_value,
+                request,
+                &route.directory,
+                content_type_set,
+            )
         }
         Err(err) => {
             match err.kind() {
@@ -553,306 +1088,804 @@ fn serve_path_or_text(
     }
 }
 
-/// The core Flack handler.
-/// This starts by assembling the request context, then immediately offloading eval to
-/// an Actix worker thread so it can assemble the request environment in Nix.
-/// This worker thread handles the current request, including calling into the Nix EvalState.
-/// Once the application is called, the result is unpacked and returned back to the toplevel Actix handler.
-async fn flack_handler(
-    req: HttpRequest,
-    request_body: web::Bytes,
+/// Serves a realised store path, or something in it, following symlinks ourselves and serving
+/// directories the way the route asked.
+fn serve_store_path(
+    response: &mut FlackResponse,
+    store: &mut Store,
+    path: &str,
+    request: &EvalRequest,
+    directory: &DirectoryOptions,
+    content_type_set: bool,
 ) -> Result<FlackResponse, FlackResponse> {
-    let app = req.app_data::<web::Data<FlackApp>>().unwrap().clone();
-
-    let port = app.args.port;
-
-    let http_version = format!("{:?}", req.version());
-    let now = format!("{:?}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis());
-    let request_id = Uuid::now_v7().to_string();
-    let str_inputs = [
-        ("RACK", "flack".to_string()),
-        ("REQUEST_METHOD", req.method().to_string()),
-        ("PATH_INFO", req.path().to_string()),
-        ("QUERY_STRING", req.query_string().to_string()),
-        ("SERVER_NAME", req.connection_info().host().to_string()),
-        ("SERVER_PROTOCOL", http_version.to_string()),
-        (
-            "rack.url_scheme",
-            req.connection_info().scheme().to_string(),
-        ),
-        ("flack.system", app.system.to_string()),
-        ("flack.request_id", request_id),
-        ("flack.request_timestamp", now),
-    ];
-    let int_inputs = [("SERVER_PORT", port as i64)];
-
-    let overrides = app.args.override_input.clone();
-    let headers = req.headers().clone();
-    let mime_type = req.mime_type();
-    let request_body_mutex = Arc::new(Mutex::<web::Bytes>::new(request_body));
-
-    // Offload eval once we've assembled the request context, since it will block.
-    web::block(move || {
-        let _guard = get_gc_guard();
+    let (base_path, path, store_path) =
+        get_safe_path(store, path).map_err(|err| response.server_error(err))?;
+    let etag = store_etag(&base_path, &path);
+    response
+        .store_paths
+        .push(base_path.to_string_lossy().into_owned());
+
+    // Follow symlinks ourselves, so they can't lead out of the closure.
+    let mut resolver =
+        Resolver::new(store, store_path, &base_path).map_err(|err| response.server_error(err))?;
+    let (path, stat) = match resolver.resolve(&path) {
+        Ok(resolved) => resolved,
+        Err(err)
+            if matches!(
+                err.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied
+            ) =>
+        {
+            warn!("Not serving store path: {}", err);
+            return Ok(response.not_found("no such store path"));
+        }
+        Err(err) => return Ok(response.server_error(err)),
+    };
 
-        let dir = app.args.dir.clone();
+    if let Stat::Directory = stat {
+        return Ok(directory::serve_directory(
+            response,
+            &mut resolver,
+            &path,
+            request,
+            directory,
+            content_type_set,
+        ));
+    }
 
-        let mut response = FlackResponse::new();
+    // Serve the store path.
+    if let Some(etag) = etag {
+        response.add_header(header::ETAG.to_string(), etag);
+    }
+    resolver
+        .serve(response, &path)
+        .map_err(|err| response.server_error(err))
+}
 
-        let mut st = app
-            .state
-            .get_cloned()
-            .map_err(|err| response.server_error(err))?;
+/// The request context, assembled on the Actix side before eval.
+/// In worker mode, this is what gets sent to the evaluator process.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+struct EvalRequest {
+    str_inputs: Vec<(String, String)>,
+    int_inputs: Vec<(String, i64)>,
+    headers: Vec<(String, String)>,
+    mime_type: Option<String>,
+    fields: Vec<(String, String)>,
+    uploads: Vec<upload::Upload>,
+    multipart: bool,
+    body_size: u64,
+
+    #[serde(skip)]
+    body: web::Bytes,
+}
 
-        let mut pairs =
-            Vec::with_capacity(str_inputs.len() + int_inputs.len() + overrides.len() + 1);
-        for (key, value) in str_inputs {
-            add_str_value(&mut response, &mut st, &mut pairs, key, value.as_str())?;
-        }
-        for (key, value) in int_inputs {
-            add_int_value(&mut response, &mut st, &mut pairs, key, value)?;
-        }
-        for name_value in overrides.chunks(2) {
-            let key = format!("flack.override.{}", name_value[0]);
-            add_str_value(
-                &mut response,
-                &mut st,
-                &mut pairs,
-                key.as_str(),
-                name_value[1].as_str(),
-            )?;
+/// Implementation for eval requests.
+impl EvalRequest {
+    /// Assembles the request context from an HTTP request.
+    fn new(
+        req: &HttpRequest,
+        app: &FlackApp,
+        body: upload::Body,
+    ) -> Result<EvalRequest, FlackResponse> {
+        let http_version = format!("{:?}", req.version());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let request_id = Uuid::now_v7().to_string();
+        let mut str_inputs = vec![
+            ("RACK".to_string(), "flack".to_string()),
+            ("REQUEST_METHOD".to_string(), req.method().to_string()),
+            ("PATH_INFO".to_string(), req.path().to_string()),
+            ("QUERY_STRING".to_string(), req.query_string().to_string()),
+            (
+                "SERVER_NAME".to_string(),
+                req.connection_info().host().to_string(),
+            ),
+            ("SERVER_PROTOCOL".to_string(), http_version),
+            (
+                "rack.url_scheme".to_string(),
+                req.connection_info().scheme().to_string(),
+            ),
+            ("flack.system".to_string(), app.system.to_string()),
+            ("flack.request_id".to_string(), request_id),
+            ("flack.request_timestamp".to_string(), now.to_string()),
+        ];
+        for name_value in app.args.override_input.chunks(2) {
+            str_inputs.push((
+                format!("flack.override.{}", name_value[0]),
+                name_value[1].to_string(),
+            ));
         }
+        let int_inputs = vec![
+            ("SERVER_PORT".to_string(), app.args.port as i64),
+            ("flack.request_timestamp_ms".to_string(), now),
+        ];
+
+        let headers = req
+            .headers()
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_str().unwrap_or("").to_string()))
+            .collect();
+
+        // Only complain about the content type if there's a body for it to describe.
+        let mime_type = match req.mime_type() {
+            Ok(mime) => mime.map(|mime| mime.to_string()),
+            Err(err) if body.size > 0 => return Err(FlackResponse::new().bad_request(err)),
+            Err(_) => None,
+        };
 
-        match headers.get("host") {
-            Some(v) => add_str_value(
-                &mut response,
-                &mut st,
-                &mut pairs,
-                "HTTP_HOST",
-                v.to_str().unwrap_or(""),
-            ),
-            None => Ok(()),
-        }?;
+        Ok(EvalRequest {
+            str_inputs,
+            int_inputs,
+            headers,
+            mime_type,
+            fields: body.fields,
+            uploads: body.uploads,
+            multipart: body.multipart,
+            body_size: body.size,
+            body: body.bytes,
+        })
+    }
 
-        match headers.get("content-type") {
-            Some(v) => add_str_value(
-                &mut response,
-                &mut st,
-                &mut pairs,
-                "CONTENT_TYPE",
-                v.to_str().unwrap_or(""),
-            ),
-            None => Ok(()),
-        }?;
+    /// Returns a string input, e.g. `PATH_INFO`.
+    fn input(&self, name: &str) -> Option<&str> {
+        self.str_inputs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
 
-        let request_body = request_body_mutex
-            .get_cloned()
-            .map_err(|err| response.server_error(err))?;
+    /// Returns the first value of a request header.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
 
-        if !request_body.is_empty() {
-            add_int_value(
-                &mut response,
-                &mut st,
-                &mut pairs,
-                "CONTENT_LENGTH",
-                request_body.len() as i64,
-            )?;
-
-            let json_body = match mime_type {
-                Ok(v) => match v {
-                    Some(mime) => match (mime.type_(), mime.subtype()) {
-                        (mime::APPLICATION, mime::JSON) => {
-                            let request_body_str = std::str::from_utf8(&request_body);
-                            match request_body_str {
-                                Ok(request_body) => Some(request_body),
-                                Err(err) => {
-                                    return Err(response.bad_request(err));
-                                }
-                            }
-                        }
-                        _ => None,
-                    },
-                    None => None,
-                },
-                Err(err) => {
-                    return Err(response.bad_request(err));
-                }
-            };
+/// Evals a request against the Flack app, and unpacks the result into a response.
+/// This blocks, so it runs on an Actix worker thread or in an evaluator process.
+fn eval_request(
+    args: &FlackArgs,
+    st: &mut EvalState,
+    flack_app: Value,
+    request: EvalRequest,
+    budget: &EvalBudget,
+) -> Result<FlackResponse, FlackResponse> {
+    let dir = args.dir.clone();
 
-            if let Some(body) = json_body {
-                let body_val = st
-                    .new_value_str(body)
-                    .map_err(|err| response.bad_request(err))?;
-                let body_attrset_val = call_fn("builtins.fromJSON", &mut st, &body_val, &dir)
-                    .map_err(|err| response.bad_request(err))?;
-                pairs.push(("flack.body".to_string(), body_attrset_val));
-            }
-        }
+    let mut response = FlackResponse::new();
 
-        for (key, value) in headers.iter() {
-            if key.as_str().eq("host") || key.as_str().eq("content-type") {
-                continue;
-            }
+    let mut pairs = Vec::with_capacity(
+        request.str_inputs.len() + request.int_inputs.len() + request.headers.len() + 2,
+    );
+    for (key, value) in &request.str_inputs {
+        add_str_value(&mut response, st, &mut pairs, key, value)?;
+    }
+    for (key, value) in &request.int_inputs {
+        add_int_value(&mut response, st, &mut pairs, key, *value)?;
+    }
 
-            let key_str = key.as_str();
-            if key_str
-                .chars()
-                .all(|c| matches!(c, 'A'..='Z' | 'a'..='z' | '-'))
-            {
-                let env_str = format!("HTTP_{}", key_str.to_ascii_uppercase().replace("-", "_"));
-                add_str_value(
-                    &mut response,
-                    &mut st,
-                    &mut pairs,
-                    &env_str,
-                    value.to_str().unwrap_or(""),
-                )?;
-            }
-        }
+    if let Some(host) = request.header("host") {
+        add_str_value(&mut response, st, &mut pairs, "HTTP_HOST", host)?;
+    }
 
-        let env = st
-            .new_value_attrs(pairs)
-            .map_err(|err| response.server_error(err))?;
+    if let Some(content_type) = request.header("content-type") {
+        add_str_value(&mut response, st, &mut pairs, "CONTENT_TYPE", content_type)?;
+    }
 
-        debug!("calling into app");
-        let flack_app = app
-            .app
-            .get_cloned()
-            .map_err(|err| response.server_error(err))?;
+    let request_body = &request.body;
 
-        let res = st
-            .call(flack_app, env)
-            .map_err(|err| response.server_error(err))?;
+    if request.body_size > 0 {
+        add_int_value(
+            &mut response,
+            st,
+            &mut pairs,
+            "CONTENT_LENGTH",
+            request.body_size as i64,
+        )?;
+    }
 
-        let length = st
-            .require_list_size(&res)
-            .map_err(|err| response.server_error(err))?;
-        if length < 3 {
-            return Err(response.server_error("result of flack app did not have length of at least 3"));
+    if request.multipart {
+        // Text fields go in the body, like a form post, and files go in their own attrset.
+        let mut params = BTreeMap::new();
+        for (key, value) in &request.fields {
+            insert_param(&mut params, key, Some(value.clone()));
         }
-        let code_val = match st
-            .require_list_select_idx_strict(&res, 0)
-            .map_err(|err| response.server_error(err))?
+        let body_val =
+            to_value(st, &params_to_json(params)).map_err(|err| response.server_error(err))?;
+        pairs.push(("flack.body".to_string(), body_val));
+
+        let files_val =
+            upload::files_value(st, &request.uploads).map_err(|err| response.server_error(err))?;
+        pairs.push(("flack.files".to_string(), files_val));
+    } else if let Some(upload) = request.uploads.first() {
+        // The body was too big to keep, so it's in the store.
+        let body_val =
+            upload::upload_value(st, upload).map_err(|err| response.server_error(err))?;
+        pairs.push(("flack.body".to_string(), body_val));
+    } else if !request_body.is_empty() {
+        let mime_type = request
+            .mime_type
+            .as_deref()
+            .map(mime::Mime::from_str)
+            .transpose()
+            .map_err(|err| response.bad_request(err))?;
+
+        // Pass the raw body along too, so long as Nix can hold it in a string.
+        if let Ok(input) = std::str::from_utf8(request_body)
+            && !input.contains('\0')
         {
-            Some(val) => val,
-            None => return Err(response.server_error("error getting code")),
-        };
-        let code = st
-            .require_int(&code_val)
-            .map_err(|err| response.server_error(err))?;
-        if (100..=599).contains(&code) {
-            response.code = code as u16;
-        } else {
-            return Err(response.server_error("invalid status code"));
+            add_str_value(&mut response, st, &mut pairs, "flack.input", input)?;
         }
 
-        let res_headers_value = match st
-            .require_list_select_idx_strict(&res, 1)
-            .map_err(|err| response.server_error(err))?
+        if let Some(body_val) = parse_body(st, &dir, mime_type.as_ref(), request_body)
+            .map_err(|err| response.bad_request(err))?
         {
-            Some(val) => val,
-            None => return Err(response.server_error("error getting headers")),
-        };
-        let res_headers_names = st
-            .require_attrs_names(&res_headers_value)
-            .map_err(|err| response.server_error(err))?;
+            pairs.push(("flack.body".to_string(), body_val));
+        }
+    }
 
-        let body = match st
-            .require_list_select_idx_strict(&res, 2)
-            .map_err(|err| response.server_error(err))?
-        {
-            Some(val) => val,
-            None => return Err(response.server_error("error getting body")),
-        };
+    // Headers may repeat, so group their values by name.
+    let mut headers: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (key, value) in &request.headers {
+        headers
+            .entry(key.as_str())
+            .or_default()
+            .push(value.as_str());
+    }
 
-        let body_type = st
-            .value_type(&body)
+    let mut header_lists = Vec::with_capacity(headers.len());
+    for (key, values) in &headers {
+        let list = values
+            .iter()
+            .map(|value| st.new_value_str(value))
+            .collect::<Result<Vec<_>, _>>()
             .map_err(|err| response.server_error(err))?;
+        let list = st
+            .new_value_list(list)
+            .map_err(|err| response.server_error(err))?;
+        header_lists.push((key.to_string(), list));
 
-        let mut content_type_set: bool = false;
-        for header_name in res_headers_names.iter() {
-            if !header_name.starts_with("_") && !header_name.ends_with("'")
-                && let Ok(val) = st.require_attrs_select(&res_headers_value, header_name)
-                && let Ok(header_value) = st.require_string(&val)
-            {
-                debug!("{}: {}", header_name, header_value);
-                if header_name.eq_ignore_ascii_case("content-type") {
-                    content_type_set = true;
-                }
-                response.add_header(header_name.to_string(), header_value.to_string());
-            }
+        if key.eq(&"host") || key.eq(&"content-type") {
+            continue;
         }
 
-        let ret = if body_type == ValueType::String {
-            serve_path_or_text(&mut response, &mut st, &dir, &body, content_type_set)
-        } else if body_type == ValueType::AttrSet {
-            // Could be a derivation.
-            let attrs_type = match st.require_attrs_select_opt(&body, "type") {
-                Ok(maybe_val) => match maybe_val {
-                    Some(val) => match st.require_string(&val) {
-                        Ok(val_str) => val_str,
-                        Err(_) => "attrs".to_string(),
-                    },
-                    None => "attrs".to_string(),
-                },
-                Err(_) => "attrs".to_string(),
-            };
-            if attrs_type.as_str().eq("derivation") {
-                serve_path_or_text(&mut response, &mut st, &dir, &body, content_type_set)
-            } else {
-                // Normal attrset, try to coerce to JSON
-                let (_, json_str) = call_string_fn("builtins.toJSON", &mut st, &body, &dir)
-                    .map_err(|err| response.server_error(err))?;
+        if key
+            .chars()
+            .all(|c| matches!(c, 'A'..='Z' | 'a'..='z' | '-'))
+        {
+            let env_str = format!("HTTP_{}", key.to_ascii_uppercase().replace("-", "_"));
+            add_str_value(&mut response, st, &mut pairs, &env_str, &values.join(", "))?;
+        }
+    }
 
-                if !content_type_set {
-                    // Default to application/json.
-                    let header = header::ContentType::json()
-                        .try_into_pair()
-                        .map_err(|err| response.server_error(err))?;
-                    let key = header.0.to_string();
-                    let value = header
-                        .1
-                        .to_str()
-                        .map_err(|err| response.server_error(err))?
-                        .to_string();
-                    response.add_header(key, value);
-                }
-                Ok(response.string(response.code, json_str))
-            }
-        } else {
-            Err(response.server_error("body was not a string or attribute set"))
-        };
+    let header_lists = st
+        .new_value_attrs(header_lists)
+        .map_err(|err| response.server_error(err))?;
+    pairs.push(("flack.headers".to_string(), header_lists));
 
-        let elapsed = response.stopwatch();
-        if elapsed.as_millis() >= app.args.gc_after as u128 {
-            info!("Request took {}ms, garbage collecting", elapsed.as_millis());
-            gc_now();
-            let gc_elapsed = response.stopwatch();
-            info!("GC took {}ms", gc_elapsed.as_millis());
-        } else {
-            debug!("Request took {}ms", elapsed.as_millis());
-        }
+    let env = st
+        .new_value_attrs(pairs)
+        .map_err(|err| response.server_error(err))?;
 
-        ret
-    })
-    .await
-    .map_err(|err| FlackResponse::new().server_error(err))?
-}
+    debug!("calling into app");
+    let res = st
+        .call(flack_app, env)
+        .map_err(|err| response.eval_error(err))?;
+
+    let length = st
+        .require_list_size(&res)
+        .map_err(|err| response.eval_error(err))?;
+    if length < 3 {
+        return Err(response.server_error("result of flack app did not have length of at least 3"));
+    }
+    let code_val = match st
+        .require_list_select_idx_strict(&res, 0)
+        .map_err(|err| response.eval_error(err))?
+    {
+        Some(val) => val,
+        None => return Err(response.server_error("error getting code")),
+    };
+    let code = st
+        .require_int(&code_val)
+        .map_err(|err| response.eval_error(err))?;
+    if (100..=599).contains(&code) {
+        response.code = code as u16;
+    } else {
+        return Err(response.server_error("invalid status code"));
+    }
+
+    // Routes may set their own eval timeout, directory handling and async builds.
+    let mut route = RouteOptions::default();
+    if length > 3
+        && let Some(extra) = st
+            .require_list_select_idx_strict(&res, 3)
+            .map_err(|err| response.eval_error(err))?
+    {
+        if let Some(timeout_val) = st
+            .require_attrs_select_opt(&extra, "timeout")
+            .map_err(|err| response.eval_error(err))?
+        {
+            let timeout = st
+                .require_int(&timeout_val)
+                .map_err(|err| response.eval_error(err))?;
+            debug!("Route asked for a timeout of {}ms", timeout);
+            budget.shorten(timeout.clamp(0, u32::MAX as i64) as u32);
+        }
+
+        if let Some(directory_val) = st
+            .require_attrs_select_opt(&extra, "directory")
+            .map_err(|err| response.eval_error(err))?
+        {
+            route.directory =
+                from_value(st, &directory_val).map_err(|err| response.eval_error(err))?;
+        }
+
+        if let Some(async_val) = st
+            .require_attrs_select_opt(&extra, "async")
+            .map_err(|err| response.eval_error(err))?
+        {
+            route.async_build = st
+                .require_bool(&async_val)
+                .map_err(|err| response.eval_error(err))?;
+        }
+    }
+
+    // Clients that want to watch the build get it queued too, so there's something to watch.
+    // Jobs send clients back with a GET once they're done, so only safe methods can have one.
+    route.async_build = args.build_queue > 0
+        && matches!(request.input("REQUEST_METHOD"), Some("GET" | "HEAD"))
+        && (route.async_build || jobs::wants_events(request.header("accept")));
+    route.defer_build = args.realise_policy != policy::RealisePolicy::All
+        || args.max_download_size.is_some()
+        || args.build_timeout.is_some();
+
+    let res_headers_value = match st
+        .require_list_select_idx_strict(&res, 1)
+        .map_err(|err| response.eval_error(err))?
+    {
+        Some(val) => val,
+        None => return Err(response.server_error("error getting headers")),
+    };
+    let res_headers_names = st
+        .require_attrs_names(&res_headers_value)
+        .map_err(|err| response.eval_error(err))?;
+
+    let body = match st
+        .require_list_select_idx_strict(&res, 2)
+        .map_err(|err| response.eval_error(err))?
+    {
+        Some(val) => val,
+        None => return Err(response.server_error("error getting body")),
+    };
+
+    let body_type = st
+        .value_type(&body)
+        .map_err(|err| response.eval_error(err))?;
+
+    let mut content_type_set: bool = false;
+    for header_name in res_headers_names.iter() {
+        if !header_name.starts_with("_")
+            && !header_name.ends_with("'")
+            && let Ok(val) = st.require_attrs_select(&res_headers_value, header_name)
+            && let Ok(header_value) = st.require_string(&val)
+        {
+            debug!("{}: {}", header_name, header_value);
+            if header_name.eq_ignore_ascii_case("content-type") {
+                content_type_set = true;
+            }
+            response.add_header(header_name.to_string(), header_value.to_string());
+        }
+    }
+
+    let ret = if body_type == ValueType::String {
+        serve_path_or_text(
+            &mut response,
+            st,
+            &dir,
+            &body,
+            content_type_set,
+            &request,
+            &route,
+        )
+    } else if body_type == ValueType::AttrSet {
+        // Could be a derivation.
+        let attrs_type = match st.require_attrs_select_opt(&body, "type") {
+            Ok(maybe_val) => match maybe_val {
+                Some(val) => match st.require_string(&val) {
+                    Ok(val_str) => val_str,
+                    Err(_) => "attrs".to_string(),
+                },
+                None => "attrs".to_string(),
+            },
+            Err(_) => "attrs".to_string(),
+        };
+        if attrs_type.as_str().eq("derivation") {
+            serve_path_or_text(
+                &mut response,
+                st,
+                &dir,
+                &body,
+                content_type_set,
+                &request,
+                &route,
+            )
+        } else {
+            // Normal attrset, coerce it to JSON the way Nix does, copying paths to the store.
+            let (_, json_str) = call_string_fn("builtins.toJSON", st, &body, &dir)
+                .map_err(|err| response.eval_error(err))?;
+
+            if !content_type_set {
+                // Default to application/json.
+                let header = header::ContentType::json()
+                    .try_into_pair()
+                    .map_err(|err| response.server_error(err))?;
+                let key = header.0.to_string();
+                let value = header
+                    .1
+                    .to_str()
+                    .map_err(|err| response.server_error(err))?
+                    .to_string();
+                response.add_header(key, value);
+            }
+            Ok(response.string(response.code, json_str))
+        }
+    } else {
+        Err(response.server_error("body was not a string or attribute set"))
+    };
+
+    let elapsed = response.stopwatch();
+    if elapsed.as_millis() >= args.gc_after as u128 {
+        info!("Request took {}ms, garbage collecting", elapsed.as_millis());
+        gc_now();
+        let gc_elapsed = response.stopwatch();
+        info!("GC took {}ms", gc_elapsed.as_millis());
+    } else {
+        debug!("Request took {}ms", elapsed.as_millis());
+    }
+
+    ret
+}
+
+/// How long an interrupted eval gets to unwind before its response gives up on it.
+const UNWIND_GRACE: Duration = Duration::from_secs(5);
+
+/// Waits for an eval, calling `expire` once it runs `grace` past the budget's deadline.
+/// Returns None if the eval ran out of time, whether or not it has stopped yet.
+async fn wait_with_budget<F: std::future::Future>(
+    work: F,
+    budget: &EvalBudget,
+    grace: Duration,
+    expire: impl FnOnce(),
+) -> Option<F::Output> {
+    tokio::pin!(work);
+
+    loop {
+        // Without a deadline, still wake up now and then in case the route sets one.
+        let wake = budget
+            .deadline()
+            .map(|deadline| deadline + grace)
+            .unwrap_or_else(|| Instant::now() + Duration::from_secs(1));
+        tokio::select! {
+            result = &mut work => return Some(result),
+            _ = tokio::time::sleep_until(wake.into()) => {
+                if budget.deadline().is_some_and(|deadline| Instant::now() >= deadline + grace) {
+                    warn!("Eval exceeded {}ms, interrupting", budget.timeout_ms());
+                    expire();
+
+                    // Let the eval unwind before giving up on it, but not for long: builds, primops
+                    // and store I/O don't check for interrupts, and finish on their own.
+                    if tokio::time::timeout(UNWIND_GRACE, &mut work).await.is_err() {
+                        warn!("Eval didn't stop within {}ms, leaving it to finish", UNWIND_GRACE.as_millis());
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Evals a request on an Actix worker thread in this process.
+async fn eval_in_process(
+    app: web::Data<FlackApp>,
+    request: EvalRequest,
+) -> Result<FlackResponse, FlackResponse> {
+    // Clone the state here so we hold its interrupt handle before eval starts.
+    let mut st = app
+        .state
+        .get_cloned()
+        .map_err(|err| FlackResponse::new().server_error(err))?;
+    let budget = EvalBudget::new(st.interrupt_handle(), app.args.timeout);
+    let eval_budget = budget.clone();
+    let interrupt = st.interrupt_handle();
+    let mut cancel_on_drop = CancelOnDrop(Some(move || interrupt.interrupt()));
+
+    // Offload eval, since it will block.
+    let work = web::block(move || {
+        let _guard = get_gc_guard();
+
+        let flack_app = app
+            .app
+            .get_cloned()
+            .map_err(|err| FlackResponse::new().server_error(err))?;
+
+        eval_request(&app.args, &mut st, flack_app, request, &eval_budget)
+    });
+
+    let result = wait_with_budget(work, &budget, Duration::ZERO, || {
+        budget.interrupt.interrupt()
+    })
+    .await;
+    cancel_on_drop.disarm();
+
+    match result {
+        Some(result) => result.map_err(|err| FlackResponse::new().server_error(err))?,
+        None => Err(budget.timed_out()),
+    }
+}
+
+/// The core Flack handler.
+/// This starts by reading the body, importing any uploads into the store, and assembling
+/// the request context. Then it hands the request off for eval: either to
+/// an Actix worker thread, or to an evaluator process if --workers is set.
+/// Either way, the app is called, and the result is unpacked and returned back to the toplevel Actix handler.
+/// Responses the app marked as cacheable skip all of this next time.
+async fn flack_handler(
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<FlackResponse, FlackResponse> {
+    let app = req.app_data::<web::Data<FlackApp>>().unwrap().clone();
+
+    let cache = app.cache.clone();
+    if let Some(cache) = &cache
+        && let Some(response) = cache.get(&req)
+    {
+        return Ok(response);
+    }
+
+    let body = upload::read_body(&req, payload, &app).await?;
+    let request = EvalRequest::new(&req, &app, body)?;
+
+    let mut response = eval(&app, request.clone()).await?;
+    if let Some(build) = response.build.take() {
+        // Nothing gets realised without passing the policy, and only builds are worth queueing.
+        // Once the path is there, serve what the route already evaluated to.
+        match policy::check(&app, &build).await? {
+            policy::Checked::Build if build.queue => return jobs::accept(&app, &req, build),
+            checked => policy::realise(&app, &build, checked).await?,
+        }
+        response = serve_built(&app, response, build, request).await?;
+    }
+
+    if !response.store_paths.is_empty() {
+        binary_cache::add(&app, response.store_paths.clone()).await;
+    }
+
+    if let Some(cache) = &cache {
+        cache.put(&req, &response);
+    }
+    Ok(response)
+}
+
+/// Serves the store path a deferred build was for, now that it's built.
+async fn serve_built(
+    app: &web::Data<FlackApp>,
+    mut response: FlackResponse,
+    build: jobs::PendingBuild,
+    request: EvalRequest,
+) -> Result<FlackResponse, FlackResponse> {
+    let app = app.clone();
+    web::block(move || {
+        let mut store = app
+            .state
+            .lock()
+            .map_err(|err| response.server_error(err.to_string()))?
+            .store()
+            .clone();
+        serve_store_path(
+            &mut response,
+            &mut store,
+            &build.path,
+            &request,
+            &build.directory,
+            build.content_type_set,
+        )
+    })
+    .await
+    .map_err(|err| FlackResponse::new().server_error(err))?
+}
+
+/// Evaluates a request in a worker, or in this process if there aren't any.
+async fn eval(
+    app: &web::Data<FlackApp>,
+    request: EvalRequest,
+) -> Result<FlackResponse, FlackResponse> {
+    match app.workers.clone() {
+        Some(workers) => {
+            let store = app
+                .state
+                .lock()
+                .map_err(|err| FlackResponse::new().server_error(err))?
+                .store()
+                .clone();
+            workers.eval(request, app.args.timeout, store).await
+        }
+        None => eval_in_process(app.clone(), request).await,
+    }
+}
+
+/// Query parameters for the cache purge endpoint.
+#[derive(serde::Deserialize)]
+struct PurgeQuery {
+    path: Option<String>,
+}
+
+/// Purges the response cache, or just the paths under `?path=`.
+/// Only requests with the --cache-purge-token-file token may purge.
+async fn purge_cache(req: HttpRequest, query: web::Query<PurgeQuery>) -> HttpResponse {
+    let app = req.app_data::<web::Data<FlackApp>>().unwrap().clone();
+
+    let authorized = app.purge_token.as_ref().is_some_and(|token| {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| tokens_match(given.as_bytes(), token.as_bytes()))
+    });
+
+    let response = if !authorized {
+        FlackResponse::new().forbidden("cache purges need the purge token")
+    } else if let Some(cache) = &app.cache {
+        let purged = cache.purge(query.path.as_deref());
+        info!("Purged {} cached responses", purged);
+        let mut response = FlackResponse::new();
+        response.add_header(
+            header::CONTENT_TYPE.to_string(),
+            mime::APPLICATION_JSON.to_string(),
+        );
+        response.string(200, serde_json::json!({ "purged": purged }))
+    } else {
+        FlackResponse::new().not_found("the response cache is disabled")
+    };
+
+    build_response(req, &response).await
+}
+
+/// Compares tokens in time that only depends on their lengths.
+fn tokens_match(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// A response body that another thread sends, chunk by chunk.
+struct ChannelBody {
+    rx: tokio::sync::mpsc::Receiver<std::io::Result<web::Bytes>>,
+
+    /// The length of the body, if it's known up front.
+    size: Option<u64>,
+}
+
+impl MessageBody for ChannelBody {
+    type Error = std::io::Error;
+
+    fn size(&self) -> BodySize {
+        self.size.map_or(BodySize::Stream, BodySize::Sized)
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<web::Bytes, Self::Error>>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Streams a file out of its store path's NAR, for stores that don't have it on this machine.
+fn stream_nar_file(req: &HttpRequest, file: nar::NarFile) -> HttpResponse {
+    let app = req.app_data::<web::Data<FlackApp>>().unwrap().clone();
+    let content_type = file
+        .subpath
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(
+            mime::APPLICATION_OCTET_STREAM,
+            actix_files::file_extension_to_mime,
+        );
+    let size = file.size;
+
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    actix_web::rt::task::spawn_blocking(move || {
+        let mut store = match app.state.lock() {
+            Ok(st) => st.store().clone(),
+            Err(err) => {
+                let _ = tx.blocking_send(Err(std::io::Error::other(err.to_string())));
+                return;
+            }
+        };
+        let result = nar::read_file(&mut store, &file, |chunk| {
+            tx.blocking_send(Ok(web::Bytes::copy_from_slice(chunk)))
+                .map_err(|_| std::io::Error::other("client went away"))
+        });
+        if let Err(err) = result {
+            warn!(
+                "Error streaming {:?} from {}: {}",
+                file.subpath, file.store_path, err
+            );
+            let _ = tx.blocking_send(Err(err));
+        }
+    });
+
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header((header::CONTENT_TYPE, content_type));
+    builder.body(ChannelBody {
+        rx,
+        size: Some(size),
+    })
+}
 
 /// This function builds an HttpResponse from a FlackResponse.
 /// It handles literal bodies, body paths, and errors that get serialized as JSON.
 async fn build_response(req: HttpRequest, response: &FlackResponse) -> HttpResponse {
-    if response.body.is_some() {
+    if let Some(job) = &response.body_events {
+        jobs::stream(&req, job)
+    } else if response.body.is_some() {
         let mut builder = response.to_builder();
         builder.status(StatusCode::from_u16(response.code).unwrap());
         builder.body(response.body.as_ref().unwrap().clone())
-    } else if response.body_path.is_some() {
+    } else if response.body_path.is_some() || response.body_nar.is_some() {
+        // Store paths are content-addressed, so unless the app says otherwise, they never go stale.
+        let etag = response.header(header::ETAG);
+        let immutable = etag.is_some() && response.header(header::CACHE_CONTROL).is_none();
+        let cache_control = HeaderValue::from_static("public, max-age=31536000, immutable");
+
+        if let Some(etag) = etag
+            && let Some(if_none_match) = req.headers().get(header::IF_NONE_MATCH)
+            && if_none_match
+                .to_str()
+                .is_ok_and(|if_none_match| etag_matches(if_none_match, etag))
+        {
+            debug!("Not modified: {}", etag);
+            let mut builder = response.to_builder();
+            builder.status(StatusCode::NOT_MODIFIED);
+            if immutable {
+                builder.insert_header((header::CACHE_CONTROL, cache_control));
+            }
+            return builder.finish();
+        }
+
+        if let Some(file) = &response.body_nar {
+            let mut res = stream_nar_file(&req, file.clone());
+            response.headers_into_response(&mut res);
+            if immutable {
+                res.headers_mut()
+                    .insert(header::CACHE_CONTROL, cache_control);
+            }
+            return res;
+        }
+
         match NamedFile::open_async(response.body_path.as_ref().unwrap().clone()).await {
             Ok(file) => {
                 if file.metadata().is_file() {
                     debug!("Serving file {:?}", response.body_path.as_ref().unwrap());
+                    // Store mtimes are all the same, so they make for weak validators.
+                    let file = if etag.is_some() {
+                        file.use_etag(false).use_last_modified(false)
+                    } else {
+                        file
+                    };
                     let mut res = file.into_response(&req);
                     response.headers_into_response(&mut res);
+                    if immutable {
+                        res.headers_mut()
+                            .insert(header::CACHE_CONTROL, cache_control);
+                    }
                     res
                 } else {
                     let cloned_response = response.clone().not_found("store path was not a file");
@@ -882,26 +1915,49 @@ async fn build_response(req: HttpRequest, response: &FlackResponse) -> HttpRespo
 }
 
 /// This is the toplevel Flack request handler.
-async fn flack(req: HttpRequest, body: web::Bytes) -> actix_web::Result<HttpResponse> {
-    match flack_handler(req.clone(), body).await {
-        Ok(response) => Ok(build_response(req.clone(), &response).await),
-        Err(response) => Ok(build_response(req.clone(), &response).await),
-    }
+async fn flack(req: HttpRequest, payload: web::Payload) -> actix_web::Result<HttpResponse> {
+    let app = req.app_data::<web::Data<FlackApp>>().unwrap().clone();
+    let response = match flack_handler(req.clone(), payload).await {
+        Ok(response) => response,
+        Err(response) => response,
+    };
+
+    // Keep what we're serving from being collected until it's been sent.
+    let roots = gc_roots::add(&app, &response.store_paths).await;
+    let res = build_response(req.clone(), &response).await;
+    Ok(match roots {
+        Some(roots) => roots.hold(res),
+        None => res,
+    })
 }
 
 /// Preloads the Flack app.
 fn preload(args: FlackArgs, st: &mut EvalState, project: Value, app: Value) -> std::io::Result<RealisedString> {
+    let to_string_value = preload_closure(&args, st, project, app)?;
+
+    if let Err(err) = log_missing(st, &to_string_value) {
+        warn!("Couldn't tell what the preload closure needs: {}", err);
+    }
+
+    // Allow IFD for app preloading.
+    let realised = st.realise_string(&to_string_value, true)
+        .map_err(std::io::Error::other)?;
+
+    Ok(realised)
+}
+
+/// Evaluates the app's preload closure to a string whose context is everything in it.
+fn preload_closure(
+    args: &FlackArgs,
+    st: &mut EvalState,
+    project: Value,
+    app: Value,
+) -> std::io::Result<Value> {
     let maybe_closure_fn = st.require_attrs_select_opt(&app, "mkClosure")
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))?;
 
     if let Some(closure_fn) = maybe_closure_fn {
-        let prev_substitute = nix_bindings_util::settings::get("substitute").unwrap_or("true".to_string());
-        if args.no_preload_substitute {
-            nix_bindings_util::settings::set("substitute", "false")
-                .map_err(std::io::Error::other)?;
-        }
-
-        let system = nix_bindings_util::settings::get("system").unwrap_or("unknown".to_string());
+        let system = settings::SYSTEM.get().unwrap_or("unknown".to_string());
         let system_val = st.new_value_str(system.as_str())
             .map_err(std::io::Error::other)?;
 
@@ -920,25 +1976,124 @@ fn preload(args: FlackArgs, st: &mut EvalState, project: Value, app: Value) -> s
         st.force(&closure)
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
-        let (to_string_value, _string_value) = call_string_fn(
-            "builtins.toString", st, &closure, &args.dir
-        )?;
-
-        // Allow IFD for app preloading.
-        let realised = st.realise_string(&to_string_value, true)
-            .map_err(std::io::Error::other)?;
-
-        if args.no_preload_substitute {
-            nix_bindings_util::settings::set("substitute", prev_substitute.as_str())
+        let (to_string_value, _string_value) =
+            call_string_fn("builtins.toString", st, &closure, &args.dir)
                 .map_err(std::io::Error::other)?;
-        }
 
-        Ok(realised)
+        Ok(to_string_value)
     } else {
         Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no mkClosure"))
     }
 }
 
+/// How many lines of a failed build's log go in its error response, like Nix's `log-lines`.
+const BUILD_LOG_LINES: usize = 25;
+
+/// Returns the options for builds the server process does.
+fn realise_options(args: &FlackArgs) -> RealiseOptions {
+    RealiseOptions {
+        timeout: args.build_timeout.map(Duration::from_secs),
+        ..RealiseOptions::default()
+    }
+}
+
+/// Returns the end of the log of whichever of these derivations failed to build, in debug mode.
+fn build_log_tail(store: &mut Store, drv_paths: &[String]) -> Option<String> {
+    if !log_enabled!(Level::Debug) {
+        return None;
+    }
+
+    let paths: Vec<StorePath> = drv_paths
+        .iter()
+        .filter_map(|drv_path| store.parse_store_path(drv_path).ok())
+        .collect();
+
+    // Whatever failed is still missing, and derivations that never got to build have no log.
+    let missing = store.query_missing(&paths).ok()?;
+    let log = missing
+        .will_build
+        .iter()
+        .find_map(|path| store.build_log(path).ok().flatten())?;
+    let lines: Vec<&str> = log.lines().collect();
+    Some(lines[lines.len().saturating_sub(BUILD_LOG_LINES)..].join("\n"))
+}
+
+/// Logs what realising the preload closure will build and download.
+fn log_missing(st: &mut EvalState, value: &Value) -> anyhow::Result<()> {
+    let mut store = st.store().clone();
+    let mut paths = Vec::new();
+    for elem in st.require_string_context(value)? {
+        if elem.needs_build() {
+            paths.push(store.parse_store_path(elem.path())?);
+        }
+    }
+
+    let missing = store.query_missing(&paths)?;
+    info!(
+        "Preload will build {} derivations and substitute {} paths ({} bytes to download, {} unpacked)",
+        missing.will_build.len(),
+        missing.will_substitute.len(),
+        missing.download_size,
+        missing.nar_size
+    );
+    if !missing.unknown.is_empty() {
+        warn!(
+            "Preload needs {} paths that can't be built or substituted",
+            missing.unknown.len()
+        );
+    }
+    Ok(())
+}
+
+/// Builds the preload closure for --no-preload-substitute, without substituting anything.
+/// Nix only has a global substitute setting, so this runs before the server accepts requests,
+/// which can then substitute; the preload finds everything already built.
+fn build_without_substitutes(
+    args: &FlackArgs,
+    st: &mut EvalState,
+    project: Value,
+    app: Value,
+) -> anyhow::Result<()> {
+    let value = preload_closure(args, st, project, app)?;
+
+    let _substitute = settings::SUBSTITUTE.scoped(&false)?;
+
+    // Daemons take our settings when we connect, so build on a connection made after the change.
+    let mut store = Store::open(Some(args.store.as_str()), [])?;
+    for elem in st.require_string_context(&value)? {
+        if elem.needs_build() {
+            let path = store.parse_store_path(elem.path())?;
+            store.realise(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the preload closure to another store for --copy-to.
+fn copy_closure(store: &mut Store, uri: &str, paths: &[StorePath]) {
+    info!("Copying the preload closure to {}...", uri);
+    let copy_start = Instant::now();
+
+    // We realised these ourselves, so the destination has to trust us rather than signatures.
+    let options = CopyClosureOptions::default();
+    let copied = Store::open(Some(uri), [])
+        .and_then(|mut dst| store.copy_closure(&mut dst, paths, &options));
+    match copied {
+        Ok(copied) => {
+            let copy_duration = Instant::now().saturating_duration_since(copy_start);
+            info!(
+                "Copied {} paths to {} in {}ms",
+                copied.len(),
+                uri,
+                copy_duration.as_millis()
+            );
+        }
+        Err(err) => {
+            error!("Couldn't copy the preload closure to {}: {:?}", uri, err);
+        }
+    }
+}
+
 /// Imports a project with idc.
 fn import_idc_project(args: &mut FlackArgs, st: &mut EvalState, name: String, path: String, toplevel: bool) -> std::io::Result<Value> {
     let import = std::fs::canonicalize(path)?
@@ -1069,7 +2224,8 @@ fn import_flake(args: &mut FlackArgs, st: &mut EvalState) -> std::io::Result<Val
         .map_err(std::io::Error::other)?;
 
     // Set the working directory to the flake's directory.
-    let (_, string_value) = call_string_fn("builtins.toString", st, &flake, &args.dir)?;
+    let (_, string_value) = call_string_fn("builtins.toString", st, &flake, &args.dir)
+        .map_err(std::io::Error::other)?;
 
     let mut flake_realisation_store = st.store().clone();
     match get_safe_path(&mut flake_realisation_store, &string_value) {
@@ -1091,6 +2247,75 @@ fn import_flake(args: &mut FlackArgs, st: &mut EvalState) -> std::io::Result<Val
     }
 }
 
+/// Returns the narHash of the locked flake, or None for projects that aren't flakes.
+fn get_nar_hash(st: &mut EvalState, project: &Value) -> Option<String> {
+    let nar_hash = st
+        .require_attrs_select_opt(project, "narHash")
+        .ok()
+        .flatten();
+    nar_hash.and_then(|val| st.require_string(&val).ok())
+}
+
+/// Connects to the store and loads the Flack app.
+/// Returns the eval state, its GC guard, the project, and the app.
+fn load_app(
+    args: &mut FlackArgs,
+    cores: u32,
+) -> std::io::Result<(EvalState, ThreadRegistrationGuard, Value, Value)> {
+    let store_uri = url::Url::parse_with_params(
+        args.store.as_str(),
+        &[(
+            "max-connections",
+            format!("{}", args.max_connections).as_str(),
+        )],
+    )
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    info!("Connecting to store: {}", store_uri);
+
+    let store = nix_bindings_store::store::Store::open(Some(store_uri.to_string().as_str()), [])
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e))?;
+
+    // Find out how we're going to load the Nix files.
+    // Either we're passed a flake ref, or an import, which we'll try to resolve using idc.
+    let (mut st, guard) = init_get_state(args.clone(), store, cores, args.import.is_none())
+        .map_err(std::io::Error::other)?;
+
+    let project = if let Some(ref import) = args.import.clone() {
+        import_idc_project(args, &mut st, "app".to_string(), import.to_string(), true)?
+    } else {
+        import_flake(args, &mut st)?
+    };
+
+    let mut app = project.clone();
+    if !args.attr.is_empty() && args.attr != "." {
+        let attr: Vec<String> = args.attr.split('.').map(str::to_string).collect();
+        for item in &attr {
+            app = match st
+                .require_attrs_select_opt(&app, item)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))?
+            {
+                Some(v) => {
+                    // Immediately drop the toplevel attribute to save memory.
+                    drop(app);
+                    v
+                }
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("attribute '{}' not found", item),
+                    ));
+                }
+            };
+        }
+    }
+
+    // Run a GC cycle.
+    gc_now();
+
+    Ok((st, guard, project, app))
+}
+
 /// The main application entrypoint. In order, we:
 /// - Parse command line arguments
 /// - Connect to the store
@@ -1137,64 +2362,99 @@ async fn main() -> std::io::Result<()> {
         args.max_connections = cores.get() as u16;
     }
 
-    let store_uri = url::Url::parse_with_params(
-        args.store.as_str(),
-        &[(
-            "max-connections",
-            format!("{}", args.max_connections).as_str(),
-        )],
-    )
-    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
-
     info!("Flack {} early startup", version);
 
-    info!("Connecting to store: {}", store_uri);
+    if args.worker {
+        return worker::run_worker(args);
+    }
 
-    let store = nix_bindings_store::store::Store::open(Some(store_uri.to_string().as_str()), [])
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e))?;
+    let (mut st, _guard, project, app) = load_app(&mut args, cores.get() as u32)?;
 
-    // Find out how we're going to load the Nix files.
-    // Either we're passed a flake ref, or an import, which we'll try to resolve using idc.
-    let (mut st, _guard) =
-        init_get_state(args.clone(), store, cores.get() as u32, args.import.is_none()).map_err(std::io::Error::other)?;
+    info!("App loaded successfully.");
 
-    let project = if let Some(ref import) = args.import.clone() {
-        import_idc_project(&mut args, &mut st, "app".to_string(), import.to_string(), true)?
+    if args.no_preload_substitute && !args.no_preload {
+        info!("Building the preload closure without substitutes...");
+        build_without_substitutes(&args, &mut st, project.clone(), app.clone())
+            .map_err(std::io::Error::other)?;
+    }
+
+    let cache = if args.cache_size > 0 {
+        // Imported projects have no narHash, so each load of one gets its own keys instead.
+        let nar_hash =
+            get_nar_hash(&mut st, &project).unwrap_or_else(|| Uuid::now_v7().to_string());
+        info!("Caching up to {} bytes of responses", args.cache_size);
+        Some(Arc::new(cache::ResponseCache::new(
+            args.cache_size,
+            Duration::from_secs(args.cache_ttl),
+            nar_hash,
+        )))
     } else {
-        import_flake(&mut args, &mut st)?
+        None
     };
 
-    let mut app = project.clone();
-    if !args.attr.is_empty() && args.attr != "." {
-        let attr: Vec<String> = args.attr.split('.').map(str::to_string).collect();
-        for item in &attr {
-            app = match st
-                .require_attrs_select_opt(&app, item)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))?
-            {
-                Some(v) => {
-                    // Immediately drop the toplevel attribute to save memory.
-                    drop(app);
-                    v
-                }
-                None => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::NotFound,
-                        format!("attribute '{}' not found", item),
-                    ));
-                }
-            };
+    let purge_token = match &args.cache_purge_token_file {
+        Some(path) => {
+            let token = std::fs::read_to_string(path)?.trim().to_string();
+            if token.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("the cache purge token in {:?} is empty", path),
+                ));
+            }
+            Some(Arc::new(token))
         }
-    }
+        None => None,
+    };
 
-    // Run a GC cycle.
-    gc_now();
+    let binary_cache = if args.binary_cache {
+        let mut store = st.store().clone();
+        let binary_cache = binary_cache::init(&mut store, args.secret_key_file.as_deref())
+            .map_err(std::io::Error::other)?;
+        info!("Serving a binary cache of built closures");
+        Some(binary_cache)
+    } else {
+        None
+    };
 
-    info!("App loaded successfully.");
+    let gc_roots = Arc::new(gc_roots::GcRoots::new(&args.host, args.port)?);
+
+    let policy = Arc::new(policy::Policy::new(
+        args.realise_policy,
+        args.allow_build.clone(),
+        args.max_download_size,
+    ));
+
+    let jobs = if args.build_queue > 0 {
+        Some(Arc::new(jobs::JobQueue::new(
+            args.build_queue,
+            args.build_jobs,
+        )))
+    } else {
+        None
+    };
 
     let host = args.host.clone();
     let port = args.port;
 
+    let workers = if args.workers > 0 {
+        // Locked-down workers can't write to the store, so only a daemon can realise for them.
+        let store_uri = st
+            .store()
+            .clone()
+            .get_uri()
+            .map_err(std::io::Error::other)?;
+        if store_uri != "daemon" && !store_uri.starts_with("unix://") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("--workers needs a daemon store, not {}", store_uri),
+            ));
+        }
+        info!("Starting {} workers", args.workers);
+        Some(worker::WorkerPool::start(args.workers)?)
+    } else {
+        None
+    };
+
     let args_mutex = Arc::new(Mutex::<FlackArgs>::new(args));
     let state_mutex = Arc::new(Mutex::<EvalState>::new(st.clone()));
     let project_mutex = Arc::new(Mutex::<Value>::new(project));
@@ -1202,7 +2462,8 @@ async fn main() -> std::io::Result<()> {
 
     static SERVER_START: Once = Once::new();
 
-    HttpServer::new(move || {
+    let release_gc_roots = gc_roots.clone();
+    let result = HttpServer::new(move || {
         let args_data = args_mutex.get_cloned().expect("no args");
         let state_data = state_mutex.get_cloned().expect("no state");
         let app_data = app_mutex.get_cloned().expect("no app");
@@ -1211,21 +2472,42 @@ async fn main() -> std::io::Result<()> {
         let mut preload_state = state_mutex.get_cloned().expect("no preload state");
         let preload_project = project_mutex.get_cloned().expect("no preload project");
         let preload_app = app_mutex.get_cloned().expect("no preload app");
+        let preload_binary_cache = binary_cache.clone();
+        let preload_gc_roots = gc_roots.clone();
+        let preload_policy = policy.clone();
+        let copy_to = preload_args.copy_to.clone();
 
         let log_host = args_data.host.clone();
         let log_port = args_data.port;
 
         let app = FlackApp {
             args: args_data,
-            system: nix_bindings_util::settings::get("system").unwrap_or("unknown".to_string()),
+            system: settings::SYSTEM.get().unwrap_or("unknown".to_string()),
             state: Arc::new(Mutex::<EvalState>::new(state_data)),
             app: Arc::new(Mutex::<Value>::new(app_data)),
+            workers: workers.clone(),
+            cache: cache.clone(),
+            purge_token: purge_token.clone(),
+            binary_cache: binary_cache.clone(),
+            gc_roots: gc_roots.clone(),
+            jobs: jobs.clone(),
+            policy: policy.clone(),
         };
 
-        let ret = App::new()
+        let mut ret = App::new()
             .wrap(actix_web::middleware::Logger::default())
             .app_data(web::Data::new(app))
-            .default_service(web::route().to(flack));
+            .route("/_flack/jobs/{id}", web::get().to(jobs::job));
+        if purge_token.is_some() {
+            ret = ret.route("/_flack/cache/purge", web::post().to(purge_cache));
+        }
+        if binary_cache.is_some() {
+            ret = ret
+                .route("/nix-cache-info", web::get().to(binary_cache::cache_info))
+                .route("/{hash}.narinfo", web::get().to(binary_cache::narinfo))
+                .route("/nar/{hash}.nar.zst", web::get().to(binary_cache::nar));
+        }
+        let ret = ret.default_service(web::route().to(flack));
 
         SERVER_START.call_once(|| {
             info!(
@@ -1256,6 +2538,30 @@ v{} bound to {}:{}
                 match preload(preload_args, &mut preload_state, preload_project, preload_app) {
                     Ok(closure) => {
                         info!("App preloaded: {}", closure.s);
+                        let mut store = preload_state.store().clone();
+                        // Whatever an earlier preload rooted may not be in this closure.
+                        preload_gc_roots.release();
+                        for path in &closure.paths {
+                            if let Err(err) = preload_gc_roots.add_perm(&mut store, path) {
+                                warn!("Couldn't root the preload closure: {}", err);
+                            }
+                        }
+                        if let Err(err) = preload_policy.add_preloaded(&mut store, &closure.paths) {
+                            warn!("Couldn't allow builds of the preload closure: {}", err);
+                        }
+                        if let Some(uri) = &copy_to {
+                            copy_closure(&mut store, uri, &closure.paths);
+                        }
+                        if let Some(binary_cache) = &preload_binary_cache {
+                            for path in &closure.paths {
+                                if let Err(err) = binary_cache.add(&mut store, path) {
+                                    warn!(
+                                        "Couldn't add the preload closure to the binary cache: {}",
+                                        err
+                                    );
+                                }
+                            }
+                        }
                     },
                     Err(err) => {
                         error!("App preload failed: {:?}", err);
@@ -1279,5 +2585,9 @@ v{} bound to {}:{}
     })
     .bind((host, port))?
     .run()
-    .await
+    .await;
+
+    // Let the preload closure be collected once we're no longer serving it.
+    release_gc_roots.release();
+    result
 }
@@ -0,0 +1,394 @@
+//! Reading store paths out of NARs.
+//!
+//! Binary caches and remote stores don't keep their paths anywhere we can open them, but any
+//! store can serialize a path as a NAR. We dump a path's NAR once into a temporary file,
+//! listing what's in it and where each file's contents start as it goes past, and serve
+//! files by reading them straight out of that copy. Paths whose NARs are too big to keep
+//! are refused before anything is dumped, and requests for a path being dumped wait for it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use log::debug;
+
+use nix_bindings_store::store::{NarReader, Store};
+
+/// The longest string we accept in a NAR, other than file contents.
+/// Names and symlink targets are both well under this.
+const MAX_STRING: u64 = 4096;
+
+/// How many listings to keep around.
+const MAX_LISTINGS: usize = 1024;
+
+/// How many bytes of NARs to keep around.
+const MAX_LISTINGS_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// How deeply directories may nest in a NAR. Paths this deep are already past PATH_MAX.
+const MAX_DEPTH: usize = 2048;
+
+/// Listings of store paths, by store path. Store paths never change, so neither do these.
+static LISTINGS: LazyLock<Mutex<Listings>> = LazyLock::new(|| Mutex::new(Listings::default()));
+
+/// The cached listings, evicted least recently used first.
+#[derive(Default)]
+struct Listings {
+    by_path: HashMap<String, (Arc<Listing>, u64)>,
+
+    /// A lock for each store path being dumped, held until its listing is cached.
+    dumping: HashMap<String, Arc<Mutex<()>>>,
+
+    size: u64,
+    clock: u64,
+}
+
+/// A store path's NAR, and what's in it.
+#[derive(Debug)]
+pub struct Listing {
+    /// The root of the store path.
+    pub root: Node,
+
+    /// An unlinked copy of the NAR, which goes away with the listing.
+    nar: File,
+
+    /// The size of the NAR.
+    size: u64,
+}
+
+/// A file, symlink or directory in a NAR.
+#[derive(Debug)]
+pub enum Node {
+    Regular {
+        executable: bool,
+        size: u64,
+
+        /// Where the file's contents start in the NAR.
+        offset: u64,
+    },
+    Symlink(PathBuf),
+    Directory(BTreeMap<OsString, Node>),
+}
+
+/// Implementation for NAR nodes.
+impl Node {
+    /// Looks up a path relative to this node, without following symlinks.
+    pub fn get(&self, path: &Path) -> Option<&Node> {
+        let mut node = self;
+        for component in path.components() {
+            match (component, node) {
+                (Component::Normal(name), Node::Directory(entries)) => node = entries.get(name)?,
+                (Component::CurDir, _) => {}
+                _ => return None,
+            }
+        }
+        Some(node)
+    }
+}
+
+/// A file in a store path's NAR, to be streamed out when the response is sent.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct NarFile {
+    /// The store path the file is in.
+    pub store_path: String,
+
+    /// Where the file is within the store path.
+    pub subpath: PathBuf,
+
+    /// The size of the file.
+    pub size: u64,
+}
+
+/// Returns an error for a malformed NAR.
+fn invalid<S: std::fmt::Display>(err: S) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("bad NAR: {}", err))
+}
+
+/// Starts dumping the NAR of a store path on its own thread, if it isn't too big to keep.
+/// Reads past the size the store has for the NAR fail.
+fn dump(store: &mut Store, store_path: &str) -> std::io::Result<std::io::Take<NarReader>> {
+    let path = store
+        .parse_store_path(store_path)
+        .map_err(std::io::Error::other)?;
+    let nar_size = store
+        .query_path_info(&path)
+        .map_err(std::io::Error::other)?
+        .nar_size;
+    if nar_size > MAX_LISTINGS_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::FileTooLarge,
+            format!("the NAR of {} is {} bytes", store_path, nar_size),
+        ));
+    }
+    Ok(store.nar_reader(&path).take(nar_size))
+}
+
+/// A pull parser for NARs, which copies the NAR out as it goes.
+struct Parser<R: Read, W: Write> {
+    r: R,
+    w: W,
+    pos: u64,
+}
+
+/// Implementation for NAR parsers.
+impl<R: Read, W: Write> Parser<R, W> {
+    /// Reads exactly enough bytes to fill the buffer, copying them out.
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.r.read_exact(buf)?;
+        self.w.write_all(buf)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Reads a little-endian integer.
+    fn int(&mut self) -> std::io::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Reads the zeroes that pad a string of the given length to 8 bytes.
+    fn padding(&mut self, len: u64) -> std::io::Result<()> {
+        let mut buf = [0u8; 8];
+        let pad = ((8 - len % 8) % 8) as usize;
+        self.read_exact(&mut buf[..pad])?;
+        if buf.iter().any(|b| *b != 0) {
+            return Err(invalid("non-zero padding"));
+        }
+        Ok(())
+    }
+
+    /// Reads a string.
+    fn string(&mut self) -> std::io::Result<Vec<u8>> {
+        let len = self.int()?;
+        if len > MAX_STRING {
+            return Err(invalid(format!("{} byte string", len)));
+        }
+        let mut buf = vec![0u8; len as usize];
+        self.read_exact(&mut buf)?;
+        self.padding(len)?;
+        Ok(buf)
+    }
+
+    /// Reads a string, which must be the given tag.
+    fn expect(&mut self, tag: &str) -> std::io::Result<()> {
+        let s = self.string()?;
+        if s != tag.as_bytes() {
+            return Err(invalid(format!(
+                "expected {:?}, got {:?}",
+                tag,
+                String::from_utf8_lossy(&s)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Reads file contents of the given size.
+    fn contents(&mut self, size: u64) -> std::io::Result<()> {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut left = size;
+        while left > 0 {
+            let len = left.min(buf.len() as u64) as usize;
+            self.read_exact(&mut buf[..len])?;
+            left -= len as u64;
+        }
+        self.padding(size)
+    }
+
+    /// Reads a node, nested in the given number of directories.
+    fn node(&mut self, depth: usize) -> std::io::Result<Node> {
+        if depth > MAX_DEPTH {
+            return Err(invalid(format!("nested more than {} deep", MAX_DEPTH)));
+        }
+        self.expect("(")?;
+        self.expect("type")?;
+        match self.string()?.as_slice() {
+            b"regular" => {
+                let mut tag = self.string()?;
+                let executable = tag == b"executable";
+                if executable {
+                    self.expect("")?;
+                    tag = self.string()?;
+                }
+                if tag != b"contents" {
+                    return Err(invalid("expected contents"));
+                }
+                let size = self.int()?;
+                let offset = self.pos;
+                self.contents(size)?;
+                self.expect(")")?;
+                Ok(Node::Regular {
+                    executable,
+                    size,
+                    offset,
+                })
+            }
+            b"symlink" => {
+                self.expect("target")?;
+                let target = PathBuf::from(OsString::from_vec(self.string()?));
+                self.expect(")")?;
+                Ok(Node::Symlink(target))
+            }
+            b"directory" => {
+                let mut entries = BTreeMap::new();
+                loop {
+                    match self.string()?.as_slice() {
+                        b")" => break,
+                        b"entry" => {
+                            self.expect("(")?;
+                            self.expect("name")?;
+                            let name = OsString::from_vec(self.string()?);
+                            if name.is_empty()
+                                || name == "."
+                                || name == ".."
+                                || name
+                                    .as_encoded_bytes()
+                                    .iter()
+                                    .any(|b| *b == b'/' || *b == 0)
+                            {
+                                return Err(invalid(format!("bad entry name {:?}", name)));
+                            }
+                            self.expect("node")?;
+                            let node = self.node(depth + 1)?;
+                            entries.insert(name, node);
+                            self.expect(")")?;
+                        }
+                        _ => return Err(invalid("expected an entry")),
+                    }
+                }
+                Ok(Node::Directory(entries))
+            }
+            _ => Err(invalid("unknown node type")),
+        }
+    }
+
+    /// Reads a whole NAR.
+    fn nar(&mut self) -> std::io::Result<Node> {
+        self.expect("nix-archive-1")?;
+        self.node(0)
+    }
+}
+
+/// Implementation for the listing cache.
+impl Listings {
+    /// Looks up a listing, marking it as used.
+    fn get(&mut self, store_path: &str) -> Option<Arc<Listing>> {
+        self.clock += 1;
+        let (listing, used) = self.by_path.get_mut(store_path)?;
+        *used = self.clock;
+        Some(listing.clone())
+    }
+
+    /// Adds a listing, evicting the least recently used ones to make room.
+    fn insert(&mut self, store_path: &str, listing: Arc<Listing>) {
+        if listing.size > MAX_LISTINGS_SIZE {
+            return;
+        }
+        while self.by_path.len() >= MAX_LISTINGS || self.size + listing.size > MAX_LISTINGS_SIZE {
+            let Some(oldest) = self
+                .by_path
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            if let Some((evicted, _)) = self.by_path.remove(&oldest) {
+                self.size -= evicted.size;
+            }
+        }
+        self.clock += 1;
+        self.size += listing.size;
+        self.by_path
+            .insert(store_path.to_string(), (listing, self.clock));
+    }
+}
+
+/// Lists the contents of a store path from its NAR, which is kept around to serve files from.
+/// Each store path is only dumped by one caller at a time; the others wait for its listing.
+pub fn list(store: &mut Store, store_path: &str) -> std::io::Result<Arc<Listing>> {
+    let dumping = {
+        let mut listings = LISTINGS
+            .lock()
+            .map_err(|_| std::io::Error::other("NAR listings poisoned"))?;
+        if let Some(listing) = listings.get(store_path) {
+            return Ok(listing);
+        }
+        listings
+            .dumping
+            .entry(store_path.to_string())
+            .or_default()
+            .clone()
+    };
+    let _dumping = dumping.lock().unwrap_or_else(|err| err.into_inner());
+    let result = list_uncached(store, store_path);
+    if let Ok(mut listings) = LISTINGS.lock()
+        && listings
+            .dumping
+            .get(store_path)
+            .is_some_and(|lock| Arc::ptr_eq(lock, &dumping))
+    {
+        listings.dumping.remove(store_path);
+    }
+    result
+}
+
+/// Lists a store path, unless whoever dumped it before us managed to.
+fn list_uncached(store: &mut Store, store_path: &str) -> std::io::Result<Arc<Listing>> {
+    if let Some(listing) = LISTINGS
+        .lock()
+        .ok()
+        .and_then(|mut listings| listings.get(store_path))
+    {
+        return Ok(listing);
+    }
+
+    debug!("Listing {} from its NAR", store_path);
+    let nar = tempfile::tempfile()?;
+    let mut parser = Parser {
+        r: dump(store, store_path)?,
+        w: BufWriter::new(&nar),
+        pos: 0,
+    };
+    let root = parser.nar()?;
+    let size = parser.pos;
+    parser.w.flush()?;
+    drop(parser);
+    let listing = Arc::new(Listing { root, nar, size });
+
+    if let Ok(mut listings) = LISTINGS.lock() {
+        listings.insert(store_path, listing.clone());
+    }
+    Ok(listing)
+}
+
+/// Streams the contents of a file out of its store path's NAR.
+pub fn read_file(
+    store: &mut Store,
+    file: &NarFile,
+    mut out: impl FnMut(&[u8]) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    debug!("Streaming {:?} from {}", file.subpath, file.store_path);
+    let listing = list(store, &file.store_path)?;
+    let Some(Node::Regular { size, offset, .. }) = listing.root.get(&file.subpath) else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{:?} is not a file in {}", file.subpath, file.store_path),
+        ));
+    };
+
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut pos = *offset;
+    let end = offset + size;
+    while pos < end {
+        let len = (end - pos).min(buf.len() as u64) as usize;
+        listing.nar.read_exact_at(&mut buf[..len], pos)?;
+        out(&buf[..len])?;
+        pos += len as u64;
+    }
+    Ok(())
+}
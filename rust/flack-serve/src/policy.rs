@@ -0,0 +1,272 @@
+//! What flack-serve may build for a request.
+//!
+//! Routes that interpolate request data into derivations let anyone who can reach the server
+//! start builds. `--realise-policy` limits that: `all` builds anything, `substitute` only serves
+//! paths that are already valid or can be substituted, and `allowlist` also builds derivations
+//! in the preload closure or with names matching `--allow-build`. `--max-download-size` caps
+//! what a request may have substituted.
+//!
+//! Evaluator processes can't see the preload closure, so under any policy but `all` they hand
+//! unbuilt paths back to the server process, which asks the store what realising them would
+//! build and download, and checks that here before anything is fetched.
+
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+
+use actix_web::web;
+
+use nix_bindings_store::path::StorePath;
+use nix_bindings_store::store::Store;
+
+use crate::jobs::PendingBuild;
+use crate::{FlackApp, FlackResponse, build_log_tail, realise_options};
+
+/// Which realisations are allowed.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RealisePolicy {
+    /// Build anything.
+    #[default]
+    All,
+
+    /// Only serve paths that are valid or can be substituted.
+    Substitute,
+
+    /// Also build derivations in the preload closure, or with names matching --allow-build.
+    Allowlist,
+}
+
+/// The realisation policy, and what it allows.
+pub struct Policy {
+    mode: RealisePolicy,
+
+    /// Derivation name patterns that may be built. `*` matches anything.
+    patterns: Vec<String>,
+
+    /// Derivations in the preload closure, by store path.
+    preloaded: RwLock<HashSet<String>>,
+
+    /// The most a request may have substituted, in compressed bytes.
+    max_download_size: Option<u64>,
+}
+
+/// What an allowed build would do.
+pub enum Checked {
+    /// Build at least one derivation.
+    Build,
+
+    /// Only substitute paths.
+    Substitute,
+}
+
+/// Implementation for realisation policies.
+impl Policy {
+    /// Creates a policy.
+    pub fn new(
+        mode: RealisePolicy,
+        patterns: Vec<String>,
+        max_download_size: Option<u64>,
+    ) -> Policy {
+        Policy {
+            mode,
+            patterns,
+            preloaded: RwLock::new(HashSet::new()),
+            max_download_size,
+        }
+    }
+
+    /// Allows building the derivations of a realised preload closure.
+    pub fn add_preloaded(&self, store: &mut Store, paths: &[StorePath]) -> anyhow::Result<()> {
+        if self.mode != RealisePolicy::Allowlist {
+            return Ok(());
+        }
+
+        let mut preloaded = self
+            .preloaded
+            .write()
+            .map_err(|err| anyhow!(err.to_string()))?;
+        let store_dir = store.get_storedir()?;
+        for path in paths {
+            for path in store.get_fs_closure(path, false, false, true)? {
+                if let Some(name) = path.name()?.strip_suffix(".drv") {
+                    debug!("Allowing builds of {}", name);
+                    preloaded.insert(logical_path(store, &store_dir, &path)?);
+                }
+            }
+        }
+        info!(
+            "Allowing builds of {} preloaded derivations",
+            preloaded.len()
+        );
+        Ok(())
+    }
+
+    /// Returns true if a derivation may be built.
+    fn may_build(&self, drv_path: &str) -> bool {
+        match self.mode {
+            RealisePolicy::All => true,
+            RealisePolicy::Substitute => false,
+            RealisePolicy::Allowlist => {
+                let name = drv_name(drv_path);
+                self.patterns
+                    .iter()
+                    .any(|pattern| glob_matches(pattern, name))
+                    || self
+                        .preloaded
+                        .read()
+                        .is_ok_and(|preloaded| preloaded.contains(drv_path))
+            }
+        }
+    }
+}
+
+/// Returns the path a request would name a store path by, which isn't where chroot stores keep it.
+fn logical_path(store: &mut Store, store_dir: &str, path: &StorePath) -> anyhow::Result<String> {
+    let real_path = store.real_path(path)?;
+    let base_name = real_path
+        .rsplit('/')
+        .next()
+        .ok_or_else(|| anyhow!("{} is not a store path", real_path))?;
+    Ok(format!("{}/{}", store_dir, base_name))
+}
+
+/// Returns the name of a derivation, without its hash or `.drv`.
+fn drv_name(drv_path: &str) -> &str {
+    let base = drv_path.rsplit('/').next().unwrap_or(drv_path);
+    let name = base.split_once('-').map_or(base, |(_, name)| name);
+    name.strip_suffix(".drv").unwrap_or(name)
+}
+
+/// Matches a name against a pattern where `*` matches any run of characters.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcards, so the whole name has to match.
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Works out what a build would do, and checks that against the policy and --max-download-size.
+/// Builds that aren't allowed get a 403, or a 503 when only substitution is allowed, and
+/// substitutions that would download too much get a 403.
+pub async fn check(
+    app: &web::Data<FlackApp>,
+    build: &PendingBuild,
+) -> Result<Checked, FlackResponse> {
+    let blocking_app = app.clone();
+    let drv_paths = build.drv_paths.clone();
+    let (will_build, download_size) = web::block(move || -> anyhow::Result<(Vec<String>, u64)> {
+        let mut store = blocking_app
+            .state
+            .lock()
+            .map_err(|err| anyhow!(err.to_string()))?
+            .store()
+            .clone();
+        let paths = drv_paths
+            .iter()
+            .map(|drv_path| store.parse_store_path(drv_path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let missing = store.query_missing(&paths)?;
+
+        let store_dir = store.get_storedir()?;
+        let will_build = missing
+            .will_build
+            .iter()
+            .map(|path| logical_path(&mut store, &store_dir, path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok((will_build, missing.download_size))
+    })
+    .await
+    .map_err(|err| FlackResponse::new().server_error(err))?
+    .map_err(|err| FlackResponse::new().server_error(err))?;
+
+    let policy = &app.policy;
+    let denied: Vec<&String> = will_build
+        .iter()
+        .filter(|drv_path| !policy.may_build(drv_path))
+        .collect();
+    if !denied.is_empty() {
+        warn!("Denied building {:?}", denied);
+        let message = format!("{} is not built, and can't be built here", build.store_path);
+        return Err(match policy.mode {
+            RealisePolicy::Substitute => FlackResponse::new().service_unavailable(message),
+            _ => FlackResponse::new().forbidden(message),
+        });
+    }
+
+    if let Some(max_download_size) = policy.max_download_size
+        && download_size > max_download_size
+    {
+        warn!(
+            "Denied downloading {} bytes for {}",
+            download_size, build.store_path
+        );
+        return Err(FlackResponse::new().forbidden(format!(
+            "{} would download {} bytes, more than the {} allowed",
+            build.store_path, download_size, max_download_size
+        )));
+    }
+
+    debug!(
+        "{} needs {} builds and a {} byte download",
+        build.store_path,
+        will_build.len(),
+        download_size
+    );
+    Ok(if will_build.is_empty() {
+        Checked::Substitute
+    } else {
+        Checked::Build
+    })
+}
+
+/// Builds the derivations a response is waiting on, while the client waits.
+/// Only substitutes them if that's all the check allowed.
+pub async fn realise(
+    app: &web::Data<FlackApp>,
+    build: &PendingBuild,
+    checked: Checked,
+) -> Result<(), FlackResponse> {
+    let app = app.clone();
+    let drv_paths = build.drv_paths.clone();
+    web::block(move || -> Result<(), FlackResponse> {
+        let mut store = app
+            .state
+            .lock()
+            .map_err(|err| FlackResponse::new().server_error(err))?
+            .store()
+            .clone();
+        let mut options = realise_options(&app.args);
+        if matches!(checked, Checked::Substitute) || app.policy.mode == RealisePolicy::Substitute {
+            // Nix falls back to building when a substituter fails, unless it can't build anything.
+            options.max_jobs = Some(0);
+        }
+        for drv_path in &drv_paths {
+            debug!("Realising {}", drv_path);
+            let path = store
+                .parse_store_path(drv_path)
+                .map_err(|err| FlackResponse::new().server_error(err))?;
+            store.realise_with_options(&path, &options).map_err(|err| {
+                FlackResponse::new().build_error(err, build_log_tail(&mut store, &drv_paths))
+            })?;
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|err| FlackResponse::new().server_error(err))?
+}
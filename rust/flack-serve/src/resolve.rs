@@ -0,0 +1,307 @@
+//! Symlink-safe resolution of store paths.
+//!
+//! Paths from the app are logical: they start with the store directory, like `/nix/store`.
+//! The resolver walks them one component at a time, mapping each store path to where it
+//! really lives with `Store::real_path`, so this works for chroot stores too. Store paths that
+//! aren't on this machine at all, like those in binary caches, are read from their NARs.
+//! Symlinks are followed by hand, and may only lead to the store path we started from or its closure.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use nix_bindings_store::path::StorePath;
+use nix_bindings_store::store::Store;
+
+use crate::nar::{self, NarFile, Node};
+use crate::{FlackResponse, store_etag};
+
+/// How many symlinks a path may pass through, like Linux's MAXSYMLINKS.
+const MAX_SYMLINKS: usize = 40;
+
+/// What a path is, without following symlinks.
+#[derive(Clone, Debug)]
+pub enum Stat {
+    File { size: u64 },
+    Directory,
+    Symlink(PathBuf),
+}
+
+/// Where the contents of a store path are.
+enum Mount {
+    /// On this machine, at the store path's real path.
+    Local(PathBuf),
+
+    /// Only in the store path's NAR, which this is the listing of.
+    Nar(Arc<nar::Listing>),
+}
+
+/// Resolves paths beneath a store path.
+pub struct Resolver<'a> {
+    store: &'a mut Store,
+    store_dir: PathBuf,
+    root: StorePath,
+    root_name: OsString,
+    closure: Option<HashSet<OsString>>,
+    mounts: HashMap<OsString, Mount>,
+}
+
+/// Returns an error for a path that tried to leave the closure.
+fn escaped(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        format!("{:?} leads out of the store path's closure", path),
+    )
+}
+
+/// Returns an error for a path that doesn't exist.
+fn not_found(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{:?} does not exist", path),
+    )
+}
+
+/// Stats a path on this machine, without following symlinks.
+fn local_stat(path: &Path) -> std::io::Result<Stat> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    Ok(if metadata.is_symlink() {
+        Stat::Symlink(std::fs::read_link(path)?)
+    } else if metadata.is_dir() {
+        Stat::Directory
+    } else {
+        Stat::File {
+            size: metadata.len(),
+        }
+    })
+}
+
+/// Implementation for stats.
+impl From<&Node> for Stat {
+    fn from(node: &Node) -> Stat {
+        match node {
+            Node::Regular { size, .. } => Stat::File { size: *size },
+            Node::Symlink(target) => Stat::Symlink(target.clone()),
+            Node::Directory(_) => Stat::Directory,
+        }
+    }
+}
+
+/// Implementation for resolvers.
+impl<'a> Resolver<'a> {
+    /// Creates a resolver for paths beneath the given store path, at the given logical base path.
+    pub fn new(
+        store: &'a mut Store,
+        root: StorePath,
+        base_path: &Path,
+    ) -> std::io::Result<Resolver<'a>> {
+        let store_dir = PathBuf::from(store.get_storedir().map_err(std::io::Error::other)?);
+        let root_name = base_path
+            .file_name()
+            .ok_or_else(|| std::io::Error::other("store path has no name"))?
+            .to_os_string();
+        Ok(Resolver {
+            store,
+            store_dir,
+            root,
+            root_name,
+            closure: None,
+            mounts: HashMap::new(),
+        })
+    }
+
+    /// Returns true if the named store path is the root or in its closure.
+    fn allowed(&mut self, name: &OsStr) -> std::io::Result<bool> {
+        if name == self.root_name {
+            return Ok(true);
+        }
+        if self.closure.is_none() {
+            let mut closure = HashSet::new();
+            let paths = self
+                .store
+                .get_fs_closure(&self.root, false, false, false)
+                .map_err(std::io::Error::other)?;
+            for path in paths {
+                let real_path = self.store.real_path(&path).map_err(std::io::Error::other)?;
+                if let Some(name) = Path::new(&real_path).file_name() {
+                    closure.insert(name.to_os_string());
+                }
+            }
+            self.closure = Some(closure);
+        }
+        Ok(self
+            .closure
+            .as_ref()
+            .is_some_and(|closure| closure.contains(name)))
+    }
+
+    /// Splits a logical path into its store path's name and the rest.
+    fn split(&self, logical: &Path) -> std::io::Result<(OsString, PathBuf)> {
+        let rest = logical
+            .strip_prefix(&self.store_dir)
+            .map_err(|_| escaped(logical))?;
+        let mut components = rest.components();
+        let Some(Component::Normal(name)) = components.next() else {
+            return Err(escaped(logical));
+        };
+        Ok((name.to_os_string(), components.as_path().to_path_buf()))
+    }
+
+    /// Finds where the named store path's contents are.
+    /// Local paths are preferred, and anything else is listed from its NAR.
+    fn mount(&mut self, name: &OsStr) -> std::io::Result<&Mount> {
+        if !self.mounts.contains_key(name) {
+            let logical = self.store_dir.join(name);
+            let logical = logical
+                .to_str()
+                .ok_or_else(|| std::io::Error::other("store path is not UTF-8"))?;
+            let store_path = self
+                .store
+                .parse_store_path(logical)
+                .map_err(std::io::Error::other)?;
+            let real_path = PathBuf::from(
+                self.store
+                    .real_path(&store_path)
+                    .map_err(std::io::Error::other)?,
+            );
+            let mount = if std::fs::symlink_metadata(&real_path).is_ok() {
+                Mount::Local(real_path)
+            } else {
+                Mount::Nar(nar::list(self.store, logical)?)
+            };
+            self.mounts.insert(name.to_os_string(), mount);
+        }
+        Ok(&self.mounts[name])
+    }
+
+    /// Stats a logical path, without following symlinks.
+    pub fn stat(&mut self, logical: &Path) -> std::io::Result<Stat> {
+        let (name, rest) = self.split(logical)?;
+        match self.mount(&name)? {
+            Mount::Local(base) => local_stat(&base.join(rest)),
+            Mount::Nar(listing) => listing
+                .root
+                .get(&rest)
+                .map(Stat::from)
+                .ok_or_else(|| not_found(logical)),
+        }
+    }
+
+    /// Lists a resolved logical directory, without following symlinks.
+    pub fn read_dir(&mut self, logical: &Path) -> std::io::Result<Vec<(OsString, Stat)>> {
+        let (name, rest) = self.split(logical)?;
+        match self.mount(&name)? {
+            Mount::Local(base) => {
+                let mut entries = Vec::new();
+                for entry in std::fs::read_dir(base.join(rest))? {
+                    let entry = entry?;
+                    entries.push((entry.file_name(), local_stat(&entry.path())?));
+                }
+                Ok(entries)
+            }
+            Mount::Nar(listing) => match listing.root.get(&rest) {
+                Some(Node::Directory(entries)) => Ok(entries
+                    .iter()
+                    .map(|(name, node)| (name.clone(), Stat::from(node)))
+                    .collect()),
+                _ => Err(not_found(logical)),
+            },
+        }
+    }
+
+    /// Serves a resolved logical file with a 200 OK, wherever it is.
+    pub fn serve(
+        &mut self,
+        response: &mut FlackResponse,
+        logical: &Path,
+    ) -> std::io::Result<FlackResponse> {
+        let (name, rest) = self.split(logical)?;
+        let store_path = self.store_dir.join(&name);
+        response.served_path = Some(logical.to_path_buf());
+        match self.mount(&name)? {
+            Mount::Local(base) => Ok(response.ok_path(base.join(rest))),
+            Mount::Nar(listing) => match listing.root.get(&rest) {
+                Some(Node::Regular { size, .. }) => Ok(response.ok_nar(NarFile {
+                    store_path: store_path.to_string_lossy().to_string(),
+                    subpath: rest,
+                    size: *size,
+                })),
+                _ => Err(not_found(logical)),
+            },
+        }
+    }
+
+    /// Returns the ETag for a resolved logical path.
+    pub fn etag(&self, path: &Path) -> Option<String> {
+        let name = path
+            .strip_prefix(&self.store_dir)
+            .ok()?
+            .components()
+            .next()?;
+        store_etag(&self.store_dir.join(name), path)
+    }
+
+    /// Resolves a logical path, following symlinks.
+    /// Returns the resolved logical path and what's there.
+    pub fn resolve(&mut self, path: &Path) -> std::io::Result<(PathBuf, Stat)> {
+        let rest = path
+            .strip_prefix(&self.store_dir)
+            .map_err(|_| escaped(path))?;
+        let mut queue: VecDeque<OsString> = rest
+            .components()
+            .map(|component| component.as_os_str().to_os_string())
+            .collect();
+        let mut current = self.store_dir.clone();
+        let mut symlinks = 0;
+
+        while let Some(component) = queue.pop_front() {
+            if component == ".." {
+                current.pop();
+                if !current.starts_with(&self.store_dir) {
+                    return Err(escaped(path));
+                }
+                continue;
+            } else if component == "." || component.is_empty() {
+                continue;
+            }
+
+            current.push(&component);
+            if current.parent() == Some(self.store_dir.as_path()) && !self.allowed(&component)? {
+                return Err(escaped(path));
+            }
+
+            let Stat::Symlink(target) = self.stat(&current)? else {
+                continue;
+            };
+
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(std::io::Error::other(format!(
+                    "too many symlinks in {:?}",
+                    path
+                )));
+            }
+
+            current.pop();
+            let target = if target.is_absolute() {
+                current = self.store_dir.clone();
+                target
+                    .strip_prefix(&self.store_dir)
+                    .map_err(|_| escaped(path))?
+                    .to_path_buf()
+            } else {
+                target
+            };
+            for component in target.components().rev() {
+                queue.push_front(component.as_os_str().to_os_string());
+            }
+        }
+
+        if current == self.store_dir {
+            return Err(escaped(path));
+        }
+        let stat = self.stat(&current)?;
+        Ok((current, stat))
+    }
+}
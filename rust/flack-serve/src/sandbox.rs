@@ -0,0 +1,218 @@
+//! Confinement for evaluator processes.
+//!
+//! Workers lock themselves down once the app is loaded, in two layers:
+//! a Landlock ruleset that only allows reading the store and the app directory,
+//! and seccomp filters that refuse syscalls an evaluator has no business making.
+//! Realisation only works through a daemon, which does the building: a worker can't write
+//! to a local store, so flack-serve refuses `--workers` without a daemon store.
+//!
+//! Landlock doesn't restrict connecting to Unix sockets, so seccomp refuses `socket` and
+//! `connect` outright. Workers connect to the daemon before locking down, and keep using
+//! that connection; anything else, like the Docker socket, is out of reach.
+
+use std::path::PathBuf;
+
+#[cfg(target_os = "linux")]
+use log::{info, warn};
+
+/// Syscalls that evaluators have no use for.
+#[cfg(target_os = "linux")]
+const DENIED_SYSCALLS: &[i64] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_setns,
+    libc::SYS_unshare,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_userfaultfd,
+    libc::SYS_personality,
+    libc::SYS_io_uring_setup,
+    libc::SYS_socket,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+];
+
+/// Flags that would give a cloned process new namespaces.
+#[cfg(target_os = "linux")]
+const NAMESPACE_FLAGS: &[libc::c_int] = &[
+    libc::CLONE_NEWNS,
+    libc::CLONE_NEWUTS,
+    libc::CLONE_NEWIPC,
+    libc::CLONE_NEWUSER,
+    libc::CLONE_NEWPID,
+    libc::CLONE_NEWNET,
+    libc::CLONE_NEWCGROUP,
+];
+
+/// Locks down the current process, allowing reads beneath the given paths only.
+#[cfg(target_os = "linux")]
+pub fn lock_down(readable: &[PathBuf]) -> std::io::Result<()> {
+    restrict_filesystem(readable)?;
+    restrict_syscalls()
+}
+
+/// Locks down the current process. Only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn lock_down(_readable: &[PathBuf]) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "evaluator sandboxing is only supported on Linux",
+    ))
+}
+
+/// Applies a Landlock ruleset that makes everything but the given paths inaccessible,
+/// and those paths read-only.
+/// Note that this only covers the calling thread and the threads it starts afterwards.
+#[cfg(target_os = "linux")]
+fn restrict_filesystem(readable: &[PathBuf]) -> std::io::Result<()> {
+    use landlock::{
+        ABI, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+        path_beneath_rules,
+    };
+
+    let abi = ABI::V5;
+
+    // The Boehm GC reads its own memory maps.
+    let mut paths = readable.to_vec();
+    paths.push(PathBuf::from("/proc/self"));
+
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))
+        .map_err(std::io::Error::other)?
+        .create()
+        .map_err(std::io::Error::other)?
+        .add_rules(path_beneath_rules(&paths, AccessFs::from_read(abi)))
+        .map_err(std::io::Error::other)?
+        .restrict_self()
+        .map_err(std::io::Error::other)?;
+
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => {
+            info!("Landlock ruleset enforced for {:?}", paths);
+            Ok(())
+        }
+        RulesetStatus::PartiallyEnforced => {
+            warn!("Landlock ruleset only partially enforced; a newer kernel would do better");
+            Ok(())
+        }
+        RulesetStatus::NotEnforced => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "the kernel does not support Landlock",
+        )),
+    }
+}
+
+/// Applies seccomp filters to every thread that fail denied syscalls with EPERM.
+/// No new sockets may be made or connected, and clone may not create namespaces.
+/// clone3 fails with ENOSYS, since its flags can't be inspected, and libc falls back to clone.
+#[cfg(target_os = "linux")]
+fn restrict_syscalls() -> std::io::Result<()> {
+    use seccompiler::{
+        BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+        SeccompRule, TargetArch,
+    };
+    use std::collections::BTreeMap;
+
+    #[cfg(target_arch = "x86_64")]
+    seccompiler::apply_filter_all_threads(&x32_filter()).map_err(std::io::Error::other)?;
+
+    // An empty rule list matches the syscall unconditionally.
+    let mut rules: BTreeMap<i64, Vec<SeccompRule>> = DENIED_SYSCALLS
+        .iter()
+        .map(|syscall| (*syscall, Vec::new()))
+        .collect();
+
+    // Rules for the same syscall match if any of them do.
+    let namespace_rules = NAMESPACE_FLAGS
+        .iter()
+        .map(|flag| {
+            let flag = *flag as u64;
+            SeccompCondition::new(
+                0,
+                SeccompCmpArgLen::Qword,
+                SeccompCmpOp::MaskedEq(flag),
+                flag,
+            )
+            .and_then(|condition| SeccompRule::new(vec![condition]))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(std::io::Error::other)?;
+    rules.insert(libc::SYS_clone, namespace_rules);
+
+    let arch = TargetArch::try_from(std::env::consts::ARCH).map_err(std::io::Error::other)?;
+    let clone3 = SeccompFilter::new(
+        BTreeMap::from([(libc::SYS_clone3, Vec::new())]),
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::ENOSYS as u32),
+        arch,
+    )
+    .map_err(std::io::Error::other)?;
+    let program: BpfProgram = clone3.try_into().map_err(std::io::Error::other)?;
+    seccompiler::apply_filter_all_threads(&program).map_err(std::io::Error::other)?;
+
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        arch,
+    )
+    .map_err(std::io::Error::other)?;
+    let program: BpfProgram = filter.try_into().map_err(std::io::Error::other)?;
+
+    seccompiler::apply_filter_all_threads(&program).map_err(std::io::Error::other)?;
+
+    info!("Seccomp filter applied");
+    Ok(())
+}
+
+/// Returns a filter that refuses x32 syscalls, which are numbered from 0x40000000 and so
+/// don't match any of the rules for their x86_64 counterparts.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn x32_filter() -> seccompiler::BpfProgram {
+    use seccompiler::sock_filter;
+
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+    let op = |code: u32, jt: u8, jf: u8, k: u32| sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    vec![
+        // Load the syscall number.
+        op(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 0, 0, 0),
+        op(
+            libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+            0,
+            1,
+            X32_SYSCALL_BIT,
+        ),
+        op(
+            libc::BPF_RET | libc::BPF_K,
+            0,
+            0,
+            libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+        ),
+        op(libc::BPF_RET | libc::BPF_K, 0, 0, libc::SECCOMP_RET_ALLOW),
+    ]
+}
@@ -0,0 +1,389 @@
+//! Request bodies that go into the store.
+//!
+//! File parts of `multipart/form-data` bodies, and other bodies too big to keep in memory,
+//! are streamed to temporary files and imported into the store as flat, content-addressed
+//! paths. Apps get them as store path strings with context, so derivations can use them
+//! directly. With `--upload-roots`, each upload is rooted there for `--upload-ttl` seconds.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use futures_util::StreamExt as _;
+use log::{debug, warn};
+
+use actix_web::{HttpMessage, HttpRequest, mime, web};
+
+use nix_bindings_expr::eval_state::{EvalState, StringContextElem};
+use nix_bindings_expr::value::Value;
+
+use crate::{FlackApp, FlackResponse, Param, call_fn, get_gc_guard, insert_param};
+
+/// The longest name Nix accepts for a store path.
+const MAX_NAME: usize = 211;
+
+/// An uploaded file, once it's in the store.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Upload {
+    /// The form field the file came in, or None if it was the whole body.
+    pub field: Option<String>,
+
+    /// The store path.
+    pub path: String,
+
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub size: u64,
+}
+
+/// A request body, as far as it's been read.
+#[derive(Default)]
+pub struct Body {
+    /// The body, if it was small enough to keep in memory.
+    pub bytes: web::Bytes,
+
+    /// The fields of a multipart body that weren't files.
+    pub fields: Vec<(String, String)>,
+
+    /// Files that were imported into the store.
+    pub uploads: Vec<Upload>,
+
+    /// Whether the body was multipart.
+    pub multipart: bool,
+
+    /// The number of bytes received.
+    pub size: u64,
+}
+
+/// A file that's still being received.
+struct Pending {
+    field: Option<String>,
+    filename: Option<String>,
+    content_type: Option<String>,
+    file: tempfile::NamedTempFile,
+    size: u64,
+}
+
+/// Implementation for pending uploads.
+impl Pending {
+    /// Starts receiving a file.
+    fn new(
+        field: Option<String>,
+        filename: Option<String>,
+        content_type: Option<String>,
+    ) -> Result<Pending, FlackResponse> {
+        Ok(Pending {
+            field,
+            filename,
+            content_type,
+            file: tempfile::NamedTempFile::new()
+                .map_err(|err| FlackResponse::new().server_error(err))?,
+            size: 0,
+        })
+    }
+
+    /// Appends a chunk of the file.
+    fn write(&mut self, chunk: &[u8]) -> Result<(), FlackResponse> {
+        self.file
+            .write_all(chunk)
+            .map_err(|err| FlackResponse::new().server_error(err))?;
+        self.size += chunk.len() as u64;
+        Ok(())
+    }
+}
+
+/// Returns true if a body of this type has to be parsed, so can't be imported into the store.
+fn needs_parsing(mime_type: Option<&mime::Mime>) -> bool {
+    mime_type.is_some_and(|mime_type| {
+        mime_type.type_() == mime::APPLICATION
+            && (mime_type.subtype() == mime::JSON
+                || mime_type.subtype() == mime::WWW_FORM_URLENCODED
+                || mime_type.subtype() == "toml")
+    })
+}
+
+/// Returns the error for a body that's over the limit.
+fn too_large(limit: impl std::fmt::Display) -> FlackResponse {
+    FlackResponse::new().payload_too_large(format!("request body is over {} bytes", limit))
+}
+
+/// Reads a request body, importing files into the store.
+pub async fn read_body(
+    req: &HttpRequest,
+    mut payload: web::Payload,
+    app: &web::Data<FlackApp>,
+) -> Result<Body, FlackResponse> {
+    let args = &app.args;
+    let mime_type = req.mime_type().ok().flatten();
+    if mime_type
+        .as_ref()
+        .is_some_and(|mime_type| mime_type.essence_str() == mime::MULTIPART_FORM_DATA.essence_str())
+    {
+        return read_multipart(req, payload, app).await;
+    }
+
+    let mut bytes = web::BytesMut::new();
+    let mut spilled: Option<Pending> = None;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|err| FlackResponse::new().bad_request(err))?;
+        if let Some(pending) = &mut spilled {
+            if pending.size + chunk.len() as u64 > args.max_upload_size {
+                return Err(too_large(args.max_upload_size));
+            }
+            pending.write(&chunk)?;
+        } else if bytes.len() + chunk.len() <= args.max_body_size {
+            bytes.extend_from_slice(&chunk);
+        } else if args.max_upload_size == 0 || needs_parsing(mime_type.as_ref()) {
+            return Err(too_large(args.max_body_size));
+        } else {
+            debug!(
+                "Request body is over {} bytes, spilling it",
+                args.max_body_size
+            );
+            let mut pending = Pending::new(None, None, mime_type.as_ref().map(|m| m.to_string()))?;
+            pending.write(&bytes)?;
+            pending.write(&chunk)?;
+            bytes.clear();
+            spilled = Some(pending);
+        }
+    }
+
+    match spilled {
+        Some(pending) => {
+            let size = pending.size;
+            Ok(Body {
+                uploads: import(app, vec![pending]).await?,
+                size,
+                ..Body::default()
+            })
+        }
+        None => Ok(Body {
+            size: bytes.len() as u64,
+            bytes: bytes.freeze(),
+            ..Body::default()
+        }),
+    }
+}
+
+/// Reads a `multipart/form-data` body. Fields with filenames are files; the rest are text.
+async fn read_multipart(
+    req: &HttpRequest,
+    payload: web::Payload,
+    app: &web::Data<FlackApp>,
+) -> Result<Body, FlackResponse> {
+    let args = &app.args;
+    if args.max_upload_size == 0 {
+        return Err(FlackResponse::new().payload_too_large("uploads are disabled"));
+    }
+
+    let mut multipart = actix_multipart::Multipart::new(req.headers(), payload);
+    let mut fields = Vec::new();
+    let mut pending = Vec::new();
+    let mut fields_size = 0;
+    let mut files_size = 0;
+    while let Some(field) = multipart.next().await {
+        let mut field = field.map_err(|err| FlackResponse::new().bad_request(err))?;
+        let name = field.name().unwrap_or_default().to_string();
+        let filename = field
+            .content_disposition()
+            .and_then(|disposition| disposition.get_filename())
+            .map(str::to_string);
+
+        if filename.is_some() {
+            let content_type = field.content_type().map(|m| m.to_string());
+            let mut file = Pending::new(Some(name), filename, content_type)?;
+            while let Some(chunk) = field.next().await {
+                let chunk = chunk.map_err(|err| FlackResponse::new().bad_request(err))?;
+                files_size += chunk.len() as u64;
+                if files_size > args.max_upload_size {
+                    return Err(too_large(args.max_upload_size));
+                }
+                file.write(&chunk)?;
+            }
+            pending.push(file);
+        } else {
+            let mut value = Vec::new();
+            while let Some(chunk) = field.next().await {
+                let chunk = chunk.map_err(|err| FlackResponse::new().bad_request(err))?;
+                fields_size += chunk.len();
+                if fields_size > args.max_body_size {
+                    return Err(too_large(args.max_body_size));
+                }
+                value.extend_from_slice(&chunk);
+            }
+            let value =
+                String::from_utf8(value).map_err(|err| FlackResponse::new().bad_request(err))?;
+            fields.push((name, value));
+        }
+    }
+
+    Ok(Body {
+        fields,
+        uploads: import(app, pending).await?,
+        multipart: true,
+        size: fields_size as u64 + files_size,
+        ..Body::default()
+    })
+}
+
+/// Returns a store path name for an upload, going by its filename.
+fn store_name(filename: Option<&str>) -> String {
+    let name: String = filename
+        .and_then(|filename| Path::new(filename).file_name())
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "+-._?=".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .skip_while(|c| *c == '.')
+        .take(MAX_NAME)
+        .collect();
+    if name.is_empty() {
+        "upload".to_string()
+    } else {
+        name
+    }
+}
+
+/// Imports files into the store.
+async fn import(
+    app: &web::Data<FlackApp>,
+    pending: Vec<Pending>,
+) -> Result<Vec<Upload>, FlackResponse> {
+    if pending.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let app = app.clone();
+    web::block(move || {
+        let _guard = get_gc_guard();
+        let mut st = app
+            .state
+            .get_cloned()
+            .map_err(|err| FlackResponse::new().server_error(err))?;
+
+        let mut uploads = Vec::with_capacity(pending.len());
+        for file in pending {
+            let name = store_name(file.filename.as_deref());
+            let path = import_file(&mut st, &app.args.dir, file.file.path(), &name)
+                .map_err(|err| FlackResponse::new().server_error(err))?;
+            debug!("Imported {} bytes as {}", file.size, path);
+
+            if let Some(roots) = &app.args.upload_roots
+                && let Err(err) = add_root(roots, &path, Duration::from_secs(app.args.upload_ttl))
+            {
+                warn!("Couldn't root {} in {:?}: {}", path, roots, err);
+            }
+
+            uploads.push(Upload {
+                field: file.field,
+                path,
+                filename: file.filename,
+                content_type: file.content_type,
+                size: file.size,
+            });
+        }
+        Ok(uploads)
+    })
+    .await
+    .map_err(|err| FlackResponse::new().server_error(err))?
+}
+
+/// Imports a file into the store as a flat, content-addressed path, returning the store path.
+fn import_file(st: &mut EvalState, dir: &str, file: &Path, name: &str) -> anyhow::Result<String> {
+    let path = st.new_value_path(file)?;
+    let name = st.new_value_str(name)?;
+    let args = st.new_value_attrs([("path".to_string(), path), ("name".to_string(), name)])?;
+    let store_path = call_fn(
+        "args: builtins.path (args // { recursive = false; })",
+        st,
+        &args,
+        dir,
+    )?;
+    st.require_string(&store_path)
+}
+
+/// Roots a store path in the upload roots directory, and unroots anything that has expired.
+fn add_root(roots: &Path, store_path: &str, ttl: Duration) -> std::io::Result<()> {
+    let now = SystemTime::now();
+    for entry in std::fs::read_dir(roots)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_symlink()
+            && metadata
+                .modified()
+                .is_ok_and(|modified| modified + ttl < now)
+        {
+            debug!("Unrooting {:?}", entry.path());
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+
+    // Replace any existing link, so the TTL starts again.
+    let link = roots.join(
+        Path::new(store_path)
+            .file_name()
+            .ok_or_else(|| std::io::Error::other("store path has no name"))?,
+    );
+    if let Err(err) = std::fs::remove_file(&link)
+        && err.kind() != std::io::ErrorKind::NotFound
+    {
+        return Err(err);
+    }
+    std::os::unix::fs::symlink(store_path, link)
+}
+
+/// Returns an upload as a Nix attrset, `{ path, filename, contentType, size }`,
+/// where `path` depends on the store path.
+pub fn upload_value(st: &mut EvalState, upload: &Upload) -> anyhow::Result<Value> {
+    let path = st.new_value_str_with_context(
+        &upload.path,
+        &[StringContextElem::Opaque {
+            path: upload.path.clone(),
+        }],
+    )?;
+    let filename = match &upload.filename {
+        Some(filename) => st.new_value_str(filename)?,
+        None => st.new_value_null()?,
+    };
+    let content_type = match &upload.content_type {
+        Some(content_type) => st.new_value_str(content_type)?,
+        None => st.new_value_null()?,
+    };
+    let size = st.new_value_int(upload.size as i64)?;
+    st.new_value_attrs([
+        ("path".to_string(), path),
+        ("filename".to_string(), filename),
+        ("contentType".to_string(), content_type),
+        ("size".to_string(), size),
+    ])
+}
+
+/// Returns the uploaded files of a multipart body as a Nix attrset, by field.
+/// `foo[]` fields are lists, like they are in query strings.
+pub fn files_value(st: &mut EvalState, uploads: &[Upload]) -> anyhow::Result<Value> {
+    let mut params = BTreeMap::new();
+    for upload in uploads {
+        if let Some(field) = &upload.field {
+            let value = upload_value(st, upload)?;
+            insert_param(&mut params, field, Some(value));
+        }
+    }
+
+    let mut attrs = Vec::with_capacity(params.len());
+    for (key, param) in params {
+        let value = match param {
+            Param::One(Some(value)) => value,
+            Param::One(None) => st.new_value_null()?,
+            Param::Many(values) => st.new_value_list(values)?,
+        };
+        attrs.push((key, value));
+    }
+    st.new_value_attrs(attrs)
+}
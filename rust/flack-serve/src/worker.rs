@@ -0,0 +1,573 @@
+//! Sandboxed evaluator processes.
+//!
+//! With `--workers N`, flack-serve runs itself N more times with `--worker`. Each worker
+//! loads the app into its own EvalState, locks itself down (see the sandbox module), and
+//! evals requests that the server sends it over stdin, answering over stdout.
+//!
+//! Frames on either pipe are a little-endian u32 length followed by that many bytes.
+//! Messages are JSON; request bodies follow their request as a raw frame.
+//!
+//! Workers aren't trusted any more than the app is. Files they serve are resolved again from
+//! their logical paths, and the store paths they name are checked again, by the server.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+
+use actix_web::{Either, web};
+use tokio::sync::Semaphore;
+
+use nix_bindings_store::store::Store;
+use nix_bindings_util::interrupt::InterruptHandle;
+
+use crate::{
+    CancelOnDrop, EvalBudget, EvalRequest, FlackArgs, FlackError, FlackResponse, eval_request,
+    get_safe_path, jobs::PendingBuild, load_app, resolve::Resolver, sandbox, wait_with_budget,
+};
+
+/// How long past its deadline a worker gets to answer before it's killed.
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// How often a worker checks whether its current request is over budget.
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The largest frame either side will accept.
+const MAX_FRAME: usize = 256 * 1024 * 1024;
+
+/// Messages from the server to a worker.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+enum ToWorker {
+    /// Eval a request. Followed by a frame containing the request body.
+    Request(u64, EvalRequest),
+
+    /// Interrupt the request with this serial, if it's still running.
+    Cancel(u64),
+}
+
+/// Messages from a worker to the server.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+enum FromWorker {
+    /// The app is loaded and the worker is locked down.
+    Ready,
+
+    /// The route shortened the current request's timeout (milliseconds).
+    Timeout(u32),
+
+    /// The result of the current request.
+    Response(WireResponse),
+}
+
+/// A FlackResponse, in a form that can cross the process boundary.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct WireResponse {
+    ok: bool,
+    code: u16,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+    served_path: Option<PathBuf>,
+    error: Option<(String, String, Option<String>)>,
+    store_paths: Vec<String>,
+    build: Option<PendingBuild>,
+}
+
+/// Implementation for wire responses.
+impl WireResponse {
+    /// Packs up the result of an eval.
+    fn new(result: Result<FlackResponse, FlackResponse>) -> WireResponse {
+        let (ok, response) = match result {
+            Ok(response) => (true, response),
+            Err(response) => (false, response),
+        };
+        WireResponse {
+            ok,
+            code: response.code,
+            headers: response
+                .headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_str().unwrap_or("").to_string()))
+                .collect(),
+            body: response.body,
+            served_path: response.served_path,
+            error: response
+                .error
+                .map(|error| (error.error, error.long, error.log)),
+            store_paths: response.store_paths,
+            build: response.build,
+        }
+    }
+
+    /// Unpacks the result of an eval, checking every path in it against the server's store.
+    fn into_result(self, store: &mut Store) -> Result<FlackResponse, FlackResponse> {
+        let mut response = FlackResponse::new();
+        response.code = self.code;
+        for (key, value) in self.headers {
+            response.add_header(key, value);
+        }
+        if let Some(body) = self.body {
+            response.set(0, Either::Right(Either::Left(body)));
+        }
+        if let Some(served_path) = self.served_path {
+            resolve_served(&mut response, store, &served_path).map_err(|err| {
+                FlackResponse::new()
+                    .server_error(format!("worker served {:?}: {}", served_path, err))
+            })?;
+        }
+        for store_path in &self.store_paths {
+            check_store_path(store, store_path)
+                .map_err(|err| FlackResponse::new().server_error(err))?;
+        }
+        if let Some(build) = &self.build {
+            check_store_path(store, &build.store_path)
+                .map_err(|err| FlackResponse::new().server_error(err))?;
+            let (base_path, _, _) = get_safe_path(store, &build.path)
+                .map_err(|err| FlackResponse::new().server_error(err))?;
+            if base_path != Path::new(&build.store_path) {
+                return Err(FlackResponse::new().server_error(format!(
+                    "worker asked to serve {} after building {}",
+                    build.path, build.store_path
+                )));
+            }
+            for drv_path in &build.drv_paths {
+                if !drv_path.ends_with(".drv") {
+                    return Err(FlackResponse::new().server_error(format!(
+                        "worker asked to build {}, which isn't a derivation",
+                        drv_path
+                    )));
+                }
+                check_store_path(store, drv_path)
+                    .map_err(|err| FlackResponse::new().server_error(err))?;
+            }
+        }
+        response.store_paths = self.store_paths;
+        response.build = self.build;
+        if let Some((error, long, log)) = self.error {
+            response.set(0, Either::Left(FlackError { error, long, log }));
+        }
+        if self.ok { Ok(response) } else { Err(response) }
+    }
+}
+
+/// Resolves a file that a worker served from its logical path, as if the server had served it.
+fn resolve_served(
+    response: &mut FlackResponse,
+    store: &mut Store,
+    logical: &Path,
+) -> std::io::Result<()> {
+    let logical = logical
+        .to_str()
+        .ok_or_else(|| std::io::Error::other("path is not UTF-8"))?;
+    let (base_path, path, store_path) = get_safe_path(store, logical)?;
+    let mut resolver = Resolver::new(store, store_path, &base_path)?;
+    let (path, _) = resolver.resolve(&path)?;
+    let code = response.code;
+    resolver.serve(response, &path)?;
+    response.code = code;
+    Ok(())
+}
+
+/// Checks that a path from a worker is a store path itself, not something in or outside one.
+fn check_store_path(store: &mut Store, path: &str) -> std::io::Result<()> {
+    let (base_path, full_path, _) = get_safe_path(store, path)?;
+    if base_path != full_path {
+        return Err(std::io::Error::other(format!(
+            "{} is not a store path",
+            path
+        )));
+    }
+    Ok(())
+}
+
+/// Writes a frame.
+fn write_frame(w: &mut impl Write, bytes: &[u8]) -> std::io::Result<()> {
+    let len = u32::try_from(bytes.len()).map_err(std::io::Error::other)?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(bytes)?;
+    w.flush()
+}
+
+/// Reads a frame.
+fn read_frame(r: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", len),
+        ));
+    }
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Writes a message frame.
+fn write_message<T: serde::Serialize>(w: &mut impl Write, message: &T) -> std::io::Result<()> {
+    write_frame(
+        w,
+        &serde_json::to_vec(message).map_err(std::io::Error::other)?,
+    )
+}
+
+/// Reads a message frame.
+fn read_message<T: serde::de::DeserializeOwned>(r: &mut impl Read) -> std::io::Result<T> {
+    serde_json::from_slice(&read_frame(r)?)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// A worker process, as seen from the server.
+/// Dropping it kills the process.
+struct Worker {
+    id: u32,
+    serial: u64,
+    child: Arc<Mutex<Child>>,
+    stdin: Arc<Mutex<ChildStdin>>,
+    stdout: ChildStdout,
+}
+
+/// Implementation for workers.
+impl Worker {
+    /// Spawns a worker with our own arguments, and waits for it to load the app.
+    fn spawn() -> std::io::Result<Worker> {
+        let mut child = Command::new(std::env::current_exe()?)
+            .args(std::env::args_os().skip(1))
+            .arg("--worker")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| std::io::Error::other("worker has no stdin"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| std::io::Error::other("worker has no stdout"))?;
+
+        let mut worker = Worker {
+            id: child.id(),
+            serial: 0,
+            child: Arc::new(Mutex::new(child)),
+            stdin: Arc::new(Mutex::new(stdin)),
+            stdout,
+        };
+
+        debug!("Waiting for worker {}", worker.id);
+        match read_message(&mut worker.stdout)? {
+            FromWorker::Ready => Ok(worker),
+            message => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("worker sent {:?} before it was ready", message),
+            )),
+        }
+    }
+
+    /// Sends a request, and waits for the unchecked result.
+    /// An error means the worker is no longer usable.
+    fn eval(&mut self, request: EvalRequest, budget: &EvalBudget) -> std::io::Result<WireResponse> {
+        let body = request.body.clone();
+        {
+            let mut stdin = self
+                .stdin
+                .lock()
+                .map_err(|_| std::io::Error::other("worker stdin poisoned"))?;
+            write_message(&mut *stdin, &ToWorker::Request(self.serial, request))?;
+            write_frame(&mut *stdin, &body)?;
+        }
+
+        loop {
+            match read_message(&mut self.stdout)? {
+                FromWorker::Timeout(timeout_ms) => budget.shorten(timeout_ms),
+                FromWorker::Response(response) => return Ok(response),
+                FromWorker::Ready => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "worker sent ready during a request",
+                    ));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if let Ok(mut child) = self.child.lock() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Asks a worker to interrupt a request.
+fn send_cancel(stdin: &Mutex<ChildStdin>, serial: u64) {
+    if let Ok(mut stdin) = stdin.lock()
+        && let Err(err) = write_message(&mut *stdin, &ToWorker::Cancel(serial))
+    {
+        warn!("Couldn't cancel worker request: {}", err);
+    }
+}
+
+/// A pool of worker processes.
+/// Each permit on the semaphore corresponds to an idle worker.
+pub struct WorkerPool {
+    idle: Mutex<Vec<Worker>>,
+    available: Semaphore,
+}
+
+/// Implementation for worker pools.
+impl WorkerPool {
+    /// Starts the first worker, failing if it can't load the app and lock itself down, then
+    /// the rest in the background. Requests wait until one is ready.
+    pub fn start(count: u16) -> std::io::Result<Arc<WorkerPool>> {
+        let pool = Arc::new(WorkerPool {
+            idle: Mutex::new(Vec::with_capacity(count as usize)),
+            available: Semaphore::new(0),
+        });
+        let worker = Worker::spawn().map_err(|err| {
+            std::io::Error::new(err.kind(), format!("starting a worker: {}", err))
+        })?;
+        info!("Worker {} is ready", worker.id);
+        pool.release(worker);
+        for _ in 1..count {
+            pool.respawn();
+        }
+        Ok(pool)
+    }
+
+    /// Spawns a worker in the background, retrying until it comes up.
+    fn respawn(self: &Arc<Self>) {
+        let pool = self.clone();
+        std::thread::spawn(move || {
+            let mut backoff = Duration::from_millis(250);
+            loop {
+                match Worker::spawn() {
+                    Ok(worker) => {
+                        info!("Worker {} is ready", worker.id);
+                        pool.release(worker);
+                        return;
+                    }
+                    Err(err) => {
+                        error!(
+                            "Error starting worker, retrying in {}ms: {}",
+                            backoff.as_millis(),
+                            err
+                        );
+                        std::thread::sleep(backoff);
+                        backoff = (backoff * 2).min(Duration::from_secs(30));
+                    }
+                }
+            }
+        });
+    }
+
+    /// Returns an idle worker to the pool.
+    fn release(&self, worker: Worker) {
+        self.idle.lock().expect("worker pool poisoned").push(worker);
+        self.available.add_permits(1);
+    }
+
+    /// Evals a request on the next idle worker.
+    /// Workers that die or stop making sense are replaced.
+    pub async fn eval(
+        self: &Arc<Self>,
+        request: EvalRequest,
+        timeout_ms: u32,
+        mut store: Store,
+    ) -> Result<FlackResponse, FlackResponse> {
+        // The worker enforces the deadline itself, and tells us when the route moves it.
+        // Nothing evaluates here, so this budget's interrupt handle goes unused.
+        let budget = EvalBudget::new(InterruptHandle::new(), timeout_ms);
+        let eval_budget = budget.clone();
+
+        // Waiting for a worker counts against the request's budget.
+        let permit = match budget.deadline() {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), self.available.acquire())
+                .await
+                .map_err(|_| budget.timed_out())?,
+            None => self.available.acquire().await,
+        };
+        permit
+            .map_err(|err| FlackResponse::new().server_error(err))?
+            .forget();
+        let mut worker = self
+            .idle
+            .lock()
+            .expect("worker pool poisoned")
+            .pop()
+            .expect("worker pool had a permit but no worker");
+
+        worker.serial = worker.serial.wrapping_add(1);
+        let serial = worker.serial;
+        let stdin = worker.stdin.clone();
+        let child = worker.child.clone();
+        let mut cancel_on_drop = CancelOnDrop(Some(move || send_cancel(&stdin, serial)));
+
+        let pool = self.clone();
+        let work = web::block(move || {
+            let id = worker.id;
+            match worker.eval(request, &eval_budget) {
+                Ok(response) => {
+                    pool.release(worker);
+                    response.into_result(&mut store)
+                }
+                Err(err) => {
+                    error!("Worker {} failed, replacing it: {}", id, err);
+                    drop(worker);
+                    pool.respawn();
+                    Err(FlackResponse::new().server_error(format!("worker {} failed: {}", id, err)))
+                }
+            }
+        });
+
+        // Only kill the worker if it doesn't stop on its own.
+        let result = wait_with_budget(work, &budget, KILL_GRACE, || {
+            if let Ok(mut child) = child.lock() {
+                let _ = child.kill();
+            }
+        })
+        .await;
+        cancel_on_drop.disarm();
+
+        match result {
+            Some(result) => result.map_err(|err| FlackResponse::new().server_error(err))?,
+            None => Err(budget.timed_out()),
+        }
+    }
+}
+
+/// Takes over stdout for frames, pointing fd 1 at stderr so stray output can't corrupt them.
+fn take_stdout() -> std::io::Result<std::fs::File> {
+    use std::os::fd::FromRawFd;
+
+    let fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+}
+
+/// Runs an evaluator process: loads the app, locks down, and evals requests until stdin closes.
+pub fn run_worker(mut args: FlackArgs) -> std::io::Result<()> {
+    let output = Arc::new(Mutex::new(take_stdout()?));
+
+    // Locked-down workers can't connect to anything, so they get one daemon connection,
+    // made before locking down, and reuse it.
+    args.max_connections = 1;
+
+    // Landlock only covers threads started after it's applied, so keep eval on this thread.
+    let (st, _guard, _project, app) = load_app(&mut args, 1)?;
+
+    let mut store = st.store().clone();
+    let store_dir = store.get_storedir().map_err(std::io::Error::other)?;
+    let probe = store
+        .parse_store_path(&format!(
+            "{}/00000000000000000000000000000000-flack-connect",
+            store_dir
+        ))
+        .map_err(std::io::Error::other)?;
+    store.is_valid_path(&probe).map_err(std::io::Error::other)?;
+    sandbox::lock_down(&[PathBuf::from(store_dir), PathBuf::from(&args.dir)])?;
+
+    let current = Arc::new(Mutex::<Option<(u64, EvalBudget)>>::new(None));
+
+    // Read on a separate thread, so cancellations can arrive mid-request.
+    let (requests_tx, requests_rx) = mpsc::channel::<(u64, EvalRequest)>();
+    let reader_current = current.clone();
+    std::thread::spawn(move || {
+        let mut input = std::io::stdin().lock();
+        loop {
+            match read_message(&mut input) {
+                Ok(ToWorker::Request(serial, mut request)) => match read_frame(&mut input) {
+                    Ok(body) => {
+                        request.body = web::Bytes::from(body);
+                        if requests_tx.send((serial, request)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        error!("Error reading request body: {}", err);
+                        return;
+                    }
+                },
+                Ok(ToWorker::Cancel(serial)) => {
+                    if let Ok(current) = reader_current.lock()
+                        && let Some((current_serial, budget)) = current.as_ref()
+                        && *current_serial == serial
+                    {
+                        info!("Cancelling request {}", serial);
+                        budget.interrupt.interrupt();
+                    }
+                }
+                Err(err) => {
+                    if err.kind() != std::io::ErrorKind::UnexpectedEof {
+                        error!("Error reading from server: {}", err);
+                    }
+                    return;
+                }
+            }
+        }
+    });
+
+    // Interrupt requests that run over budget.
+    let watchdog_current = current.clone();
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(WATCHDOG_INTERVAL);
+            if let Ok(current) = watchdog_current.lock()
+                && let Some((_, budget)) = current.as_ref()
+                && budget.expired()
+                && !budget.interrupt.is_interrupted()
+            {
+                warn!("Eval exceeded {}ms, interrupting", budget.timeout_ms());
+                budget.interrupt.interrupt();
+            }
+        }
+    });
+
+    write_message(
+        &mut *output.lock().expect("worker output poisoned"),
+        &FromWorker::Ready,
+    )?;
+
+    for (serial, request) in requests_rx {
+        let mut request_st = st.clone();
+        let timeout_output = output.clone();
+        let budget = EvalBudget::new(request_st.interrupt_handle(), args.timeout).on_timeout(
+            move |timeout_ms| {
+                if let Ok(mut output) = timeout_output.lock() {
+                    let _ = write_message(&mut *output, &FromWorker::Timeout(timeout_ms));
+                }
+            },
+        );
+        *current.lock().expect("worker state poisoned") = Some((serial, budget.clone()));
+
+        let mut result = eval_request(&args, &mut request_st, app.clone(), request, &budget);
+
+        *current.lock().expect("worker state poisoned") = None;
+        if budget.interrupt.is_interrupted() {
+            result = Err(if budget.expired() {
+                budget.timed_out()
+            } else {
+                FlackResponse::new().server_error("request cancelled")
+            });
+        }
+
+        write_message(
+            &mut *output.lock().expect("worker output poisoned"),
+            &FromWorker::Response(WireResponse::new(result)),
+        )?;
+    }
+
+    Ok(())
+}
@@ -11,7 +11,10 @@ impl bindgen::callbacks::ParseCallbacks for StripNixPrefix {
 
 fn main() {
     println!("cargo:rerun-if-changed=include/nix-c-expr.h");
+    println!("cargo:rerun-if-changed=include/nix_api_expr_ext.h");
+    println!("cargo:rerun-if-changed=shim/nix_api_expr_ext.cc");
     println!("cargo:rustc-link-lib=nixexprc");
+    println!("cargo:rustc-link-lib=nixexpr");
 
     let mut args = Vec::new();
     for path in pkg_config::probe_library("nix-expr-c")
@@ -39,4 +42,22 @@ fn main() {
     bindings
         .write_to_file(out_path.join("bindings.rs"))
         .expect("Couldn't write bindings!");
+
+    compile_shim();
+}
+
+/// Compiles the functions declared in include/nix_api_expr_ext.h, which the pinned Nix lacks.
+fn compile_shim() {
+    let mut build = cc::Build::new();
+    build.cpp(true).std("c++23").include("include");
+    for lib in ["nix-expr-c", "nix-expr"] {
+        let lib = pkg_config::Config::new()
+            .cargo_metadata(false)
+            .probe(lib)
+            .unwrap();
+        build.includes(&lib.include_paths);
+    }
+    build
+        .file("shim/nix_api_expr_ext.cc")
+        .compile("nixexprcext");
 }
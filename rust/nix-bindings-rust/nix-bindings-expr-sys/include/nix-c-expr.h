@@ -1,2 +1,3 @@
 #include <nix_api_expr.h>
 #include <nix_api_value.h>
+#include "nix_api_expr_ext.h"
@@ -0,0 +1,38 @@
+#ifndef NIX_API_EXPR_EXT_H
+#define NIX_API_EXPR_EXT_H
+/**
+ * @file
+ * @brief Evaluator functions missing from the pinned Nix C API
+ *
+ * These are implemented in `shim/nix_api_expr_ext.cc` against the Nix C++ API, and can be
+ * dropped once Nix exports them itself.
+ */
+
+#include <nix_api_util.h>
+#include <nix_api_expr.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+// cffi start
+
+/**
+ * @brief Sets an evaluator setting for the states the builder builds, without changing it for others.
+ *
+ * Call this after nix_eval_state_builder_load, which would override it otherwise.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] builder the builder to set it on
+ * @param[in] name the name of the setting, like `pure-eval`
+ * @param[in] value its value, as it would be written in nix.conf
+ * @return NIX_OK on success, or an error if it isn't an evaluator setting
+ */
+nix_err nix_eval_state_builder_set_setting(
+    nix_c_context * context, nix_eval_state_builder * builder, const char * name, const char * value);
+
+// cffi end
+#ifdef __cplusplus
+}
+#endif
+
+#endif // NIX_API_EXPR_EXT_H
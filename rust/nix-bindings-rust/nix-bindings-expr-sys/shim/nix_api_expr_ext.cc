@@ -0,0 +1,24 @@
+#include <nix_api_util.h>
+#include <nix_api_util_internal.h>
+#include <nix_api_expr.h>
+#include <nix_api_expr_internal.h>
+
+#include "nix/util/error.hh"
+
+#include "nix_api_expr_ext.h"
+
+extern "C" {
+
+nix_err nix_eval_state_builder_set_setting(
+    nix_c_context * context, nix_eval_state_builder * builder, const char * name, const char * value)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        if (!builder->settings.set(name, value))
+            throw nix::UsageError("'%s' is not an evaluator setting", name);
+    }
+    NIXC_CATCH_ERRS
+}
+
+} // extern "C"
@@ -139,13 +139,19 @@ use nix_bindings_store::path::StorePath;
 use nix_bindings_store::store::{Store, StoreWeak};
 use nix_bindings_store_sys as raw_store;
 use nix_bindings_util::context::Context;
+use nix_bindings_util::interrupt::InterruptHandle;
+#[cfg(nix_at_least = "2.33")]
+use nix_bindings_util::settings::{Setting, SettingValue};
 use nix_bindings_util::string_return::{
     callback_get_result_string, callback_get_result_string_data,
 };
 use nix_bindings_util::{check_call, check_call_opt_key, result_string_init};
-use std::ffi::{c_char, CString};
+use std::collections::BTreeMap;
+use std::ffi::{c_char, CStr, CString, OsStr};
 use std::iter::FromIterator;
 use std::os::raw::c_uint;
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::{Path, PathBuf};
 use std::ptr::{null, null_mut, NonNull};
 use std::sync::{Arc, LazyLock, Weak};
 
@@ -176,6 +182,35 @@ pub struct RealisedString {
     pub paths: Vec<StorePath>,
 }
 
+/// An element of a string's [context](https://nix.dev/manual/nix/latest/language/string-context.html).
+///
+/// Paths are absolute store paths, as `builtins.getContext` reports them.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StringContextElem {
+    /// A store path that is used as is, e.g. a source file that was interpolated into the string.
+    Opaque { path: String },
+    /// An output of a derivation, e.g. from interpolating the derivation itself.
+    Built { drv_path: String, output: String },
+    /// A derivation and its whole build closure, e.g. from interpolating `drvPath`.
+    DrvDeep { drv_path: String },
+}
+
+impl StringContextElem {
+    /// Whether the element refers to a derivation output, which may have to be built or substituted before it exists.
+    pub fn needs_build(&self) -> bool {
+        matches!(self, StringContextElem::Built { .. })
+    }
+
+    /// The store path the element refers to; for derivation outputs, this is the derivation.
+    pub fn path(&self) -> &str {
+        match self {
+            StringContextElem::Opaque { path } => path,
+            StringContextElem::Built { drv_path, .. } => drv_path,
+            StringContextElem::DrvDeep { drv_path } => drv_path,
+        }
+    }
+}
+
 /// A [Weak] reference to an [EvalState].
 pub struct EvalStateWeak {
     inner: Weak<EvalStateRef>,
@@ -191,6 +226,7 @@ impl EvalStateWeak {
                 eval_state,
                 store,
                 context: Context::new(),
+                interrupt: InterruptHandle::new(),
             })
         })
     }
@@ -248,6 +284,8 @@ pub struct EvalStateBuilder {
     eval_state_builder: *mut raw::eval_state_builder,
     lookup_path: Vec<CString>,
     load_ambient_settings: bool,
+    #[cfg(nix_at_least = "2.33")]
+    settings: Vec<(CString, CString)>,
     store: Store,
 }
 #[cfg(nix_at_least = "2.26")]
@@ -270,6 +308,8 @@ impl EvalStateBuilder {
             eval_state_builder,
             lookup_path: Vec::new(),
             load_ambient_settings: true,
+            #[cfg(nix_at_least = "2.33")]
+            settings: Vec::new(),
         })
     }
     /// Sets the [lookup path](https://nix.dev/manual/nix/latest/language/constructs/lookup-path.html) for Nix expression evaluation.
@@ -294,6 +334,25 @@ impl EvalStateBuilder {
         self.load_ambient_settings = load;
         self
     }
+    /// Sets an evaluator setting, like [`nix_bindings_util::settings::PURE_EVAL`], for this state only.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// Unlike [`nix_bindings_util::settings::set`], this doesn't affect other states, and it
+    /// overrides the ambient settings. Settings that aren't evaluator settings, like `substitute`,
+    /// make [`EvalStateBuilder::build`] fail.
+    #[cfg(nix_at_least = "2.33")]
+    pub fn setting<T: SettingValue>(mut self, setting: Setting<T>, value: &T) -> Result<Self> {
+        let name = CString::new(setting.name())?;
+        let value = CString::new(value.to_setting()).with_context(|| {
+            format!(
+                "EvalStateBuilder::setting: `{}` contains null byte",
+                name.to_string_lossy()
+            )
+        })?;
+        self.settings.push((name, value));
+        Ok(self)
+    }
     /// Builds the configured [`EvalState`].
     pub fn build(&self) -> Result<EvalState> {
         // Make sure the library is initialized
@@ -312,6 +371,18 @@ impl EvalStateBuilder {
             }
         }
 
+        #[cfg(nix_at_least = "2.33")]
+        for (name, value) in &self.settings {
+            unsafe {
+                check_call!(raw::eval_state_builder_set_setting(
+                    &mut context,
+                    self.eval_state_builder,
+                    name.as_ptr(),
+                    value.as_ptr()
+                ))?;
+            }
+        }
+
         // Note: these raw C string pointers borrow from self.lookup_path
         let mut lookup_path: Vec<*const c_char> = self
             .lookup_path
@@ -338,6 +409,7 @@ impl EvalStateBuilder {
             }),
             store: self.store.clone(),
             context,
+            interrupt: InterruptHandle::new(),
         })
     }
     /// Returns a raw pointer to the underlying eval state builder.
@@ -355,6 +427,7 @@ pub struct EvalState {
     eval_state: Arc<EvalStateRef>,
     store: Store,
     pub(crate) context: Context,
+    interrupt: InterruptHandle,
 }
 impl EvalState {
     /// Creates a new EvalState with basic configuration.
@@ -388,6 +461,39 @@ impl EvalState {
         }
     }
 
+    /// Returns a handle that interrupts evaluation and realisation performed through this `EvalState`.
+    ///
+    /// The handle can be triggered from any thread. Methods that evaluate, such as [call](EvalState::call),
+    /// [force](EvalState::force) and [realise_string](EvalState::realise_string), then fail at Nix's next interrupt
+    /// check, and keep failing until the handle is [reset](InterruptHandle::reset).
+    ///
+    /// Each `EvalState` value has its own handle: a [clone](Clone::clone) shares the underlying evaluator, but not
+    /// the handle, so interrupting one clone leaves work on the other clones alone.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+    /// # use nix_bindings_store::store::Store;
+    /// # use std::collections::HashMap;
+    /// # fn main() -> anyhow::Result<()> {
+    /// # test_init();
+    /// # let guard = gc_register_my_thread()?;
+    /// # let mut es = EvalState::new(Store::open(None, HashMap::new())?, [])?;
+    /// let handle = es.interrupt_handle();
+    /// std::thread::spawn(move || handle.interrupt()).join().unwrap();
+    /// assert!(es.eval_from_string("builtins.genList (x: x) 10", "<example>").and_then(|v| es.force(&v)).is_err());
+    /// # drop(guard);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[doc(alias = "nix_set_interrupt_callback")]
+    #[doc(alias = "cancel")]
+    #[doc(alias = "abort")]
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
     /// Parses and evaluates a Nix expression `expr`.
     ///
     /// Expressions can contain relative paths such as `./.` that are resolved relative to the given `path`.
@@ -415,6 +521,7 @@ impl EvalState {
     #[doc(alias = "eval")]
     #[doc(alias = "evaluate")]
     pub fn eval_from_string(&mut self, expr: &str, path: &str) -> Result<Value> {
+        let _interrupt = self.interrupt.enter()?;
         let expr_ptr =
             CString::new(expr).with_context(|| "eval_from_string: expr contains null byte")?;
         let path_ptr =
@@ -442,6 +549,7 @@ impl EvalState {
     #[doc(alias = "evaluate")]
     #[doc(alias = "strict")]
     pub fn force(&mut self, v: &Value) -> Result<()> {
+        let _interrupt = self.interrupt.enter()?;
         unsafe {
             check_call!(raw::value_force(
                 &mut self.context,
@@ -577,6 +685,7 @@ impl EvalState {
     where
         C: FromIterator<Value>,
     {
+        let _interrupt = self.interrupt.enter()?;
         let t = self.value_type(value)?;
         if t != ValueType::List {
             bail!("expected a list, but got a {:?}", t);
@@ -652,6 +761,7 @@ impl EvalState {
     #[doc(alias = "attribute")]
     #[doc(alias = "field")]
     pub fn require_attrs_select(&mut self, v: &Value, attr_name: &str) -> Result<Value> {
+        let _interrupt = self.interrupt.enter()?;
         let t = self.value_type(v)?;
         if t != ValueType::AttrSet {
             bail!("expected an attrset, but got a {:?}", t);
@@ -701,6 +811,7 @@ impl EvalState {
         v: &Value,
         attr_name: &str,
     ) -> Result<Option<Value>> {
+        let _interrupt = self.interrupt.enter()?;
         let t = self.value_type(v)?;
         if t != ValueType::AttrSet {
             bail!("expected an attrset, but got a {:?}", t);
@@ -753,6 +864,7 @@ impl EvalState {
     #[doc(alias = "nix_get_list_byidx")]
     #[doc(alias = "get_list_byidx")]
     pub fn require_list_select_idx_strict(&mut self, v: &Value, idx: u32) -> Result<Option<Value>> {
+        let _interrupt = self.interrupt.enter()?;
         let t = self.value_type(v)?;
         if t != ValueType::List {
             bail!("expected a list, but got a {:?}", t);
@@ -798,6 +910,56 @@ impl EvalState {
         Ok(v)
     }
 
+    /// Creates a new [string][`ValueType::String`] Nix value with the given [string context](https://nix.dev/manual/nix/latest/language/string-context.html).
+    ///
+    /// The context paths must be store paths. They are not checked for validity, as with `builtins.appendContext`.
+    #[doc(alias = "append_context")]
+    #[doc(alias = "string_with_context")]
+    pub fn new_value_str_with_context(
+        &mut self,
+        s: &str,
+        context: &[StringContextElem],
+    ) -> Result<Value> {
+        let string = self.new_value_str(s)?;
+        if context.is_empty() {
+            return Ok(string);
+        }
+
+        // builtins.appendContext takes the same shape builtins.getContext returns.
+        let mut by_path: BTreeMap<&str, (bool, bool, Vec<&str>)> = BTreeMap::new();
+        for elem in context {
+            let entry = by_path.entry(elem.path()).or_default();
+            match elem {
+                StringContextElem::Opaque { .. } => entry.0 = true,
+                StringContextElem::DrvDeep { .. } => entry.1 = true,
+                StringContextElem::Built { output, .. } => entry.2.push(output),
+            }
+        }
+        let mut attrs = Vec::with_capacity(by_path.len());
+        for (path, (opaque, all_outputs, outputs)) in by_path {
+            let mut info = Vec::new();
+            if opaque {
+                info.push(("path".to_string(), self.new_value_bool(true)?));
+            }
+            if all_outputs {
+                info.push(("allOutputs".to_string(), self.new_value_bool(true)?));
+            }
+            if !outputs.is_empty() {
+                let outputs = outputs
+                    .into_iter()
+                    .map(|output| self.new_value_str(output))
+                    .collect::<Result<Vec<_>>>()?;
+                info.push(("outputs".to_string(), self.new_value_list(outputs)?));
+            }
+            let info = self.new_value_attrs(info)?;
+            attrs.push((path.to_string(), info));
+        }
+        let attrs = self.new_value_attrs(attrs)?;
+
+        let append_context = self.builtin("appendContext")?;
+        self.call_multi(&append_context, &[string, attrs])
+    }
+
     /// Creates a new [integer][`ValueType::Int`] Nix value.
     #[doc(alias = "make_int")]
     #[doc(alias = "create_int")]
@@ -812,6 +974,157 @@ impl EvalState {
         Ok(v)
     }
 
+    /// Creates a new [boolean][`ValueType::Bool`] Nix value.
+    #[doc(alias = "make_bool")]
+    #[doc(alias = "create_bool")]
+    #[doc(alias = "bool_value")]
+    #[doc(alias = "nix_init_bool")]
+    pub fn new_value_bool(&mut self, b: bool) -> Result<Value> {
+        let value = self.new_value_uninitialized()?;
+        unsafe { check_call!(raw::init_bool(&mut self.context, value.raw_ptr(), b)) }?;
+        Ok(value)
+    }
+
+    /// Creates a new [float][`ValueType::Float`] Nix value.
+    #[doc(alias = "make_float")]
+    #[doc(alias = "create_float")]
+    #[doc(alias = "float_value")]
+    #[doc(alias = "nix_init_float")]
+    pub fn new_value_float(&mut self, f: f64) -> Result<Value> {
+        let value = self.new_value_uninitialized()?;
+        unsafe { check_call!(raw::init_float(&mut self.context, value.raw_ptr(), f)) }?;
+        Ok(value)
+    }
+
+    /// Creates a new [`null`][`ValueType::Null`] Nix value.
+    #[doc(alias = "make_null")]
+    #[doc(alias = "create_null")]
+    #[doc(alias = "null_value")]
+    #[doc(alias = "nix_init_null")]
+    pub fn new_value_null(&mut self) -> Result<Value> {
+        let value = self.new_value_uninitialized()?;
+        unsafe { check_call!(raw::init_null(&mut self.context, value.raw_ptr())) }?;
+        Ok(value)
+    }
+
+    /// Creates a new [path][`ValueType::Path`] Nix value.
+    ///
+    /// The path must be absolute. Like a path literal, it is not copied to the store until it is used in a derivation or coerced to a string.
+    #[doc(alias = "make_path")]
+    #[doc(alias = "create_path")]
+    #[doc(alias = "path_value")]
+    #[doc(alias = "nix_init_path_string")]
+    pub fn new_value_path(&mut self, path: &Path) -> Result<Value> {
+        if !path.is_absolute() {
+            bail!("new_value_path: path is not absolute: {}", path.display());
+        }
+        let path = CString::new(path.as_os_str().as_bytes())
+            .with_context(|| "new_value_path: contains null byte")?;
+        let value = self.new_value_uninitialized()?;
+        unsafe {
+            check_call!(raw::init_path_string(
+                &mut self.context,
+                self.eval_state.as_ptr(),
+                value.raw_ptr(),
+                path.as_ptr()
+            ))
+        }?;
+        Ok(value)
+    }
+
+    /// Creates a new [list][`ValueType::List`] Nix value from the given elements.
+    ///
+    /// The elements are not [evaluated](https://nix.dev/manual/nix/latest/language/evaluation.html), so thunks stay lazy.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+    /// # use nix_bindings_store::store::Store;
+    /// # fn example() -> anyhow::Result<()> {
+    /// # test_init(); let guard = gc_register_my_thread()?;
+    /// # let store = Store::open(None, [])?;
+    /// # let mut es = EvalState::new(store, [])?;
+    /// let a = es.new_value_str("text/html")?;
+    /// let b = es.new_value_str("text/plain")?;
+    /// let list = es.new_value_list([a, b])?;
+    /// assert_eq!(es.require_list_size(&list)?, 2);
+    /// # drop(guard);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[doc(alias = "make_list")]
+    #[doc(alias = "create_list")]
+    #[doc(alias = "array")]
+    #[doc(alias = "nix_make_list")]
+    pub fn new_value_list<I>(&mut self, items: I) -> Result<Value>
+    where
+        I: IntoIterator<Item = Value>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let iter = items.into_iter();
+        let list_builder = ListBuilder::new(self, iter.len())?;
+        for (i, item) in iter.enumerate() {
+            let i = c_uint::try_from(i).with_context(|| "new_value_list: too many elements")?;
+            unsafe {
+                check_call!(raw::list_builder_insert(
+                    &mut self.context,
+                    list_builder.ptr,
+                    i,
+                    item.raw_ptr()
+                ))?;
+            }
+        }
+        let value = self.new_value_uninitialized()?;
+        unsafe {
+            check_call!(raw::make_list(
+                &mut self.context,
+                list_builder.ptr,
+                value.raw_ptr()
+            ))?;
+        }
+        Ok(value)
+    }
+
+    /// Extracts the value from a [float][`ValueType::Float`] Nix value.
+    ///
+    /// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html) and verifies the value is a float.
+    ///
+    /// Returns the float value if successful, or an [`Err`] if evaluation failed or the value is not a float.
+    #[doc(alias = "double")]
+    #[doc(alias = "number")]
+    #[doc(alias = "nix_get_float")]
+    #[doc(alias = "get_float")]
+    pub fn require_float(&mut self, v: &Value) -> Result<f64> {
+        let t = self.value_type(v)?;
+        if t != ValueType::Float {
+            bail!("expected a float, but got a {:?}", t);
+        }
+        unsafe { check_call!(raw::get_float(&mut self.context, v.raw_ptr())) }
+    }
+
+    /// Extracts the path from a [path][`ValueType::Path`] Nix value.
+    ///
+    /// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html) and verifies the value is a path.
+    ///
+    /// Returns the path as written; it is not copied to the store.
+    #[doc(alias = "nix_get_path_string")]
+    #[doc(alias = "get_path_string")]
+    #[doc(alias = "get_path")]
+    pub fn require_path(&mut self, v: &Value) -> Result<PathBuf> {
+        let t = self.value_type(v)?;
+        if t != ValueType::Path {
+            bail!("expected a path, but got a {:?}", t);
+        }
+        let ptr = unsafe { check_call!(raw::get_path_string(&mut self.context, v.raw_ptr())) }?;
+        if ptr.is_null() {
+            bail!("nix_get_path_string returned a null pointer");
+        }
+        // The string belongs to the value, so copy it out.
+        let bytes = unsafe { CStr::from_ptr(ptr) }.to_bytes();
+        Ok(PathBuf::from(OsStr::from_bytes(bytes)))
+    }
+
     /// Creates a new [thunk](https://nix.dev/manual/nix/latest/language/evaluation.html#laziness) Nix value.
     ///
     /// The [thunk](https://nix.dev/manual/nix/latest/language/evaluation.html#laziness) will lazily evaluate to the result of the given Rust function when forced.
@@ -864,7 +1177,8 @@ impl EvalState {
     /// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html) and verifies the value is a string.
     /// Returns the string value if successful, or an [`Err`] if evaluation failed or the value is not a string.
     ///
-    /// NOTE: this will be replaced by two methods, one that also returns the context, and one that checks that the context is empty.
+    /// NOTE: this ignores the [string context](https://nix.dev/manual/nix/latest/language/string-context.html).
+    /// See [`Self::require_string_with_context`] and [`Self::require_string_without_context`].
     #[doc(alias = "str")]
     #[doc(alias = "text")]
     #[doc(alias = "nix_get_string")]
@@ -876,6 +1190,79 @@ impl EvalState {
         }
         self.get_string(value)
     }
+
+    /// Extracts the [context](https://nix.dev/manual/nix/latest/language/string-context.html) of a [string][`ValueType::String`] Nix value.
+    ///
+    /// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html) and verifies the value is a string.
+    /// Unlike [`Self::realise_string`], this does not build or substitute anything, so it can be used to decide whether realising is necessary.
+    ///
+    /// The elements are sorted by store path.
+    #[doc(alias = "get_context")]
+    #[doc(alias = "string_context")]
+    #[doc(alias = "references")]
+    pub fn require_string_context(&mut self, value: &Value) -> Result<Vec<StringContextElem>> {
+        let t = self.value_type(value)?;
+        if t != ValueType::String {
+            bail!("expected a string, but got a {:?}", t);
+        }
+        let get_context = self.builtin("getContext")?;
+        let context = self.call(get_context, value.clone())?;
+
+        let mut elems = Vec::new();
+        for path in self.require_attrs_names(&context)? {
+            let info = self.require_attrs_select(&context, &path)?;
+            if let Some(v) = self.require_attrs_select_opt(&info, "path")? {
+                if self.require_bool(&v)? {
+                    elems.push(StringContextElem::Opaque { path: path.clone() });
+                }
+            }
+            if let Some(v) = self.require_attrs_select_opt(&info, "allOutputs")? {
+                if self.require_bool(&v)? {
+                    elems.push(StringContextElem::DrvDeep {
+                        drv_path: path.clone(),
+                    });
+                }
+            }
+            if let Some(v) = self.require_attrs_select_opt(&info, "outputs")? {
+                for output in self.require_list_strict::<Vec<_>>(&v)? {
+                    elems.push(StringContextElem::Built {
+                        drv_path: path.clone(),
+                        output: self.require_string(&output)?,
+                    });
+                }
+            }
+        }
+        Ok(elems)
+    }
+
+    /// Extracts a string value and its [context](https://nix.dev/manual/nix/latest/language/string-context.html) from a [string][`ValueType::String`] Nix value.
+    ///
+    /// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html) and verifies the value is a string.
+    /// Nothing is built; see [`Self::require_string_context`].
+    #[doc(alias = "get_string_with_context")]
+    pub fn require_string_with_context(
+        &mut self,
+        value: &Value,
+    ) -> Result<(String, Vec<StringContextElem>)> {
+        let s = self.require_string(value)?;
+        let context = self.require_string_context(value)?;
+        Ok((s, context))
+    }
+
+    /// Extracts a string value from a [string][`ValueType::String`] Nix value that must not have any [context](https://nix.dev/manual/nix/latest/language/string-context.html).
+    ///
+    /// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html) and verifies the value is a string.
+    /// Returns an [`Err`] if the string refers to any store paths.
+    #[doc(alias = "get_string_without_context")]
+    #[doc(alias = "plain_string")]
+    pub fn require_string_without_context(&mut self, value: &Value) -> Result<String> {
+        let (s, context) = self.require_string_with_context(value)?;
+        if !context.is_empty() {
+            let paths: Vec<&str> = context.iter().map(|elem| elem.path()).collect();
+            bail!("unexpected context in string: {}", paths.join(", "));
+        }
+        Ok(s)
+    }
     /// Realises a [string][`ValueType::String`] Nix value with context information.
     ///
     /// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html), verifies the value is a string, and builds any derivations
@@ -888,6 +1275,7 @@ impl EvalState {
         value: &Value,
         is_import_from_derivation: bool,
     ) -> Result<RealisedString> {
+        let _interrupt = self.interrupt.enter()?;
         let t = self.value_type(value)?;
         if t != ValueType::String {
             bail!("expected a string, but got a {:?}", t);
@@ -943,6 +1331,7 @@ impl EvalState {
     #[doc(alias = "invoke")]
     #[doc(alias = "execute")]
     pub fn call(&mut self, f: Value, a: Value) -> Result<Value> {
+        let _interrupt = self.interrupt.enter()?;
         let value = self.new_value_uninitialized()?;
         unsafe {
             check_call!(raw::value_call(
@@ -1000,6 +1389,7 @@ impl EvalState {
     #[doc(alias = "curry")]
     #[doc(alias = "call_with_args")]
     pub fn call_multi(&mut self, f: &Value, args: &[Value]) -> Result<Value> {
+        let _interrupt = self.interrupt.enter()?;
         let value = self.new_value_uninitialized()?;
         unsafe {
             let mut args_ptrs = args.iter().map(|a| a.raw_ptr()).collect::<Vec<_>>();
@@ -1035,6 +1425,11 @@ impl EvalState {
         Ok(value)
     }
 
+    /// Looks up a function in `builtins`, for functionality the C API does not have.
+    fn builtin(&mut self, name: &str) -> Result<Value> {
+        self.eval_from_string(&format!("builtins.{}", name), "/")
+    }
+
     fn new_value_uninitialized(&mut self) -> Result<Value> {
         unsafe {
             let value = check_call!(raw::alloc_value(
@@ -1161,6 +1556,30 @@ impl BindingsBuilder {
     }
 }
 
+// Internal RAII helper, like BindingsBuilder
+struct ListBuilder {
+    ptr: *mut raw::ListBuilder,
+}
+impl Drop for ListBuilder {
+    fn drop(&mut self) {
+        unsafe {
+            raw::list_builder_free(self.ptr);
+        }
+    }
+}
+impl ListBuilder {
+    fn new(eval_state: &mut EvalState, capacity: usize) -> Result<Self> {
+        let ptr = unsafe {
+            check_call!(raw::make_list_builder(
+                &mut eval_state.context,
+                eval_state.eval_state.as_ptr(),
+                capacity
+            ))
+        }?;
+        Ok(ListBuilder { ptr })
+    }
+}
+
 /// Triggers garbage collection immediately.
 #[doc(alias = "garbage_collect")]
 #[doc(alias = "collect")]
@@ -1226,6 +1645,7 @@ impl Clone for EvalState {
             eval_state: self.eval_state.clone(),
             store: self.store.clone(),
             context: Context::new(),
+            interrupt: InterruptHandle::new(),
         }
     }
 }
@@ -1263,6 +1683,7 @@ mod tests {
     use super::*;
     use cstr::cstr;
     use ctor::ctor;
+    use nix_bindings_util::error::{NixError, NixErrorKind};
     use std::collections::HashMap;
     use std::fs::read_dir;
     use std::io::Write as _;
@@ -1586,6 +2007,45 @@ mod tests {
         .unwrap()
     }
 
+    #[test]
+    fn eval_state_error_kind() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let cases = [
+                (r#"throw "oh no the error""#, NixErrorKind::Throw),
+                (r#"abort "oh no the error""#, NixErrorKind::Abort),
+                ("assert false; 1", NixErrorKind::Assertion),
+                ("1 + true", NixErrorKind::Type),
+                ("let x = x; in x", NixErrorKind::InfiniteRecursion),
+                ("oh_no_the_error", NixErrorKind::Eval),
+            ];
+            for (expr, kind) in cases {
+                let e = es.eval_from_string(expr, "<test>").err().unwrap();
+                let nix_err = e.downcast_ref::<NixError>().unwrap();
+                assert_eq!(nix_err.kind, kind, "{}", expr);
+                assert_eq!(nix_err.code, nix_bindings_util_sys::err_NIX_ERR_NIX_ERROR);
+            }
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_error_thrown() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let expr = r#"{ a = throw "oh no the error"; }"#;
+            let v = es.eval_from_string(expr, "<test>").unwrap();
+            let e = es.require_attrs_select(&v, "a").err().unwrap();
+            let nix_err = e.downcast_ref::<NixError>().unwrap();
+            assert_eq!(nix_err.kind, NixErrorKind::Throw);
+            assert_eq!(nix_err.name.as_deref(), Some("nix::ThrownError"));
+            assert_eq!(nix_err.thrown.as_deref(), Some("oh no the error"));
+        })
+        .unwrap()
+    }
+
     #[test]
     fn eval_state_require_attrs_select_opt() {
         gc_registering_current_thread(|| {
@@ -1733,10 +2193,112 @@ mod tests {
             es.force(&v).unwrap();
             let t = es.value_type_unforced(&v);
             assert!(t == Some(ValueType::String));
-            // TODO
-            // let r = es.require_string_without_context(&v);
-            // assert!(r.is_err());
-            // assert!(r.unwrap_err().to_string().contains("unexpected context"));
+            let r = es.require_string_without_context(&v);
+            assert!(r.is_err());
+            assert!(r.unwrap_err().to_string().contains("unexpected context"));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_value_string_without_context() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = make_thunk(&mut es, "\"hello\"");
+            let s = es.require_string_without_context(&v).unwrap();
+            assert_eq!(s, "hello");
+            let (s, context) = es.require_string_with_context(&v).unwrap();
+            assert_eq!(s, "hello");
+            assert!(context.is_empty());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_string_context() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"
+                    let
+                      drv = derivation { name = "hello"; system = "dummy"; builder = "cmd.exe"; outputs = [ "out" "dev" ]; };
+                      file = builtins.toFile "hello.txt" "hello";
+                    in [ "${drv.dev} ${drv.drvPath} ${file}" drv.drvPath drv.dev.outPath file ]
+                    "#,
+                    "<test>",
+                )
+                .unwrap();
+            let paths: Vec<Value> = es.require_list_strict(&v).unwrap();
+            let drv_path = es.require_string(&paths[1]).unwrap();
+            let file = es.require_string(&paths[3]).unwrap();
+
+            let (s, context) = es.require_string_with_context(&paths[0]).unwrap();
+            assert!(s.ends_with("-hello.txt"));
+            // Sorted by path, and for each path in the order getContext lists them.
+            let mut expected = vec![
+                StringContextElem::DrvDeep {
+                    drv_path: drv_path.clone(),
+                },
+                StringContextElem::Built {
+                    drv_path: drv_path.clone(),
+                    output: "dev".to_string(),
+                },
+                StringContextElem::Opaque { path: file.clone() },
+            ];
+            expected.sort_by(|a, b| a.path().cmp(b.path()));
+            assert_eq!(context, expected);
+            assert!(context.iter().any(|elem| elem.needs_build()));
+
+            let context = es.require_string_context(&paths[3]).unwrap();
+            assert_eq!(context, vec![StringContextElem::Opaque { path: file }]);
+            assert!(!context[0].needs_build());
+
+            let v = es.new_value_int(1).unwrap();
+            let r = es.require_string_context(&v);
+            assert_eq!(
+                r.unwrap_err().to_string(),
+                "expected a string, but got a Int"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_string_with_context() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"
+                    let
+                      drv = derivation { name = "hello"; system = "dummy"; builder = "cmd.exe"; };
+                    in "${drv} ${drv.drvPath} ${builtins.toFile "hello.txt" "hello"}"
+                    "#,
+                    "<test>",
+                )
+                .unwrap();
+            let context = es.require_string_context(&v).unwrap();
+            assert_eq!(context.len(), 3);
+
+            let v = es.new_value_str_with_context("hello", &context).unwrap();
+            let (s, new_context) = es.require_string_with_context(&v).unwrap();
+            assert_eq!(s, "hello");
+            assert_eq!(new_context, context);
+
+            let v = es.new_value_str_with_context("hello", &[]).unwrap();
+            assert!(es.require_string_context(&v).unwrap().is_empty());
+
+            let r = es.new_value_str_with_context(
+                "hello",
+                &[StringContextElem::Opaque {
+                    path: "/not/a/store/path".to_string(),
+                }],
+            );
+            assert!(r.is_err());
         })
         .unwrap();
     }
@@ -1805,6 +2367,105 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn eval_state_new_bool() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.new_value_bool(true).unwrap();
+            let t = es.value_type_unforced(&v);
+            assert!(t == Some(ValueType::Bool));
+            assert!(es.require_bool(&v).unwrap());
+            let v = es.new_value_bool(false).unwrap();
+            assert!(!es.require_bool(&v).unwrap());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_float() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.new_value_float(1.5).unwrap();
+            let t = es.value_type_unforced(&v);
+            assert!(t == Some(ValueType::Float));
+            let f = es.require_float(&v).unwrap();
+            assert!(f == 1.5);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_float() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = make_thunk(&mut es, "1.0 / 4");
+            let f = es.require_float(&v).unwrap();
+            assert!(f == 0.25);
+
+            let v = es.eval_from_string("1", "<test>").unwrap();
+            let r = es.require_float(&v);
+            assert_eq!(
+                r.unwrap_err().to_string(),
+                "expected a float, but got a Int"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_null() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.new_value_null().unwrap();
+            let t = es.value_type_unforced(&v);
+            assert!(t == Some(ValueType::Null));
+            let f = es.eval_from_string("x: x == null", "<test>").unwrap();
+            let r = es.call(f, v).unwrap();
+            assert!(es.require_bool(&r).unwrap());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_path() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.new_value_path(Path::new("/foo/bar")).unwrap();
+            let t = es.value_type_unforced(&v);
+            assert!(t == Some(ValueType::Path));
+            let p = es.require_path(&v).unwrap();
+            assert_eq!(p, PathBuf::from("/foo/bar"));
+
+            let r = es.new_value_path(Path::new("foo/bar"));
+            assert!(r.unwrap_err().to_string().contains("not absolute"));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_path() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = make_thunk(&mut es, "/foo + \"/bar\"");
+            let p = es.require_path(&v).unwrap();
+            assert_eq!(p, PathBuf::from("/foo/bar"));
+
+            let v = es.eval_from_string("\"/foo\"", "<test>").unwrap();
+            let r = es.require_path(&v);
+            assert_eq!(
+                r.unwrap_err().to_string(),
+                "expected a path, but got a String"
+            );
+        })
+        .unwrap();
+    }
+
     #[test]
     fn eval_state_value_attrset() {
         gc_registering_current_thread(|| {
@@ -2155,6 +2816,53 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn eval_state_interrupt_from_other_thread() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let handle = es.interrupt_handle();
+            let remote = handle.clone();
+            let interrupter = std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                remote.interrupt();
+            });
+            // Takes far longer than the test is willing to wait.
+            let v = es
+                .eval_from_string(
+                    "builtins.foldl' (a: b: a + b) 0 (builtins.genList (x: x) 1000000000)",
+                    "<test>",
+                )
+                .unwrap();
+            let r = es.force(&v);
+            interrupter.join().unwrap();
+            assert!(r.is_err());
+            assert!(handle.is_interrupted());
+
+            // The same EvalState remains usable once the handle is reset.
+            handle.reset();
+            let v = es.eval_from_string("1 + 1", "<test>").unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 2);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_interrupt_clone_independent() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let mut es2 = es.clone();
+            es.interrupt_handle().interrupt();
+            assert!(!es2.interrupt_handle().is_interrupted());
+            let v = es2
+                .eval_from_string("builtins.length (builtins.genList (x: x) 100)", "<test>")
+                .unwrap();
+            assert_eq!(es2.require_int(&v).unwrap(), 100);
+        })
+        .unwrap();
+    }
+
     #[test]
     fn store_open_params() {
         gc_registering_current_thread(|| {
@@ -2500,6 +3208,47 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    pub fn eval_state_new_value_list_empty() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, []).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let list = es.new_value_list([]).unwrap();
+            let t = es.value_type(&list).unwrap();
+            assert!(t == ValueType::List);
+            assert_eq!(es.require_list_size(&list).unwrap(), 0);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    pub fn eval_state_new_value_list_from_vec() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, []).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let list = {
+                let a = es.new_value_int(1).unwrap();
+                let b = es.new_value_str("two").unwrap();
+                let c = make_thunk(&mut es, "throw \"not forced\"");
+                es.new_value_list(vec![a, b, c]).unwrap()
+            };
+            assert_eq!(es.require_list_size(&list).unwrap(), 3);
+            let a = es
+                .require_list_select_idx_strict(&list, 0)
+                .unwrap()
+                .unwrap();
+            assert_eq!(es.require_int(&a).unwrap(), 1);
+            let b = es
+                .require_list_select_idx_strict(&list, 1)
+                .unwrap()
+                .unwrap();
+            assert_eq!(es.require_string(&b).unwrap(), "two");
+            let r = es.require_list_select_idx_strict(&list, 2);
+            assert!(r.unwrap_err().to_string().contains("not forced"));
+        })
+        .unwrap();
+    }
+
     #[test]
     fn eval_state_require_list_select_idx_strict_basic() {
         gc_registering_current_thread(|| {
@@ -2799,6 +3548,53 @@ mod tests {
         .unwrap();
     }
 
+    /// Test that a setting on one builder doesn't leak into other states.
+    ///
+    /// The test suite sets max-call-depth = 1000 via NIX_CONFIG in setup(), which the state
+    /// built without the setting still sees.
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn eval_state_builder_setting() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let expr = r#"
+                let
+                  recurse = n: if n == 0 then "done" else recurse (n - 1);
+                in
+                  recurse 200
+            "#;
+
+            let mut shallow = EvalStateBuilder::new(store.clone())
+                .unwrap()
+                .setting(nix_bindings_util::settings::MAX_CALL_DEPTH, &100)
+                .unwrap()
+                .build()
+                .unwrap();
+            let err = shallow.eval_from_string(expr, "<test>").unwrap_err();
+            assert!(err.to_string().contains("max-call-depth"));
+
+            let mut es = EvalStateBuilder::new(store).unwrap().build().unwrap();
+            let value = es.eval_from_string(expr, "<test>").unwrap();
+            assert_eq!(es.require_string(&value).unwrap(), "done");
+        })
+        .unwrap();
+    }
+
+    /// Test that a setting that isn't an evaluator setting is rejected.
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn eval_state_builder_setting_unknown() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let builder = EvalStateBuilder::new(store)
+                .unwrap()
+                .setting(nix_bindings_util::settings::SUBSTITUTE, &false)
+                .unwrap();
+            assert!(builder.build().is_err());
+        })
+        .unwrap();
+    }
+
     /// Test that load_ambient_settings(false) ignores the ambient environment.
     ///
     /// The test suite sets max-call-depth = 1000 via NIX_CONFIG in setup().
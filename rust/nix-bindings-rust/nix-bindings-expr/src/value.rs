@@ -1,4 +1,6 @@
 pub mod __private;
+#[cfg(feature = "serde")]
+pub mod serde;
 
 use nix_bindings_expr_sys as raw;
 use nix_bindings_util::{check_call, context::Context};
@@ -0,0 +1,1192 @@
+//! Conversion between Rust types and Nix values, using [serde](https://serde.rs).
+//!
+//! [`to_value`] builds attribute sets, lists, ints, floats, bools and `null` directly through the
+//! C API, and [`from_value`] reads them back, so typed data can cross the boundary without a
+//! round trip through `builtins.toJSON` and `builtins.fromJSON`.
+//!
+//! The data model is the one serde_json uses: structs and maps become attribute sets, sequences
+//! and tuples become lists, `None` and `()` become `null`, and enums are externally tagged.
+//! Like `builtins.toJSON`, [`from_value`] reads an attribute set with `__toString` or `outPath`
+//! as the string it coerces to, so derivations come out as their output path. Unlike
+//! `builtins.toJSON`, path values are read as they are, without copying them to the store.
+//!
+//! Both directions enforce [`Limits`], since the data often comes from the outside world.
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+//! # use nix_bindings_expr::value::serde::{from_value, to_value};
+//! # use nix_bindings_store::store::Store;
+//! # use std::collections::BTreeMap;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init(); let guard = gc_register_my_thread()?;
+//! # let store = Store::open(None, [])?;
+//! # let mut es = EvalState::new(store, [])?;
+//! let input = BTreeMap::from([("answer".to_string(), vec![4_i64, 2])]);
+//! let value = to_value(&mut es, &input)?;
+//! let output: BTreeMap<String, Vec<i64>> = from_value(&mut es, &value)?;
+//! assert_eq!(input, output);
+//! # drop(guard);
+//! # Ok(())
+//! # }
+//! ```
+
+use super::{Int, Value, ValueType};
+use crate::eval_state::EvalState;
+use ::serde::de::{self, DeserializeOwned, IntoDeserializer as _};
+use ::serde::ser::{self, Serialize};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+/// Bounds on the values converted by [`to_value_with_limits`] and [`from_value_with_limits`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// The deepest nesting of lists and attribute sets allowed.
+    pub max_depth: usize,
+    /// The most values converted in total, counting every container and leaf.
+    pub max_values: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_depth: 128,
+            max_values: 1 << 20,
+        }
+    }
+}
+
+/// Converts a Rust value to a Nix value, with the default [`Limits`].
+#[doc(alias = "serialize")]
+#[doc(alias = "from_json")]
+pub fn to_value<T: Serialize + ?Sized>(es: &mut EvalState, value: &T) -> Result<Value> {
+    to_value_with_limits(es, value, Limits::default())
+}
+
+/// Converts a Rust value to a Nix value.
+pub fn to_value_with_limits<T: Serialize + ?Sized>(
+    es: &mut EvalState,
+    value: &T,
+    limits: Limits,
+) -> Result<Value> {
+    let mut state = State::new(es, limits);
+    value
+        .serialize(Serializer {
+            state: &mut state,
+            depth: 0,
+        })
+        .map_err(|e| e.0)
+}
+
+/// Converts a Nix value to a Rust value, with the default [`Limits`].
+///
+/// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html) of everything that is converted.
+#[doc(alias = "deserialize")]
+#[doc(alias = "to_json")]
+pub fn from_value<T: DeserializeOwned>(es: &mut EvalState, value: &Value) -> Result<T> {
+    from_value_with_limits(es, value, Limits::default())
+}
+
+/// Converts a Nix value to a Rust value.
+///
+/// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html) of everything that is converted.
+pub fn from_value_with_limits<T: DeserializeOwned>(
+    es: &mut EvalState,
+    value: &Value,
+    limits: Limits,
+) -> Result<T> {
+    let mut state = State::new(es, limits);
+    T::deserialize(Deserializer {
+        state: &mut state,
+        value: value.clone(),
+        depth: 0,
+    })
+    .map_err(|e| e.0)
+}
+
+/// The error type serde needs; unwrapped again before it leaves this module.
+#[derive(Debug)]
+struct Error(anyhow::Error);
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error(anyhow::format_err!("{}", msg))
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error(anyhow::format_err!("{}", msg))
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Error(e)
+    }
+}
+
+fn error(msg: impl Display) -> Error {
+    Error(anyhow::format_err!("{}", msg))
+}
+
+/// Shared by all (de)serializers of one conversion.
+struct State<'a> {
+    es: &'a mut EvalState,
+    limits: Limits,
+    values: usize,
+}
+
+impl<'a> State<'a> {
+    fn new(es: &'a mut EvalState, limits: Limits) -> Self {
+        State {
+            es,
+            limits,
+            values: 0,
+        }
+    }
+
+    fn count(&mut self) -> Result<(), Error> {
+        self.values += 1;
+        if self.values > self.limits.max_values {
+            return Err(error(format_args!(
+                "value has more than {} elements",
+                self.limits.max_values
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the depth of the elements of a container at `depth`.
+    fn descend(&self, depth: usize) -> Result<usize, Error> {
+        if depth >= self.limits.max_depth {
+            return Err(error(format_args!(
+                "value is nested more than {} levels deep",
+                self.limits.max_depth
+            )));
+        }
+        Ok(depth + 1)
+    }
+
+    fn int(&mut self, i: impl TryInto<Int> + Display + Copy) -> Result<Value, Error> {
+        self.count()?;
+        let i = i
+            .try_into()
+            .map_err(|_| error(format_args!("integer {} does not fit in a Nix int", i)))?;
+        Ok(self.es.new_value_int(i)?)
+    }
+
+    /// Wraps the value in a single-attribute set, for externally tagged enum variants.
+    fn variant(&mut self, variant: Option<&'static str>, value: Value) -> Result<Value, Error> {
+        match variant {
+            Some(name) => {
+                self.count()?;
+                Ok(self.es.new_value_attrs([(name.to_string(), value)])?)
+            }
+            None => Ok(value),
+        }
+    }
+}
+
+struct Serializer<'s, 'a> {
+    state: &'s mut State<'a>,
+    depth: usize,
+}
+
+impl<'s, 'a> ser::Serializer for Serializer<'s, 'a> {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = ListSerializer<'s, 'a>;
+    type SerializeTuple = ListSerializer<'s, 'a>;
+    type SerializeTupleStruct = ListSerializer<'s, 'a>;
+    type SerializeTupleVariant = ListSerializer<'s, 'a>;
+    type SerializeMap = AttrsSerializer<'s, 'a>;
+    type SerializeStruct = AttrsSerializer<'s, 'a>;
+    type SerializeStructVariant = AttrsSerializer<'s, 'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        self.state.count()?;
+        Ok(self.state.es.new_value_bool(v)?)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        self.state.int(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        self.state.int(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        self.state.int(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        self.state.int(v)
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Value, Error> {
+        self.state.int(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        self.state.int(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        self.state.int(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        self.state.int(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        self.state.int(v)
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Value, Error> {
+        self.state.int(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        self.state.count()?;
+        Ok(self.state.es.new_value_float(v)?)
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        self.state.count()?;
+        Ok(self.state.es.new_value_str(v)?)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        use ser::SerializeSeq as _;
+        let mut list = self.serialize_seq(Some(v.len()))?;
+        for byte in v {
+            list.serialize_element(byte)?;
+        }
+        list.end()
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        self.state.count()?;
+        Ok(self.state.es.new_value_null()?)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        let depth = self.state.descend(self.depth)?;
+        let value = value.serialize(Serializer {
+            state: &mut *self.state,
+            depth,
+        })?;
+        self.state.variant(Some(variant), value)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<ListSerializer<'s, 'a>, Error> {
+        ListSerializer::new(self, len.unwrap_or(0), None)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer<'s, 'a>, Error> {
+        ListSerializer::new(self, len, None)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer<'s, 'a>, Error> {
+        ListSerializer::new(self, len, None)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer<'s, 'a>, Error> {
+        ListSerializer::new(self, len, Some(variant))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<AttrsSerializer<'s, 'a>, Error> {
+        AttrsSerializer::new(self, None)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<AttrsSerializer<'s, 'a>, Error> {
+        AttrsSerializer::new(self, None)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<AttrsSerializer<'s, 'a>, Error> {
+        AttrsSerializer::new(self, Some(variant))
+    }
+}
+
+struct ListSerializer<'s, 'a> {
+    state: &'s mut State<'a>,
+    depth: usize,
+    items: Vec<Value>,
+    variant: Option<&'static str>,
+}
+
+impl<'s, 'a> ListSerializer<'s, 'a> {
+    fn new(
+        serializer: Serializer<'s, 'a>,
+        len: usize,
+        variant: Option<&'static str>,
+    ) -> Result<Self, Error> {
+        let state = serializer.state;
+        state.count()?;
+        let depth = state.descend(serializer.depth)?;
+        Ok(ListSerializer {
+            items: Vec::with_capacity(len.min(state.limits.max_values)),
+            state,
+            depth,
+            variant,
+        })
+    }
+
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let value = value.serialize(Serializer {
+            state: &mut *self.state,
+            depth: self.depth,
+        })?;
+        self.items.push(value);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let list = self.state.es.new_value_list(self.items)?;
+        self.state.variant(self.variant, list)
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for ListSerializer<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+struct AttrsSerializer<'s, 'a> {
+    state: &'s mut State<'a>,
+    depth: usize,
+    // Later keys win, as in serde_json, and the builder wants unique names.
+    attrs: BTreeMap<String, Value>,
+    key: Option<String>,
+    variant: Option<&'static str>,
+}
+
+impl<'s, 'a> AttrsSerializer<'s, 'a> {
+    fn new(serializer: Serializer<'s, 'a>, variant: Option<&'static str>) -> Result<Self, Error> {
+        let state = serializer.state;
+        state.count()?;
+        let depth = state.descend(serializer.depth)?;
+        Ok(AttrsSerializer {
+            state,
+            depth,
+            attrs: BTreeMap::new(),
+            key: None,
+            variant,
+        })
+    }
+
+    fn insert<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<(), Error> {
+        let value = value.serialize(Serializer {
+            state: &mut *self.state,
+            depth: self.depth,
+        })?;
+        self.attrs.insert(key, value);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let attrs = self.state.es.new_value_attrs(self.attrs)?;
+        self.state.variant(self.variant, attrs)
+    }
+}
+
+impl ser::SerializeMap for AttrsSerializer<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(key.serialize(NameSerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| error("serialize_value called before serialize_key"))?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for AttrsSerializer<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key.to_string(), value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for AttrsSerializer<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key.to_string(), value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+/// Serializes map keys to attribute names. Like serde_json, this accepts strings and numbers.
+struct NameSerializer;
+
+fn name_error() -> Error {
+    error("attribute names must be strings")
+}
+
+impl ser::Serializer for NameSerializer {
+    type Ok = String;
+    type Error = Error;
+    type SerializeSeq = ser::Impossible<String, Error>;
+    type SerializeTuple = ser::Impossible<String, Error>;
+    type SerializeTupleStruct = ser::Impossible<String, Error>;
+    type SerializeTupleVariant = ser::Impossible<String, Error>;
+    type SerializeMap = ser::Impossible<String, Error>;
+    type SerializeStruct = ser::Impossible<String, Error>;
+    type SerializeStructVariant = ser::Impossible<String, Error>;
+
+    fn serialize_bool(self, _v: bool) -> Result<String, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<String, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<String, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_char(self, v: char) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<String, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_none(self) -> Result<String, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<String, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_unit(self) -> Result<String, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<String, Error> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<String, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<String, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        Err(name_error())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(name_error())
+    }
+}
+
+struct Deserializer<'s, 'a> {
+    state: &'s mut State<'a>,
+    value: Value,
+    depth: usize,
+}
+
+impl Deserializer<'_, '_> {
+    /// Forces the value, replacing attribute sets that `builtins.toJSON` would print as strings
+    /// by the value they coerce to.
+    fn force(&mut self) -> Result<ValueType, Error> {
+        let es = &mut *self.state.es;
+        let t = es.value_type(&self.value)?;
+        if t != ValueType::AttrSet {
+            return Ok(t);
+        }
+        if let Some(to_string) = es.require_attrs_select_opt(&self.value, "__toString")? {
+            self.value = es.call(to_string, self.value.clone())?;
+        } else if let Some(out_path) = es.require_attrs_select_opt(&self.value, "outPath")? {
+            self.value = out_path;
+        } else {
+            return Ok(t);
+        }
+        Ok(es.value_type(&self.value)?)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'_, '_> {
+    type Error = Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Error> {
+        self.state.count()?;
+        let t = self.force()?;
+        let es = &mut *self.state.es;
+        match t {
+            ValueType::Null => visitor.visit_unit(),
+            ValueType::Bool => visitor.visit_bool(es.require_bool(&self.value)?),
+            ValueType::Int => visitor.visit_i64(es.require_int(&self.value)?),
+            ValueType::Float => visitor.visit_f64(es.require_float(&self.value)?),
+            ValueType::String => visitor.visit_string(es.require_string(&self.value)?),
+            ValueType::Path => {
+                let path = es.require_path(&self.value)?;
+                let path = path.into_os_string().into_string().map_err(|path| {
+                    error(format_args!(
+                        "path is not valid UTF-8: {}",
+                        path.to_string_lossy()
+                    ))
+                })?;
+                visitor.visit_string(path)
+            }
+            ValueType::List => {
+                let len = es.require_list_size(&self.value)?;
+                let depth = self.state.descend(self.depth)?;
+                let mut list = ListAccess {
+                    state: self.state,
+                    list: self.value,
+                    index: 0,
+                    len,
+                    depth,
+                };
+                let result = visitor.visit_seq(&mut list)?;
+                if list.index < len {
+                    return Err(de::Error::invalid_length(
+                        len as usize,
+                        &"fewer list elements",
+                    ));
+                }
+                Ok(result)
+            }
+            ValueType::AttrSet => {
+                let names = es.require_attrs_names(&self.value)?;
+                let depth = self.state.descend(self.depth)?;
+                visitor.visit_map(AttrsAccess {
+                    state: self.state,
+                    attrs: self.value,
+                    names: names.into_iter(),
+                    name: None,
+                    depth,
+                })
+            }
+            t => Err(error(format_args!(
+                "cannot convert a {:?} to a Rust value",
+                t
+            ))),
+        }
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Error> {
+        if self.force()? == ValueType::Null {
+            self.state.count()?;
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        mut self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.state.count()?;
+        match self.force()? {
+            ValueType::String => {
+                let variant = self.state.es.require_string(&self.value)?;
+                visitor.visit_enum(variant.into_deserializer())
+            }
+            ValueType::AttrSet => {
+                let es = &mut *self.state.es;
+                let mut names = es.require_attrs_names_unsorted(&self.value)?;
+                if names.len() != 1 {
+                    return Err(error(
+                        "expected an attribute set with a single attribute for an enum variant",
+                    ));
+                }
+                let variant = names.remove(0);
+                let value = es.require_attrs_select(&self.value, &variant)?;
+                let depth = self.state.descend(self.depth)?;
+                visitor.visit_enum(VariantAccess {
+                    variant,
+                    value: Deserializer {
+                        state: self.state,
+                        value,
+                        depth,
+                    },
+                })
+            }
+            t => Err(error(format_args!(
+                "expected a string or an attribute set for an enum variant, but got a {:?}",
+                t
+            ))),
+        }
+    }
+
+    fn deserialize_ignored_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    ::serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+struct ListAccess<'s, 'a> {
+    state: &'s mut State<'a>,
+    list: Value,
+    index: u32,
+    len: u32,
+    depth: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for ListAccess<'_, '_> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.index >= self.len {
+            return Ok(None);
+        }
+        let value = self
+            .state
+            .es
+            .require_list_select_idx_strict(&self.list, self.index)?
+            .ok_or_else(|| error(format_args!("list element {} is missing", self.index)))?;
+        self.index += 1;
+        seed.deserialize(Deserializer {
+            state: &mut *self.state,
+            value,
+            depth: self.depth,
+        })
+        .map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some((self.len - self.index) as usize)
+    }
+}
+
+struct AttrsAccess<'s, 'a> {
+    state: &'s mut State<'a>,
+    attrs: Value,
+    names: std::vec::IntoIter<String>,
+    name: Option<String>,
+    depth: usize,
+}
+
+impl<'de> de::MapAccess<'de> for AttrsAccess<'_, '_> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.names.next() {
+            Some(name) => {
+                self.name = Some(name.clone());
+                seed.deserialize(name.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let name = self
+            .name
+            .take()
+            .ok_or_else(|| error("next_value_seed called before next_key_seed"))?;
+        let value = self.state.es.require_attrs_select(&self.attrs, &name)?;
+        seed.deserialize(Deserializer {
+            state: &mut *self.state,
+            value,
+            depth: self.depth,
+        })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.names.len())
+    }
+}
+
+struct VariantAccess<'s, 'a> {
+    variant: String,
+    value: Deserializer<'s, 'a>,
+}
+
+impl<'de, 's, 'a> de::EnumAccess<'de> for VariantAccess<'s, 'a> {
+    type Error = Error;
+    type Variant = Deserializer<'s, 'a>;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Deserializer<'s, 'a>), Error> {
+        let variant = seed.deserialize(de::IntoDeserializer::<'de, Error>::into_deserializer(
+            self.variant,
+        ))?;
+        Ok((variant, self.value))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Deserializer<'_, '_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        <() as de::Deserialize>::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self, visitor)
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_register_my_thread, test_init};
+    use ::serde::{Deserialize, Serialize};
+    use nix_bindings_store::store::Store;
+    use std::collections::HashMap;
+
+    fn with_eval_state(f: impl FnOnce(&mut EvalState)) {
+        test_init();
+        let guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        f(&mut es);
+        drop(guard);
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Rect { w: i64, h: i64 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Record {
+        name: String,
+        port: u16,
+        enabled: bool,
+        ratio: f64,
+        tags: Vec<String>,
+        parent: Option<Box<Record>>,
+        shapes: Vec<Shape>,
+        pair: (i32, String),
+    }
+
+    fn record() -> Record {
+        Record {
+            name: "flack".to_string(),
+            port: 2019,
+            enabled: true,
+            ratio: 0.5,
+            tags: vec!["a".to_string(), "b".to_string()],
+            parent: None,
+            shapes: vec![Shape::Point, Shape::Circle(1.5), Shape::Rect { w: 2, h: 3 }],
+            pair: (-1, "x".to_string()),
+        }
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        with_eval_state(|es| {
+            let input = record();
+            let value = to_value(es, &input).unwrap();
+            assert_eq!(es.value_type(&value).unwrap(), ValueType::AttrSet);
+            let output: Record = from_value(es, &value).unwrap();
+            assert_eq!(input, output);
+        });
+    }
+
+    #[test]
+    fn serde_to_value_builds_nix_values() {
+        with_eval_state(|es| {
+            let value = to_value(es, &record()).unwrap();
+            let check = es
+                .eval_from_string(
+                    r#"r: r.port == 2019 && r.enabled && r.parent == null
+                        && r.ratio == 0.5 && r.tags == [ "a" "b" ]
+                        && r.shapes == [ "Point" { Circle = 1.5; } { Rect = { w = 2; h = 3; }; } ]
+                        && r.pair == [ (-1) "x" ]"#,
+                    "<test>",
+                )
+                .unwrap();
+            let result = es.call(check, value).unwrap();
+            assert!(es.require_bool(&result).unwrap());
+        });
+    }
+
+    #[test]
+    fn serde_from_value_reads_nix_values() {
+        with_eval_state(|es| {
+            let value = es
+                .eval_from_string(
+                    r#"{ name = "flack"; port = 2000 + 19; enabled = true; ratio = 0.5;
+                         tags = [ "a" "b" ]; parent = null; pair = [ (-1) "x" ];
+                         shapes = [ "Point" { Circle = 1.5; } { Rect = { w = 2; h = 3; }; } ];
+                         unknown = 1; }"#,
+                    "<test>",
+                )
+                .unwrap();
+            let output: Record = from_value(es, &value).unwrap();
+            assert_eq!(output, record());
+        });
+    }
+
+    #[test]
+    fn serde_from_value_coerces_like_to_json() {
+        with_eval_state(|es| {
+            let value = es
+                .eval_from_string(
+                    r#"[ { outPath = "/out"; } { __toString = self: "str"; outPath = "/ignored"; } /path ]"#,
+                    "<test>",
+                )
+                .unwrap();
+            let output: Vec<String> = from_value(es, &value).unwrap();
+            assert_eq!(output, vec!["/out", "str", "/path"]);
+        });
+    }
+
+    #[test]
+    fn serde_map_keys() {
+        with_eval_state(|es| {
+            let input = BTreeMap::from([(1, true), (2, false)]);
+            let value = to_value(es, &input).unwrap();
+            assert_eq!(es.require_attrs_names(&value).unwrap(), vec!["1", "2"]);
+            let output: BTreeMap<String, bool> = from_value(es, &value).unwrap();
+            assert_eq!(output.get("2"), Some(&false));
+
+            let err = to_value(es, &HashMap::from([((1, 2), 3)])).err().unwrap();
+            assert!(err.to_string().contains("attribute names must be strings"));
+        });
+    }
+
+    #[test]
+    fn serde_errors() {
+        with_eval_state(|es| {
+            let err = to_value(es, &u64::MAX).err().unwrap();
+            assert!(err.to_string().contains("does not fit in a Nix int"));
+
+            let value = es.eval_from_string("x: x", "<test>").unwrap();
+            let err = from_value::<i64>(es, &value).unwrap_err();
+            assert!(err.to_string().contains("cannot convert a Function"));
+
+            let value = es.eval_from_string("[ 1 2 3 ]", "<test>").unwrap();
+            assert!(from_value::<(i64, i64)>(es, &value).is_err());
+
+            let value = es.eval_from_string(r#"throw "boom""#, "<test>").unwrap();
+            let err = from_value::<i64>(es, &value).unwrap_err();
+            assert!(err.to_string().contains("boom"));
+        });
+    }
+
+    #[test]
+    fn serde_limits() {
+        with_eval_state(|es| {
+            let limits = Limits {
+                max_depth: 2,
+                max_values: 5,
+            };
+            let nested = vec![vec![vec![1]]];
+            assert!(to_value_with_limits(es, &vec![vec![1]], limits).is_ok());
+            let err = to_value_with_limits(es, &nested, limits).err().unwrap();
+            assert!(err.to_string().contains("nested more than 2 levels"));
+            let err = to_value_with_limits(es, &vec![1; 5], limits).err().unwrap();
+            assert!(err.to_string().contains("more than 5 elements"));
+
+            let value = es
+                .eval_from_string("let x = { inherit x; }; in x", "<test>")
+                .unwrap();
+            #[derive(Deserialize)]
+            struct Node {
+                x: Box<Node>,
+            }
+            let err = from_value_with_limits::<Node>(es, &value, limits)
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains("nested more than 2 levels"));
+        });
+    }
+}
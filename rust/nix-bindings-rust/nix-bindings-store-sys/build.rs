@@ -27,7 +27,10 @@ impl bindgen::callbacks::ParseCallbacks for AddZerocopyDerives {
 
 fn main() {
     println!("cargo:rerun-if-changed=include/nix-c-store.h");
+    println!("cargo:rerun-if-changed=include/nix_api_store_ext.h");
+    println!("cargo:rerun-if-changed=shim/nix_api_store_ext.cc");
     println!("cargo:rustc-link-lib=nixstorec");
+    println!("cargo:rustc-link-lib=nixstore");
 
     let mut args = Vec::new();
     for path in pkg_config::probe_library("nix-store-c")
@@ -54,4 +57,22 @@ fn main() {
     bindings
         .write_to_file(out_path.join("bindings.rs"))
         .expect("Couldn't write bindings!");
+
+    compile_shim();
+}
+
+/// Compiles the functions declared in include/nix_api_store_ext.h, which the pinned Nix lacks.
+fn compile_shim() {
+    let mut build = cc::Build::new();
+    build.cpp(true).std("c++23").include("include");
+    for lib in ["nix-store-c", "nix-store"] {
+        let lib = pkg_config::Config::new()
+            .cargo_metadata(false)
+            .probe(lib)
+            .unwrap();
+        build.includes(&lib.include_paths);
+    }
+    build
+        .file("shim/nix_api_store_ext.cc")
+        .compile("nixstorecext");
 }
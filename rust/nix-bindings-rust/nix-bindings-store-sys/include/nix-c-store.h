@@ -1 +1,2 @@
 #include <nix_api_store.h>
+#include "nix_api_store_ext.h"
@@ -0,0 +1,396 @@
+#ifndef NIX_API_STORE_EXT_H
+#define NIX_API_STORE_EXT_H
+/**
+ * @file
+ * @brief Store functions missing from the pinned Nix C API
+ *
+ * These are implemented in `shim/nix_api_store_ext.cc` against the Nix C++ API, and can be
+ * dropped once Nix exports them itself.
+ */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#include <nix_api_util.h>
+#include <nix_api_store.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+// cffi start
+
+/** @brief What the store knows about a valid store path. */
+typedef struct nix_path_info nix_path_info;
+
+/**
+ * @brief Serializes a store path as a NAR.
+ *
+ * Works for any kind of store, including binary caches and remote stores whose paths
+ * aren't on the local filesystem.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] store nix store reference
+ * @param[in] path the store path to serialize, which must be valid
+ * @param[in] userdata passed to the callback
+ * @param[in] callback called with each chunk of the NAR, in order
+ * @return NIX_OK on success
+ */
+nix_err nix_store_nar_from_path(
+    nix_c_context * context,
+    Store * store,
+    const StorePath * path,
+    void * userdata,
+    void (*callback)(void * userdata, const char * data, size_t len));
+
+/**
+ * @brief Reads more of a file being added to the store.
+ *
+ * @param[in] userdata the user data passed to nix_store_add_from_source
+ * @param[out] buf where to put what was read
+ * @param[in] len how much room there is in buf
+ * @return the number of bytes read, 0 at EOF, or -1 if reading failed
+ */
+typedef int64_t (*nix_read_callback)(void * userdata, char * buf, size_t len);
+
+/**
+ * @brief Adds the contents of a single regular file to the store, as they are read.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] store nix store reference
+ * @param[in] name the name part of the store path
+ * @param[in] method how to hash the contents: "flat", "nar", "text" or "git"
+ * @param[in] hash_algo the hash algorithm, like "sha256"
+ * @param[in] references store paths the contents refer to, which must be valid
+ * @param[in] n_references the number of references
+ * @param[in] expected_hash Optional, the hash the contents must have, in any format Nix accepts.
+ * If they hash differently, nothing is added.
+ * @param[in] repair whether to rewrite the path if it's already valid
+ * @param[in] userdata passed to the callback
+ * @param[in] callback reads the contents until EOF
+ * @return the store path, which must be freed with nix_store_path_free, or NULL on error
+ */
+StorePath * nix_store_add_from_source(
+    nix_c_context * context,
+    Store * store,
+    const char * name,
+    const char * method,
+    const char * hash_algo,
+    const StorePath * const * references,
+    size_t n_references,
+    const char * expected_hash,
+    bool repair,
+    void * userdata,
+    nix_read_callback callback);
+
+/**
+ * @brief Adds a file, symlink or directory from the filesystem to the store.
+ *
+ * Symlinks are added as symlinks, not followed.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] store nix store reference
+ * @param[in] name the name part of the store path
+ * @param[in] fs_path the path to add
+ * @param[in] method how to hash the contents: "flat", "nar", "text" or "git"
+ * @param[in] hash_algo the hash algorithm, like "sha256"
+ * @param[in] references store paths the contents refer to, which must be valid
+ * @param[in] n_references the number of references
+ * @param[in] expected_hash Optional, the hash the contents must have, in any format Nix accepts.
+ * If they hash differently, nothing is added.
+ * @param[in] repair whether to rewrite the path if it's already valid
+ * @return the store path, which must be freed with nix_store_path_free, or NULL on error
+ */
+StorePath * nix_store_add_path(
+    nix_c_context * context,
+    Store * store,
+    const char * name,
+    const char * fs_path,
+    const char * method,
+    const char * hash_algo,
+    const StorePath * const * references,
+    size_t n_references,
+    const char * expected_hash,
+    bool repair);
+
+/**
+ * @brief Queries what the store knows about a valid store path.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] store nix store reference
+ * @param[in] path the store path to query
+ * @return the path info, which must be freed with nix_path_info_free, or NULL on error,
+ *         such as the path not being valid
+ */
+nix_path_info * nix_store_query_path_info(nix_c_context * context, Store * store, const StorePath * path);
+
+/**
+ * @brief Frees a path info.
+ * @param[in] info the path info to free
+ */
+void nix_path_info_free(nix_path_info * info);
+
+/**
+ * @brief Gets the hash of the path's NAR serialization, in SRI format.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] info the path info
+ * @param[in] callback called with the hash
+ * @param[in] user_data passed to the callback
+ * @return NIX_OK on success
+ */
+nix_err nix_path_info_get_nar_hash(
+    nix_c_context * context, const nix_path_info * info, nix_get_string_callback callback, void * user_data);
+
+/**
+ * @brief Gets the store paths the path refers to.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] info the path info
+ * @param[in] userdata passed to the callback
+ * @param[in] callback called with each reference, which is only valid during the call
+ * @return NIX_OK on success
+ */
+nix_err nix_path_info_get_references(
+    nix_c_context * context,
+    const nix_path_info * info,
+    void * userdata,
+    void (*callback)(void * userdata, const StorePath * path));
+
+/**
+ * @brief Gets the derivation that produced the path.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] info the path info
+ * @return the deriver, which must be freed with nix_store_path_free, or NULL if the store
+ *         doesn't know it
+ */
+StorePath * nix_path_info_get_deriver(nix_c_context * context, const nix_path_info * info);
+
+/**
+ * @brief Gets the signatures on the path.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] info the path info
+ * @param[in] userdata passed to the callback
+ * @param[in] callback called with each signature, which is only valid during the call
+ * @return NIX_OK on success
+ */
+nix_err nix_path_info_get_signatures(
+    nix_c_context * context,
+    const nix_path_info * info,
+    void * userdata,
+    void (*callback)(void * userdata, const char * signature));
+
+/**
+ * @brief Gets how the path is content-addressed, like `fixed:r:sha256:...`.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] info the path info
+ * @param[in] callback called with the content address, which is empty for input-addressed paths
+ * @param[in] user_data passed to the callback
+ * @return NIX_OK on success
+ */
+nix_err nix_path_info_get_ca(
+    nix_c_context * context, const nix_path_info * info, nix_get_string_callback callback, void * user_data);
+
+/**
+ * @brief Gets when the path was registered as valid.
+ * @param[in] info the path info
+ * @return seconds since the epoch, or 0 if the store doesn't know
+ */
+int64_t nix_path_info_get_registration_time(const nix_path_info * info);
+
+/**
+ * @brief Gets the size of the path's NAR serialization.
+ * @param[in] info the path info
+ * @return the size in bytes
+ */
+uint64_t nix_path_info_get_nar_size(const nix_path_info * info);
+
+/**
+ * @brief Imports a NAR into the store as the given path.
+ *
+ * The NAR is checked against the hash and size as it is read, and the path is only
+ * registered if they match.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] store nix store reference
+ * @param[in] path the store path to import as
+ * @param[in] nar_hash the hash of the NAR, in any format Nix accepts
+ * @param[in] nar_size the size of the NAR
+ * @param[in] references store paths the path refers to, which must be valid
+ * @param[in] n_references the number of references
+ * @param[in] deriver Optional, the derivation that produced the path
+ * @param[in] signatures signatures on the path
+ * @param[in] n_signatures the number of signatures
+ * @param[in] ca Optional, how the path is content-addressed, like `fixed:r:sha256:...`
+ * @param[in] check_sigs whether to require a trusted signature on input-addressed paths
+ * @param[in] repair whether to rewrite the path if it's already valid
+ * @param[in] userdata passed to the callback
+ * @param[in] callback reads the NAR until EOF
+ * @return NIX_OK on success
+ */
+nix_err nix_store_add_nar(
+    nix_c_context * context,
+    Store * store,
+    const StorePath * path,
+    const char * nar_hash,
+    uint64_t nar_size,
+    const StorePath * const * references,
+    size_t n_references,
+    const StorePath * deriver,
+    const char * const * signatures,
+    size_t n_signatures,
+    const char * ca,
+    bool check_sigs,
+    bool repair,
+    void * userdata,
+    nix_read_callback callback);
+
+/**
+ * @brief Protects a store path from garbage collection until this process exits.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] store nix store reference
+ * @param[in] path the store path to protect
+ * @return NIX_OK on success
+ */
+nix_err nix_store_add_temp_root(nix_c_context * context, Store * store, const StorePath * path);
+
+/**
+ * @brief Registers a symlink as an indirect garbage collector root.
+ *
+ * The store path the symlink points to is kept alive for as long as the symlink is.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] store nix store reference, which must be a local or daemon store
+ * @param[in] link the absolute path of the symlink
+ * @return NIX_OK on success
+ */
+nix_err nix_store_add_indirect_root(nix_c_context * context, Store * store, const char * link);
+
+/**
+ * @brief Creates a symlink to a store path and registers it as a garbage collector root.
+ *
+ * A symlink outside the store's gcroots directory is registered as an indirect root.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] store nix store reference, which must be a local or daemon store
+ * @param[in] path the store path to keep alive, which must be valid
+ * @param[in] link where to create the symlink, replacing any symlink there
+ * @param[in] callback called with the absolute path of the symlink
+ * @param[in] user_data passed to the callback
+ * @return NIX_OK on success
+ */
+nix_err nix_store_add_perm_root(
+    nix_c_context * context,
+    Store * store,
+    const StorePath * path,
+    const char * link,
+    nix_get_string_callback callback,
+    void * user_data);
+
+/**
+ * @brief How to realise a derivation, as taken by nix_store_realise_with_events.
+ *
+ * Zero and -1 leave the corresponding setting as it is.
+ */
+typedef struct nix_realise_options
+{
+    /** Keep building other derivations after one fails. */
+    bool keep_going;
+    /** How many builds may run at once, or -1. */
+    int64_t max_jobs;
+    /** How long a build may run before it's killed, in seconds, or 0. */
+    uint64_t timeout;
+    /** How long a build may go without output before it's killed, in seconds, or 0. */
+    uint64_t max_silent_time;
+    /** 0 to build normally, 1 to repair, 2 to check. */
+    uint32_t build_mode;
+} nix_realise_options;
+
+/**
+ * @brief Realises a derivation, reporting its progress as it goes.
+ *
+ * Nix reads the options from its global settings, so they're set for the duration of the call,
+ * and the rest of the process sees them too. Calls with different options wait for each other;
+ * calls with the same options don't.
+ * Remote stores get a connection of their own, so the daemon sees the options too.
+ *
+ * Events are Nix's logger calls for the activities of this call, on the calling thread:
+ * kind 0 starts an activity of the given ActivityType with its description, kind 1 stops
+ * one, and kind 2 is a result of the given ResultType, passed on for log lines (101 and
+ * 107) and phases (104) with their text.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] store nix store reference
+ * @param[in] path the derivation to realise
+ * @param[in] options how to realise it
+ * @param[in] userdata passed to the callbacks
+ * @param[in] output_callback called with each output's name and store path, which is only
+ *            valid during the call
+ * @param[in] event_callback called with each event
+ * @return NIX_OK on success
+ */
+nix_err nix_store_realise_with_events(
+    nix_c_context * context,
+    Store * store,
+    const StorePath * path,
+    const nix_realise_options * options,
+    void * userdata,
+    void (*output_callback)(void * userdata, const char * outname, const StorePath * out),
+    void (*event_callback)(void * userdata, uint32_t kind, uint64_t activity, uint32_t type, const char * text));
+
+/**
+ * @brief Works out what realising some paths would do, like `nix build --dry-run`.
+ *
+ * Substituters are asked which paths they have, but nothing is downloaded or built.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] store nix store reference
+ * @param[in] paths derivations, whose outputs would be realised, and other store paths
+ * @param[in] n_paths the number of paths
+ * @param[in] userdata passed to the callback
+ * @param[in] callback called with each derivation that would be built (kind 0), path that
+ *            would be substituted (kind 1) and missing path that can't be either (kind 2),
+ *            which is only valid during the call
+ * @param[out] download_size the estimated size of the NARs to download, compressed
+ * @param[out] nar_size the estimated size of the NARs to substitute, unpacked
+ * @return NIX_OK on success
+ */
+nix_err nix_store_query_missing(
+    nix_c_context * context,
+    Store * store,
+    const StorePath * const * paths,
+    size_t n_paths,
+    void * userdata,
+    void (*callback)(void * userdata, uint32_t kind, const StorePath * path),
+    uint64_t * download_size,
+    uint64_t * nar_size);
+
+/**
+ * @brief Gets the log of a derivation's last build, like `nix log`.
+ *
+ * Looks in the store's log directory, then asks the substituters that keep logs.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] store nix store reference
+ * @param[in] path the derivation, or an output of it
+ * @param[in] callback called with the log, if there is one
+ * @param[in] user_data passed to the callback
+ * @return whether there was a log
+ */
+bool nix_store_get_build_log(
+    nix_c_context * context,
+    Store * store,
+    const StorePath * path,
+    nix_get_string_callback callback,
+    void * user_data);
+
+// cffi end
+#ifdef __cplusplus
+}
+#endif
+
+#endif // NIX_API_STORE_EXT_H
@@ -0,0 +1,640 @@
+#include <condition_variable>
+#include <mutex>
+
+#include <nix_api_util.h>
+#include <nix_api_util_internal.h>
+#include <nix_api_store.h>
+#include <nix_api_store_internal.h>
+
+#include "nix/store/content-address.hh"
+#include "nix/store/derived-path.hh"
+#include "nix/store/globals.hh"
+#include "nix/store/indirect-root-store.hh"
+#include "nix/store/local-fs-store.hh"
+#include "nix/store/log-store.hh"
+#include "nix/store/path-info.hh"
+#include "nix/store/remote-store.hh"
+#include "nix/store/store-api.hh"
+#include "nix/store/store-cast.hh"
+#include "nix/store/store-open.hh"
+#include "nix/util/file-system.hh"
+#include "nix/util/hash.hh"
+#include "nix/util/logging.hh"
+#include "nix/util/posix-source-accessor.hh"
+#include "nix/util/serialise.hh"
+
+#include "nix_api_store_ext.h"
+
+struct nix_path_info
+{
+    nix::ref<const nix::ValidPathInfo> info;
+};
+
+namespace {
+
+/** A Source that reads from a nix_read_callback. */
+struct CallbackSource : nix::Source
+{
+    void * userdata;
+    nix_read_callback callback;
+
+    CallbackSource(void * userdata, nix_read_callback callback)
+        : userdata(userdata)
+        , callback(callback)
+    {
+    }
+
+    size_t read(char * data, size_t len) override
+    {
+        auto n = callback(userdata, data, len);
+        if (n < 0)
+            throw nix::Error("reading the contents to add to the store failed");
+        if (n == 0)
+            throw nix::EndOfFile("end of the contents to add to the store");
+        return n;
+    }
+};
+
+nix::StorePathSet referenceSet(const StorePath * const * references, size_t n_references)
+{
+    nix::StorePathSet refs;
+    for (size_t i = 0; i < n_references; i++)
+        refs.insert(references[i]->path);
+    return refs;
+}
+
+/** Throws unless a path about to be added has the expected hash, so nothing else gets added. */
+void checkHash(
+    nix::Store & store,
+    std::string_view name,
+    const nix::SourcePath & path,
+    nix::ContentAddressMethod method,
+    nix::HashAlgorithm algo,
+    const nix::StorePathSet & references,
+    const char * expected_hash)
+{
+    auto expected = nix::Hash::parseAny(expected_hash, algo);
+    auto [storePath, hash] = store.computeStorePath(name, path, method, algo, references);
+    if (hash != expected)
+        throw nix::Error(
+            "hash mismatch adding '%s' to the store: expected %s, got %s",
+            store.printStorePath(storePath),
+            expected.to_string(nix::HashFormat::SRI, true),
+            hash.to_string(nix::HashFormat::SRI, true));
+}
+
+/** Where a realisation on this thread wants its events. */
+struct EventSink
+{
+    void * userdata;
+    void (*callback)(void * userdata, uint32_t kind, uint64_t activity, uint32_t type, const char * text);
+};
+
+thread_local EventSink * eventSink = nullptr;
+
+/**
+ * Passes everything on to the logger it replaced, and the activities of realisations on
+ * this thread to their event callbacks too.
+ */
+struct EventLogger : nix::Logger
+{
+    std::unique_ptr<nix::Logger> next;
+
+    EventLogger(std::unique_ptr<nix::Logger> next)
+        : next(std::move(next))
+    {
+    }
+
+    void stop() override
+    {
+        next->stop();
+    }
+
+    void pause() override
+    {
+        next->pause();
+    }
+
+    void resume() override
+    {
+        next->resume();
+    }
+
+    bool isVerbose() override
+    {
+        return next->isVerbose();
+    }
+
+    void log(nix::Verbosity lvl, std::string_view s) override
+    {
+        next->log(lvl, s);
+    }
+
+    void logEI(const nix::ErrorInfo & ei) override
+    {
+        next->logEI(ei);
+    }
+
+    void warn(const std::string & msg) override
+    {
+        next->warn(msg);
+    }
+
+    void startActivity(
+        nix::ActivityId act,
+        nix::Verbosity lvl,
+        nix::ActivityType type,
+        const std::string & s,
+        const Fields & fields,
+        nix::ActivityId parent) override
+    {
+        if (eventSink)
+            eventSink->callback(eventSink->userdata, 0, act, type, s.c_str());
+        next->startActivity(act, lvl, type, s, fields, parent);
+    }
+
+    void stopActivity(nix::ActivityId act) override
+    {
+        if (eventSink)
+            eventSink->callback(eventSink->userdata, 1, act, 0, nullptr);
+        next->stopActivity(act);
+    }
+
+    void result(nix::ActivityId act, nix::ResultType type, const Fields & fields) override
+    {
+        if (eventSink
+            && (type == nix::resBuildLogLine || type == nix::resPostBuildLogLine || type == nix::resSetPhase)
+            && !fields.empty() && fields[0].type == nix::Logger::Field::tString)
+            eventSink->callback(eventSink->userdata, 2, act, type, fields[0].s.c_str());
+        next->result(act, type, fields);
+    }
+
+    void writeToStdout(std::string_view s) override
+    {
+        next->writeToStdout(s);
+    }
+
+    std::optional<char> ask(std::string_view s) override
+    {
+        return next->ask(s);
+    }
+
+    void setPrintBuildLogs(bool printBuildLogs) override
+    {
+        next->setPrintBuildLogs(printBuildLogs);
+    }
+};
+
+/** Sends this thread's events to a sink until destroyed. */
+struct SinkGuard
+{
+    SinkGuard(EventSink * sink)
+    {
+        static std::once_flag installed;
+        std::call_once(installed, []() { nix::logger = std::make_unique<EventLogger>(std::move(nix::logger)); });
+        eventSink = sink;
+    }
+
+    ~SinkGuard()
+    {
+        eventSink = nullptr;
+    }
+};
+
+/** The realise options in effect, and how many realisations are using them. */
+struct Overrides
+{
+    std::mutex lock;
+    std::condition_variable idle;
+    size_t active = 0;
+    bool keepGoing;
+    int64_t maxJobs;
+    uint64_t timeout;
+    uint64_t maxSilentTime;
+
+    /** The settings from before the first of the current realisations. */
+    bool savedKeepGoing;
+    unsigned int savedMaxJobs;
+    time_t savedTimeout;
+    time_t savedMaxSilentTime;
+};
+
+Overrides overrides;
+
+bool sameOverrides(const nix_realise_options & options)
+{
+    return overrides.keepGoing == options.keep_going && overrides.maxJobs == options.max_jobs
+           && overrides.timeout == options.timeout && overrides.maxSilentTime == options.max_silent_time;
+}
+
+/** Applies realise options to Nix's settings until destroyed, waiting for realisations with other options. */
+struct OverridesGuard
+{
+    OverridesGuard(const nix_realise_options & options)
+    {
+        std::unique_lock<std::mutex> lock(overrides.lock);
+        overrides.idle.wait(lock, [&]() { return overrides.active == 0 || sameOverrides(options); });
+        if (overrides.active++ > 0)
+            return;
+
+        auto & settings = nix::settings;
+        overrides.keepGoing = options.keep_going;
+        overrides.maxJobs = options.max_jobs;
+        overrides.timeout = options.timeout;
+        overrides.maxSilentTime = options.max_silent_time;
+        overrides.savedKeepGoing = settings.keepGoing.get();
+        overrides.savedMaxJobs = settings.maxBuildJobs.get();
+        overrides.savedTimeout = settings.buildTimeout.get();
+        overrides.savedMaxSilentTime = settings.maxSilentTime.get();
+        if (options.keep_going)
+            settings.keepGoing = true;
+        if (options.max_jobs >= 0)
+            settings.maxBuildJobs = (unsigned int) options.max_jobs;
+        if (options.timeout > 0)
+            settings.buildTimeout = (time_t) options.timeout;
+        if (options.max_silent_time > 0)
+            settings.maxSilentTime = (time_t) options.max_silent_time;
+    }
+
+    ~OverridesGuard()
+    {
+        std::unique_lock<std::mutex> lock(overrides.lock);
+        if (--overrides.active > 0)
+            return;
+
+        auto & settings = nix::settings;
+        settings.keepGoing = overrides.savedKeepGoing;
+        settings.maxBuildJobs = overrides.savedMaxJobs;
+        settings.buildTimeout = overrides.savedTimeout;
+        settings.maxSilentTime = overrides.savedMaxSilentTime;
+        overrides.idle.notify_all();
+    }
+};
+
+} // namespace
+
+extern "C" {
+
+nix_err nix_store_nar_from_path(
+    nix_c_context * context,
+    Store * store,
+    const StorePath * path,
+    void * userdata,
+    void (*callback)(void * userdata, const char * data, size_t len))
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        nix::LambdaSink sink([&](std::string_view data) { callback(userdata, data.data(), data.size()); });
+        store->ptr->narFromPath(path->path, sink);
+    }
+    NIXC_CATCH_ERRS
+}
+
+StorePath * nix_store_add_from_source(
+    nix_c_context * context,
+    Store * store,
+    const char * name,
+    const char * method,
+    const char * hash_algo,
+    const StorePath * const * references,
+    size_t n_references,
+    const char * expected_hash,
+    bool repair,
+    void * userdata,
+    nix_read_callback callback)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        auto hashMethod = nix::ContentAddressMethod::parse(method);
+        auto algo = nix::parseHashAlgo(hash_algo);
+        auto refs = referenceSet(references, n_references);
+        CallbackSource source(userdata, callback);
+        if (!expected_hash) {
+            auto path = store->ptr->addToStoreFromDump(
+                source,
+                name,
+                nix::FileSerialisationMethod::Flat,
+                hashMethod,
+                algo,
+                refs,
+                repair ? nix::Repair : nix::NoRepair);
+            return new StorePath{std::move(path)};
+        }
+
+        // The contents can only be read once, so keep them to hash before adding anything.
+        auto [fd, tmpPath] = nix::createTempFile();
+        nix::AutoDelete deleteTmp(tmpPath, false);
+        {
+            nix::FdSink sink(fd.get());
+            source.drainInto(sink);
+            sink.flush();
+        }
+        auto tmp = nix::PosixSourceAccessor::createAtRoot(tmpPath);
+        checkHash(*store->ptr, name, tmp, hashMethod, algo, refs, expected_hash);
+        auto path = store->ptr->addToStore(
+            name, tmp, hashMethod, algo, refs, nix::defaultPathFilter, repair ? nix::Repair : nix::NoRepair);
+        return new StorePath{std::move(path)};
+    }
+    NIXC_CATCH_ERRS_NULL
+}
+
+StorePath * nix_store_add_path(
+    nix_c_context * context,
+    Store * store,
+    const char * name,
+    const char * fs_path,
+    const char * method,
+    const char * hash_algo,
+    const StorePath * const * references,
+    size_t n_references,
+    const char * expected_hash,
+    bool repair)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        auto hashMethod = nix::ContentAddressMethod::parse(method);
+        auto algo = nix::parseHashAlgo(hash_algo);
+        auto refs = referenceSet(references, n_references);
+        auto source = nix::PosixSourceAccessor::createAtRoot(fs_path);
+        if (expected_hash)
+            checkHash(*store->ptr, name, source, hashMethod, algo, refs, expected_hash);
+        auto path = store->ptr->addToStore(
+            name, source, hashMethod, algo, refs, nix::defaultPathFilter, repair ? nix::Repair : nix::NoRepair);
+        return new StorePath{std::move(path)};
+    }
+    NIXC_CATCH_ERRS_NULL
+}
+
+nix_path_info * nix_store_query_path_info(nix_c_context * context, Store * store, const StorePath * path)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        return new nix_path_info{store->ptr->queryPathInfo(path->path)};
+    }
+    NIXC_CATCH_ERRS_NULL
+}
+
+void nix_path_info_free(nix_path_info * info)
+{
+    delete info;
+}
+
+nix_err nix_path_info_get_nar_hash(
+    nix_c_context * context, const nix_path_info * info, nix_get_string_callback callback, void * user_data)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        return call_nix_get_string_callback(info->info->narHash.to_string(nix::HashFormat::SRI, true), callback, user_data);
+    }
+    NIXC_CATCH_ERRS
+}
+
+nix_err nix_path_info_get_references(
+    nix_c_context * context,
+    const nix_path_info * info,
+    void * userdata,
+    void (*callback)(void * userdata, const StorePath * path))
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        for (auto & ref : info->info->references) {
+            const StorePath path{ref};
+            callback(userdata, &path);
+        }
+    }
+    NIXC_CATCH_ERRS
+}
+
+StorePath * nix_path_info_get_deriver(nix_c_context * context, const nix_path_info * info)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        if (!info->info->deriver)
+            return nullptr;
+        return new StorePath{*info->info->deriver};
+    }
+    NIXC_CATCH_ERRS_NULL
+}
+
+nix_err nix_path_info_get_signatures(
+    nix_c_context * context,
+    const nix_path_info * info,
+    void * userdata,
+    void (*callback)(void * userdata, const char * signature))
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        for (auto & sig : info->info->sigs)
+            callback(userdata, sig.c_str());
+    }
+    NIXC_CATCH_ERRS
+}
+
+nix_err nix_path_info_get_ca(
+    nix_c_context * context, const nix_path_info * info, nix_get_string_callback callback, void * user_data)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        return call_nix_get_string_callback(nix::renderContentAddress(info->info->ca), callback, user_data);
+    }
+    NIXC_CATCH_ERRS
+}
+
+int64_t nix_path_info_get_registration_time(const nix_path_info * info)
+{
+    return info->info->registrationTime;
+}
+
+uint64_t nix_path_info_get_nar_size(const nix_path_info * info)
+{
+    return info->info->narSize;
+}
+
+nix_err nix_store_add_nar(
+    nix_c_context * context,
+    Store * store,
+    const StorePath * path,
+    const char * nar_hash,
+    uint64_t nar_size,
+    const StorePath * const * references,
+    size_t n_references,
+    const StorePath * deriver,
+    const char * const * signatures,
+    size_t n_signatures,
+    const char * ca,
+    bool check_sigs,
+    bool repair,
+    void * userdata,
+    nix_read_callback callback)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        nix::ValidPathInfo info{path->path, nix::Hash::parseAny(nar_hash, nix::HashAlgorithm::SHA256)};
+        info.narSize = nar_size;
+        info.references = referenceSet(references, n_references);
+        if (deriver)
+            info.deriver = deriver->path;
+        for (size_t i = 0; i < n_signatures; i++)
+            info.sigs.insert(signatures[i]);
+        if (ca)
+            info.ca = nix::ContentAddress::parseOpt(ca);
+        CallbackSource source(userdata, callback);
+        store->ptr->addToStore(
+            info, source, repair ? nix::Repair : nix::NoRepair, check_sigs ? nix::CheckSigs : nix::NoCheckSigs);
+    }
+    NIXC_CATCH_ERRS
+}
+
+nix_err nix_store_add_temp_root(nix_c_context * context, Store * store, const StorePath * path)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        store->ptr->addTempRoot(path->path);
+    }
+    NIXC_CATCH_ERRS
+}
+
+nix_err nix_store_add_indirect_root(nix_c_context * context, Store * store, const char * link)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        nix::require<nix::IndirectRootStore>(*store->ptr).addIndirectRoot(link);
+    }
+    NIXC_CATCH_ERRS
+}
+
+nix_err nix_store_add_perm_root(
+    nix_c_context * context,
+    Store * store,
+    const StorePath * path,
+    const char * link,
+    nix_get_string_callback callback,
+    void * user_data)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        auto root = nix::require<nix::LocalFSStore>(*store->ptr).addPermRoot(path->path, nix::absPath(link));
+        return call_nix_get_string_callback(root, callback, user_data);
+    }
+    NIXC_CATCH_ERRS
+}
+
+nix_err nix_store_realise_with_events(
+    nix_c_context * context,
+    Store * store,
+    const StorePath * path,
+    const nix_realise_options * options,
+    void * userdata,
+    void (*output_callback)(void * userdata, const char * outname, const StorePath * out),
+    void (*event_callback)(void * userdata, uint32_t kind, uint64_t activity, uint32_t type, const char * text))
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        EventSink sink{userdata, event_callback};
+        SinkGuard sinkGuard(&sink);
+        OverridesGuard overridesGuard(*options);
+
+        // Daemons get a client's settings when it connects, so the options need a connection of their own.
+        Store target{store->ptr};
+        if (dynamic_cast<nix::RemoteStore *>(&*store->ptr))
+            target.ptr = nix::openStore(store->ptr->config.getReference());
+
+        if (options->build_mode != 0) {
+            auto buildMode = options->build_mode == 1 ? nix::bmRepair : nix::bmCheck;
+            target.ptr->buildPaths(
+                {nix::DerivedPath::Built{
+                    .drvPath = nix::makeConstantStorePathRef(path->path),
+                    .outputs = nix::OutputsSpec::All{},
+                }},
+                buildMode);
+        }
+
+        // The outputs are there now, if they weren't already, so this only reports them.
+        return nix_store_realise(context, &target, const_cast<StorePath *>(path), userdata, output_callback);
+    }
+    NIXC_CATCH_ERRS
+}
+
+nix_err nix_store_query_missing(
+    nix_c_context * context,
+    Store * store,
+    const StorePath * const * paths,
+    size_t n_paths,
+    void * userdata,
+    void (*callback)(void * userdata, uint32_t kind, const StorePath * path),
+    uint64_t * download_size,
+    uint64_t * nar_size)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        std::vector<nix::DerivedPath> targets;
+        for (size_t i = 0; i < n_paths; i++) {
+            auto & path = paths[i]->path;
+            if (path.isDerivation())
+                targets.push_back(nix::DerivedPath::Built{
+                    .drvPath = nix::makeConstantStorePathRef(path),
+                    .outputs = nix::OutputsSpec::All{},
+                });
+            else
+                targets.push_back(nix::DerivedPath::Opaque{path});
+        }
+
+        auto missing = store->ptr->queryMissing(targets);
+        auto report = [&](uint32_t kind, const nix::StorePathSet & set) {
+            for (auto & path : set) {
+                const StorePath p{path};
+                callback(userdata, kind, &p);
+            }
+        };
+        report(0, missing.willBuild);
+        report(1, missing.willSubstitute);
+        report(2, missing.unknown);
+        *download_size = missing.downloadSize;
+        *nar_size = missing.narSize;
+    }
+    NIXC_CATCH_ERRS
+}
+
+bool nix_store_get_build_log(
+    nix_c_context * context,
+    Store * store,
+    const StorePath * path,
+    nix_get_string_callback callback,
+    void * user_data)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        auto stores = nix::getDefaultSubstituters();
+        stores.push_front(store->ptr);
+        for (auto & sub : stores) {
+            auto * logStore = dynamic_cast<nix::LogStore *>(&*sub);
+            if (!logStore)
+                continue;
+            auto log = logStore->getBuildLog(path->path);
+            if (!log)
+                continue;
+            call_nix_get_string_callback(*log, callback, user_data);
+            return true;
+        }
+        return false;
+    }
+    NIXC_CATCH_ERRS_RES(false)
+}
+
+} // extern "C"
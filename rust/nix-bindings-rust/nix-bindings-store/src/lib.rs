@@ -1,3 +1,4 @@
 pub mod derivation;
 pub mod path;
+pub mod path_info;
 pub mod store;
@@ -0,0 +1,89 @@
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context as _, Result};
+
+use super::PathInfo;
+
+impl TryFrom<&PathInfo> for harmonia_store_core::path_info::UnkeyedValidPathInfo {
+    type Error = anyhow::Error;
+
+    fn try_from(info: &PathInfo) -> Result<Self> {
+        Ok(harmonia_store_core::path_info::UnkeyedValidPathInfo {
+            deriver: info
+                .deriver
+                .as_ref()
+                .map(TryInto::try_into)
+                .transpose()
+                .context("Failed to convert deriver")?,
+            nar_hash: info.nar_hash.parse().context("Failed to parse NAR hash")?,
+            references: info
+                .references
+                .iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_>>()
+                .context("Failed to convert references")?,
+            registration_time: info
+                .registration_time
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |time| time.as_secs() as i64),
+            nar_size: info.nar_size,
+            ultimate: false,
+            signatures: info
+                .signatures
+                .iter()
+                .map(|signature| signature.parse())
+                .collect::<Result<_, _>>()
+                .context("Failed to parse signatures")?,
+            ca: info
+                .ca
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .context("Failed to parse content address")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::{AddToStoreOptions, Store};
+
+    #[test]
+    fn path_info_to_harmonia() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store_dir = temp_dir.path().join("store");
+        let state_dir = temp_dir.path().join("state");
+        let mut store = Store::open(
+            Some("local"),
+            [
+                ("store", store_dir.to_str().unwrap()),
+                ("state", state_dir.to_str().unwrap()),
+            ],
+        )
+        .unwrap();
+
+        let dep = store
+            .add_to_store_bytes("dep", b"dep", &AddToStoreOptions::default())
+            .unwrap();
+        let path = store
+            .add_to_store_bytes(
+                "top",
+                b"top",
+                &AddToStoreOptions {
+                    references: vec![dep.clone()],
+                    ..AddToStoreOptions::default()
+                },
+            )
+            .unwrap();
+        let info = store.query_path_info(&path).unwrap();
+
+        let harmonia_info: harmonia_store_core::path_info::UnkeyedValidPathInfo =
+            (&info).try_into().unwrap();
+        let harmonia_dep: harmonia_store_core::store_path::StorePath = (&dep).try_into().unwrap();
+        assert_eq!(harmonia_info.nar_size, info.nar_size);
+        assert!(harmonia_info.references.contains(&harmonia_dep));
+        assert!(harmonia_info.deriver.is_none());
+        assert!(harmonia_info.ca.is_some());
+        assert!(harmonia_info.signatures.is_empty());
+    }
+}
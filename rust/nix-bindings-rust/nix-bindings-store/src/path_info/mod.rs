@@ -0,0 +1,128 @@
+#![cfg(nix_at_least = "2.33")]
+
+use std::ffi::c_char;
+use std::ptr::NonNull;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use nix_bindings_store_sys as raw;
+use nix_bindings_util::{
+    check_call,
+    context::Context,
+    result_string_init,
+    string_return::{callback_get_result_string, callback_get_result_string_data},
+};
+
+use crate::path::StorePath;
+
+/// What the store knows about a valid store path, as returned by [`Store::query_path_info`][crate::store::Store::query_path_info].
+///
+/// **Requires Nix 2.33 or later.**
+///
+/// This is the same information `nix path-info --json` shows.
+#[derive(Clone)]
+pub struct PathInfo {
+    /// The hash of the path's NAR serialization, in SRI format (`sha256-...`).
+    pub nar_hash: String,
+    /// The size of the path's NAR serialization, in bytes.
+    pub nar_size: u64,
+    /// The store paths that this path refers to, possibly including itself.
+    pub references: Vec<StorePath>,
+    /// The derivation that produced the path, if the store knows it.
+    pub deriver: Option<StorePath>,
+    /// When the path was registered as valid, if the store knows.
+    pub registration_time: Option<SystemTime>,
+    /// Signatures on the path, like `cache.nixos.org-1:...`.
+    pub signatures: Vec<String>,
+    /// How the path is content-addressed, like `fixed:r:sha256:...`, or `None` if it's input-addressed.
+    pub ca: Option<String>,
+}
+
+impl PathInfo {
+    /// This is a low level function that you shouldn't have to call unless you are developing the Nix bindings.
+    ///
+    /// Reads everything out of a C path info, which still has to be freed afterwards.
+    ///
+    /// # Safety
+    ///
+    /// `info` must be a valid pointer to a C path info.
+    pub(crate) unsafe fn from_raw(
+        ctx: &mut Context,
+        info: NonNull<raw::path_info>,
+    ) -> Result<Self> {
+        let info = info.as_ptr() as *const raw::path_info;
+
+        let mut nar_hash = result_string_init!();
+        check_call!(raw::path_info_get_nar_hash(
+            ctx,
+            info,
+            Some(callback_get_result_string),
+            callback_get_result_string_data(&mut nar_hash)
+        ))?;
+
+        let mut references = Vec::new();
+        check_call!(raw::path_info_get_references(
+            ctx,
+            info,
+            &mut references as *mut Vec<StorePath> as *mut std::os::raw::c_void,
+            Some(callback_get_store_path)
+        ))?;
+
+        let deriver = check_call!(raw::path_info_get_deriver(ctx, info))?;
+
+        let mut signatures: Vec<Result<String>> = Vec::new();
+        check_call!(raw::path_info_get_signatures(
+            ctx,
+            info,
+            &mut signatures as *mut Vec<Result<String>> as *mut std::os::raw::c_void,
+            Some(callback_get_string)
+        ))?;
+
+        let mut ca = result_string_init!();
+        check_call!(raw::path_info_get_ca(
+            ctx,
+            info,
+            Some(callback_get_result_string),
+            callback_get_result_string_data(&mut ca)
+        ))?;
+        let ca = ca?;
+
+        // Nix uses 0 for "unknown", which is also what binary caches report.
+        let registration_time = raw::path_info_get_registration_time(info);
+
+        Ok(PathInfo {
+            nar_hash: nar_hash?,
+            nar_size: raw::path_info_get_nar_size(info),
+            references,
+            deriver: NonNull::new(deriver).map(|path| StorePath::new_raw(path)),
+            registration_time: (registration_time > 0)
+                .then(|| UNIX_EPOCH + Duration::from_secs(registration_time as u64)),
+            signatures: signatures.into_iter().collect::<Result<_>>()?,
+            ca: (!ca.is_empty()).then_some(ca),
+        })
+    }
+}
+
+unsafe extern "C" fn callback_get_store_path(
+    user_data: *mut std::os::raw::c_void,
+    store_path: *const raw::StorePath,
+) {
+    let ret = &mut *(user_data as *mut Vec<StorePath>);
+    let store_path = raw::store_path_clone(store_path);
+    let store_path =
+        NonNull::new(store_path).expect("nix_store_path_clone returned a null pointer");
+    ret.push(StorePath::new_raw(store_path));
+}
+
+unsafe extern "C" fn callback_get_string(user_data: *mut std::os::raw::c_void, s: *const c_char) {
+    let ret = &mut *(user_data as *mut Vec<Result<String>>);
+    ret.push(
+        std::ffi::CStr::from_ptr(s)
+            .to_str()
+            .map(str::to_owned)
+            .map_err(|e| anyhow::format_err!("Nix string is not valid UTF-8: {}", e)),
+    );
+}
+
+#[cfg(feature = "harmonia")]
+mod harmonia;
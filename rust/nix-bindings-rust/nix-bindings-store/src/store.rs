@@ -10,13 +10,25 @@ use nix_bindings_util_sys as raw_util;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::ffi::{c_char, CString};
+#[cfg(nix_at_least = "2.33")]
+use std::io::{Read, Write};
+#[cfg(nix_at_least = "2.33")]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(nix_at_least = "2.33")]
+use std::path::{Path, PathBuf};
 use std::ptr::null_mut;
 use std::ptr::NonNull;
+#[cfg(nix_at_least = "2.33")]
+use std::sync::mpsc;
 use std::sync::{Arc, LazyLock, Mutex, Weak};
+#[cfg(nix_at_least = "2.33")]
+use std::time::Duration;
 
 #[cfg(nix_at_least = "2.33.0pre")]
 use crate::derivation::Derivation;
 use crate::path::StorePath;
+#[cfg(nix_at_least = "2.33")]
+use crate::path_info::PathInfo;
 
 /* TODO make Nix itself thread safe */
 static INIT: LazyLock<Result<()>> = LazyLock::new(|| unsafe {
@@ -90,6 +102,376 @@ fn callback_get_result_store_path_set_data(vec: &mut Vec<StorePath>) -> *mut std
     vec as *mut Vec<StorePath> as *mut std::os::raw::c_void
 }
 
+/// How the contents of a path are hashed when it is added to the store.
+#[cfg(nix_at_least = "2.33")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ContentAddressMethod {
+    /// Hash the contents of a single regular file, like `nix-store --add-fixed` without `--recursive`.
+    Flat,
+    /// Hash the NAR serialization, which covers directories, symlinks and the executable bit.
+    #[default]
+    Recursive,
+}
+
+#[cfg(nix_at_least = "2.33")]
+impl ContentAddressMethod {
+    fn as_c_str(&self) -> &'static std::ffi::CStr {
+        match self {
+            ContentAddressMethod::Flat => c"flat",
+            ContentAddressMethod::Recursive => c"nar",
+        }
+    }
+}
+
+/// A hash algorithm that Nix can address store paths by.
+#[cfg(nix_at_least = "2.33")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+#[cfg(nix_at_least = "2.33")]
+impl HashAlgorithm {
+    fn as_c_str(&self) -> &'static std::ffi::CStr {
+        match self {
+            HashAlgorithm::Md5 => c"md5",
+            HashAlgorithm::Sha1 => c"sha1",
+            HashAlgorithm::Sha256 => c"sha256",
+            HashAlgorithm::Sha512 => c"sha512",
+        }
+    }
+}
+
+/// Options for adding content to the store, as taken by [`Store::add_to_store_bytes`],
+/// [`Store::add_to_store_reader`] and [`Store::add_to_store_path`].
+///
+/// The defaults match `nix-store --add`: recursive SHA-256 with no references.
+#[cfg(nix_at_least = "2.33")]
+#[derive(Clone, Default)]
+pub struct AddToStoreOptions {
+    /// How the content is hashed.
+    pub method: ContentAddressMethod,
+    /// The hash algorithm.
+    pub hash_algo: HashAlgorithm,
+    /// Store paths that the content refers to. They must be valid in the store.
+    pub references: Vec<StorePath>,
+    /// The hash the content must have, in any format Nix accepts, such as SRI (`sha256-...`).
+    /// If the content hashes differently, nothing is added and an error is returned.
+    pub expected_hash: Option<String>,
+    /// Rewrite the path even if it's already valid, in case it was corrupted.
+    pub repair: bool,
+}
+
+/// The kind of an activity that Nix reports while realising, as in [`BuildEvent::Start`].
+#[cfg(nix_at_least = "2.33")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ActivityType {
+    CopyPath,
+    FileTransfer,
+    Realise,
+    CopyPaths,
+    Builds,
+    /// Building a single derivation. Its log lines and phases are reported against this activity.
+    Build,
+    OptimiseStore,
+    VerifyPaths,
+    Substitute,
+    QueryPathInfo,
+    PostBuildHook,
+    BuildWaiting,
+    FetchTree,
+    /// An activity these bindings don't know about, with Nix's number for it.
+    Other(u32),
+}
+
+#[cfg(nix_at_least = "2.33")]
+impl ActivityType {
+    /// Converts Nix's `ActivityType`.
+    fn from_raw(activity_type: u32) -> ActivityType {
+        match activity_type {
+            100 => ActivityType::CopyPath,
+            101 => ActivityType::FileTransfer,
+            102 => ActivityType::Realise,
+            103 => ActivityType::CopyPaths,
+            104 => ActivityType::Builds,
+            105 => ActivityType::Build,
+            106 => ActivityType::OptimiseStore,
+            107 => ActivityType::VerifyPaths,
+            108 => ActivityType::Substitute,
+            109 => ActivityType::QueryPathInfo,
+            110 => ActivityType::PostBuildHook,
+            111 => ActivityType::BuildWaiting,
+            112 => ActivityType::FetchTree,
+            other => ActivityType::Other(other),
+        }
+    }
+}
+
+/// Something that happened while realising, as passed to [`Store::realise_with_progress`].
+///
+/// Activities nest: a [`ActivityType::Realise`] contains builds and substitutions, which
+/// contain downloads, and so on. Each one is identified by a number that's unique in the process.
+#[cfg(nix_at_least = "2.33")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildEvent {
+    /// An activity started. `text` is Nix's description of it, like `building '/nix/store/...drv'`.
+    Start {
+        activity: u64,
+        activity_type: ActivityType,
+        text: String,
+    },
+    /// An activity finished, successfully or not.
+    Stop { activity: u64 },
+    /// A builder or post-build hook printed a line.
+    LogLine { activity: u64, line: String },
+    /// A build entered a phase, like `buildPhase`.
+    Phase { activity: u64, phase: String },
+}
+
+/// The calls to Nix's logger that `nix_store_realise_with_events` passes on.
+#[cfg(nix_at_least = "2.33")]
+const EVENT_START: u32 = 0;
+#[cfg(nix_at_least = "2.33")]
+const EVENT_STOP: u32 = 1;
+#[cfg(nix_at_least = "2.33")]
+const EVENT_RESULT: u32 = 2;
+
+/// Nix's `ResultType`s for the results we pass on.
+#[cfg(nix_at_least = "2.33")]
+const RESULT_BUILD_LOG_LINE: u32 = 101;
+#[cfg(nix_at_least = "2.33")]
+const RESULT_SET_PHASE: u32 = 104;
+#[cfg(nix_at_least = "2.33")]
+const RESULT_POST_BUILD_LOG_LINE: u32 = 107;
+
+/// Options for [`Store::copy_closure`].
+///
+/// The defaults copy every missing path from the source, without checking signatures.
+#[cfg(nix_at_least = "2.33")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CopyClosureOptions {
+    /// Let the destination substitute paths it's missing before copying them from the source.
+    pub substitute: bool,
+    /// Require trusted signatures on input-addressed paths, like `nix copy` without `--no-check-sigs`.
+    pub check_sigs: bool,
+    /// Copy paths even if they're already valid in the destination, in case they were corrupted.
+    pub repair: bool,
+}
+
+/// Options for [`Store::realise_with_options`] and [`Store::realise_with_progress`].
+///
+/// The defaults leave Nix's settings as they are and build normally. The other options
+/// besides `repair` and `check` go through Nix's global settings; see [`Store::realise_with_options`].
+#[cfg(nix_at_least = "2.33")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealiseOptions {
+    /// Keep building other derivations after one fails, like `--keep-going`.
+    pub keep_going: bool,
+    /// How many builds may run at once, like `--max-jobs`.
+    pub max_jobs: Option<u32>,
+    /// How long a build may run before it's killed, like `--timeout`. Whole seconds only.
+    pub timeout: Option<Duration>,
+    /// How long a build may go without output before it's killed, like `--max-silent-time`.
+    pub max_silent_time: Option<Duration>,
+    /// Rebuild outputs that are already valid, replacing them if they were corrupted.
+    pub repair: bool,
+    /// Rebuild outputs that are already valid, and fail if they don't come out the same.
+    pub check: bool,
+}
+
+/// Nix's `BuildMode`s.
+#[cfg(nix_at_least = "2.33")]
+const BUILD_MODE_NORMAL: u32 = 0;
+#[cfg(nix_at_least = "2.33")]
+const BUILD_MODE_REPAIR: u32 = 1;
+#[cfg(nix_at_least = "2.33")]
+const BUILD_MODE_CHECK: u32 = 2;
+
+#[cfg(nix_at_least = "2.33")]
+impl RealiseOptions {
+    /// Converts the options for `nix_store_realise_with_events`, where 0 and -1 leave the
+    /// setting as it is.
+    fn to_raw(self) -> Result<raw::realise_options> {
+        let build_mode = match (self.repair, self.check) {
+            (false, false) => BUILD_MODE_NORMAL,
+            (true, false) => BUILD_MODE_REPAIR,
+            (false, true) => BUILD_MODE_CHECK,
+            (true, true) => bail!("a realisation can't both repair and check"),
+        };
+        Ok(raw::realise_options {
+            keep_going: self.keep_going,
+            max_jobs: self.max_jobs.map_or(-1, i64::from),
+            timeout: self.timeout.map_or(0, |timeout| timeout.as_secs()),
+            max_silent_time: self
+                .max_silent_time
+                .map_or(0, |max_silent_time| max_silent_time.as_secs()),
+            build_mode,
+        })
+    }
+}
+
+/// What realising some paths would do, as returned by [`Store::query_missing`].
+#[cfg(nix_at_least = "2.33")]
+#[derive(Default)]
+pub struct MissingPaths {
+    /// Derivations that would be built.
+    pub will_build: Vec<StorePath>,
+    /// Paths that would be substituted.
+    pub will_substitute: Vec<StorePath>,
+    /// Paths that are missing, but can't be built or substituted, such as outputs of
+    /// derivations that aren't in the store.
+    pub unknown: Vec<StorePath>,
+    /// The estimated number of bytes to download from substituters, as compressed NARs.
+    pub download_size: u64,
+    /// The estimated total size of the NARs that would be substituted, unpacked.
+    pub nar_size: u64,
+}
+
+/// Which list of [`MissingPaths`] `nix_store_query_missing` is reporting a path for.
+#[cfg(nix_at_least = "2.33")]
+const MISSING_WILL_BUILD: u32 = 0;
+#[cfg(nix_at_least = "2.33")]
+const MISSING_WILL_SUBSTITUTE: u32 = 1;
+#[cfg(nix_at_least = "2.33")]
+const MISSING_UNKNOWN: u32 = 2;
+
+/// A reader for the Nix C API to pull from, with the reader's error if it failed.
+#[cfg(nix_at_least = "2.33")]
+struct Source<R> {
+    reader: R,
+    error: Option<std::io::Error>,
+}
+
+#[cfg(nix_at_least = "2.33")]
+unsafe extern "C" fn callback_read<R: Read>(
+    userdata: *mut std::os::raw::c_void,
+    buf: *mut c_char,
+    len: usize,
+) -> i64 {
+    let source = &mut *(userdata as *mut Source<R>);
+    let buf = std::slice::from_raw_parts_mut(buf as *mut u8, len);
+    loop {
+        match source.reader.read(buf) {
+            Ok(n) => return n as i64,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                source.error = Some(err);
+                return -1;
+            }
+        }
+    }
+}
+
+/// How many chunks of a NAR may be buffered between a [`NarReader`] or [`NarWriter`] and the thread on the other end.
+#[cfg(nix_at_least = "2.33")]
+const NAR_CHUNKS: usize = 16;
+
+/// Lets a value that isn't `Send` move to another thread.
+///
+/// Only for values that nothing else refers to, like a freshly cloned [`StorePath`], whose
+/// C object is then only used on the new thread.
+#[cfg(nix_at_least = "2.33")]
+struct Unshared<T>(T);
+#[cfg(nix_at_least = "2.33")]
+unsafe impl<T> Send for Unshared<T> {}
+#[cfg(nix_at_least = "2.33")]
+impl<T> Unshared<T> {
+    fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// A NAR that is being serialized on another thread, as returned by [`Store::nar_reader`].
+///
+/// **Requires Nix 2.33 or later.**
+///
+/// Nix can't stop partway through a path, so if the reader is dropped early, the rest of the NAR
+/// is produced and thrown away in the background.
+#[cfg(nix_at_least = "2.33")]
+pub struct NarReader {
+    rx: mpsc::Receiver<Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+#[cfg(nix_at_least = "2.33")]
+impl Read for NarReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk.map_err(std::io::Error::other)?;
+                    self.pos = 0;
+                }
+                // The other end is done.
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// A NAR that is being imported into the store on another thread, as returned by [`Store::nar_writer`].
+///
+/// **Requires Nix 2.33 or later.**
+///
+/// Call [`NarWriter::finish`] once the whole NAR is written, to wait for the import and see whether it succeeded.
+/// If the writer is dropped instead, the import fails and nothing is added; dropping waits for that.
+#[cfg(nix_at_least = "2.33")]
+pub struct NarWriter {
+    tx: Option<mpsc::SyncSender<Result<Vec<u8>>>>,
+    thread: Option<std::thread::JoinHandle<Result<()>>>,
+}
+
+#[cfg(nix_at_least = "2.33")]
+impl NarWriter {
+    /// Signal the end of the NAR and wait for the import to finish.
+    pub fn finish(mut self) -> Result<()> {
+        drop(self.tx.take());
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => bail!("NAR import thread panicked"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(nix_at_least = "2.33")]
+impl Write for NarWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let tx = self.tx.as_ref().expect("NarWriter used after finish");
+        tx.send(Ok(buf.to_vec())).map_err(|_| {
+            std::io::Error::other("the NAR import stopped early; finish() returns why")
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(nix_at_least = "2.33")]
+impl Drop for NarWriter {
+    fn drop(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(Err(Error::msg(
+                "NarWriter was dropped before the NAR was finished",
+            )));
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
 pub struct Store {
     inner: Arc<StoreRef>,
     /* An error context to reuse. This way we don't have to allocate them for each store operation. */
@@ -367,6 +749,159 @@ impl Store {
         Ok(outputs)
     }
 
+    /// Build a derivation and return its outputs, with options for how to build it.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// This is [`Store::realise`], but with options for this build. Nix reads them from its global
+    /// settings, so they're set while the build runs and put back after:
+    ///
+    /// - Anything else this process does while the build runs sees them too, such as
+    ///   [`Store::realise`] on another thread, or builds during evaluation.
+    /// - Builds with different options wait for each other, so a long build holds up builds
+    ///   with other options until it's done. Builds with the same options don't wait.
+    /// - Daemon stores build on a connection of their own, opened with the options set, so
+    ///   the daemon only applies them to this build.
+    ///
+    /// # Parameters
+    /// - `path`: The store path of the derivation to build
+    /// - `options`: How to build it
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_realise_with_events")]
+    pub fn realise_with_options(
+        &mut self,
+        path: &StorePath,
+        options: &RealiseOptions,
+    ) -> Result<BTreeMap<String, StorePath>> {
+        self.realise_with_progress(path, options, |_| {})
+    }
+
+    /// Build a derivation and return its outputs, reporting progress as it goes.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// This is [`Store::realise_with_options`], but `on_event` is called with each
+    /// [`BuildEvent`] on this thread while the build runs, so callers can show log lines and
+    /// phases as they happen rather than only the result.
+    ///
+    /// # Parameters
+    /// - `path`: The store path of the derivation to build
+    /// - `options`: How to build it
+    /// - `on_event`: Called with each event, in order
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_realise_with_events")]
+    pub fn realise_with_progress<F: FnMut(BuildEvent)>(
+        &mut self,
+        path: &StorePath,
+        options: &RealiseOptions,
+        on_event: F,
+    ) -> Result<BTreeMap<String, StorePath>> {
+        struct Realisation<F> {
+            outputs: BTreeMap<String, StorePath>,
+            on_event: F,
+        }
+
+        unsafe extern "C" fn output_callback<F>(
+            userdata: *mut std::os::raw::c_void,
+            outname: *const c_char,
+            out_path: *const raw::StorePath,
+        ) {
+            let realisation = &mut *(userdata as *mut Realisation<F>);
+            let name = std::ffi::CStr::from_ptr(outname)
+                .to_string_lossy()
+                .into_owned();
+            let path = raw::store_path_clone(out_path);
+            let path = NonNull::new(path).expect("store_path_clone returned null");
+            realisation.outputs.insert(name, StorePath::new_raw(path));
+        }
+
+        unsafe extern "C" fn event_callback<F: FnMut(BuildEvent)>(
+            userdata: *mut std::os::raw::c_void,
+            kind: u32,
+            activity: u64,
+            event_type: u32,
+            text: *const c_char,
+        ) {
+            let realisation = &mut *(userdata as *mut Realisation<F>);
+            let text = if text.is_null() {
+                String::new()
+            } else {
+                std::ffi::CStr::from_ptr(text)
+                    .to_string_lossy()
+                    .into_owned()
+            };
+            let event = match (kind, event_type) {
+                (EVENT_START, _) => BuildEvent::Start {
+                    activity,
+                    activity_type: ActivityType::from_raw(event_type),
+                    text,
+                },
+                (EVENT_STOP, _) => BuildEvent::Stop { activity },
+                (EVENT_RESULT, RESULT_BUILD_LOG_LINE | RESULT_POST_BUILD_LOG_LINE) => {
+                    BuildEvent::LogLine {
+                        activity,
+                        line: text,
+                    }
+                }
+                (EVENT_RESULT, RESULT_SET_PHASE) => BuildEvent::Phase {
+                    activity,
+                    phase: text,
+                },
+                // Progress counters and the like.
+                _ => return,
+            };
+            (realisation.on_event)(event);
+        }
+
+        let options = options.to_raw()?;
+        let mut realisation = Realisation {
+            outputs: BTreeMap::new(),
+            on_event,
+        };
+        unsafe {
+            check_call!(raw::store_realise_with_events(
+                &mut self.context,
+                self.inner.ptr(),
+                path.as_ptr(),
+                &options,
+                &mut realisation as *mut Realisation<F> as *mut std::os::raw::c_void,
+                Some(output_callback::<F>),
+                Some(event_callback::<F>)
+            ))?;
+        }
+        Ok(realisation.outputs)
+    }
+
+    /// Get the log of a derivation's last build, like `nix log`.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// This looks in the store's log directory, then asks substituters that keep logs.
+    ///
+    /// # Parameters
+    /// - `path`: The derivation, or an output of it
+    ///
+    /// # Returns
+    /// The log, or `None` if there isn't one, such as when the derivation was never built here.
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_get_build_log")]
+    pub fn build_log(&mut self, path: &StorePath) -> Result<Option<String>> {
+        let mut r = result_string_init!();
+        let found = unsafe {
+            check_call!(raw::store_get_build_log(
+                &mut self.context,
+                self.inner.ptr(),
+                path.as_ptr(),
+                Some(callback_get_result_string),
+                callback_get_result_string_data(&mut r)
+            ))
+        }?;
+        if !found {
+            return Ok(None);
+        }
+        r.map(Some)
+    }
+
     /// Get the closure of a specific store path.
     ///
     /// **Requires Nix 2.33 or later.**
@@ -410,77 +945,668 @@ impl Store {
         Ok(r)
     }
 
-    pub fn weak_ref(&self) -> StoreWeak {
-        StoreWeak {
-            inner: Arc::downgrade(&self.inner),
+    /// Serialize a store path as a NAR.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// This works for any kind of store, including binary caches and remote stores whose
+    /// paths aren't on the local filesystem. The NAR is passed to `sink` in chunks as Nix
+    /// produces it, so it is never held in memory as a whole.
+    ///
+    /// # Parameters
+    /// - `path`: The store path to serialize. It must be valid in this store.
+    /// - `sink`: Called with each chunk of the NAR, in order.
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_nar_from_path")]
+    pub fn nar_from_path<F: FnMut(&[u8])>(&mut self, path: &StorePath, mut sink: F) -> Result<()> {
+        let userdata = &mut sink as *mut F as *mut std::os::raw::c_void;
+
+        unsafe extern "C" fn callback<F: FnMut(&[u8])>(
+            userdata: *mut std::os::raw::c_void,
+            data: *const c_char,
+            len: usize,
+        ) {
+            let sink = &mut *(userdata as *mut F);
+            if len > 0 {
+                sink(std::slice::from_raw_parts(data as *const u8, len));
+            }
         }
-    }
-}
 
-impl Clone for Store {
-    fn clone(&self) -> Self {
-        Store {
-            inner: self.inner.clone(),
-            context: Context::new(),
+        unsafe {
+            check_call!(raw::store_nar_from_path(
+                &mut self.context,
+                self.inner.ptr(),
+                path.as_ptr(),
+                userdata,
+                Some(callback::<F>)
+            ))?;
         }
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use ctor::ctor;
-    use std::collections::HashMap;
-
-    use super::*;
-
-    #[ctor]
-    fn test_setup() {
-        // Initialize settings for tests
-        let _ = INIT.as_ref();
-
-        // Enable ca-derivations for all tests
-        nix_bindings_util::settings::set("experimental-features", "ca-derivations").ok();
-
-        // Disable build hooks to prevent test recursion
-        nix_bindings_util::settings::set("build-hook", "").ok();
 
-        // Set custom build dir for sandbox
-        if cfg!(target_os = "linux") {
-            nix_bindings_util::settings::set("sandbox-build-dir", "/custom-build-dir-for-test")
-                .ok();
+    /// Check whether a store path is valid in this store, that is, whether it has been added or built.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// For binary caches and remote stores, this asks the store, which may mean a network round trip.
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_is_valid_path")]
+    pub fn is_valid_path(&mut self, path: &StorePath) -> Result<bool> {
+        unsafe {
+            check_call!(raw::store_is_valid_path(
+                &mut self.context,
+                self.inner.ptr(),
+                path.as_ptr()
+            ))
         }
+    }
 
-        std::env::set_var("_NIX_TEST_NO_SANDBOX", "1");
+    /// Work out what realising some paths would do, without doing it, like `nix build --dry-run`.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// This asks substituters which paths they have, so it may mean network round trips,
+    /// but nothing is downloaded or built.
+    ///
+    /// # Parameters
+    /// - `paths`: Derivations, whose outputs would be realised, and other store paths
+    ///
+    /// # Returns
+    /// The derivations that would be built and the paths that would be substituted, with the
+    /// estimated download and NAR sizes. Paths that are already valid are left out.
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_query_missing")]
+    pub fn query_missing(&mut self, paths: &[StorePath]) -> Result<MissingPaths> {
+        let mut missing = MissingPaths::default();
+        let mut download_size = 0;
+        let mut nar_size = 0;
+        let userdata = &mut missing as *mut MissingPaths as *mut std::os::raw::c_void;
+        let paths = paths
+            .iter()
+            .map(|path| unsafe { path.as_ptr() as *const raw::StorePath })
+            .collect::<Vec<_>>();
 
-        // Tests run offline
-        nix_bindings_util::settings::set("substituters", "").ok();
-    }
+        unsafe extern "C" fn callback(
+            userdata: *mut std::os::raw::c_void,
+            kind: u32,
+            path: *const raw::StorePath,
+        ) {
+            let missing = &mut *(userdata as *mut MissingPaths);
+            let path = raw::store_path_clone(path);
+            let path = NonNull::new(path).expect("store_path_clone returned null");
+            let path = StorePath::new_raw(path);
+            match kind {
+                MISSING_WILL_BUILD => missing.will_build.push(path),
+                MISSING_WILL_SUBSTITUTE => missing.will_substitute.push(path),
+                MISSING_UNKNOWN => missing.unknown.push(path),
+                _ => {}
+            }
+        }
 
-    #[test]
-    fn none_works() {
-        let res = Store::open(None, HashMap::new());
-        res.unwrap();
+        unsafe {
+            check_call!(raw::store_query_missing(
+                &mut self.context,
+                self.inner.ptr(),
+                paths.as_ptr(),
+                paths.len(),
+                userdata,
+                Some(callback),
+                &mut download_size,
+                &mut nar_size
+            ))?;
+        }
+        missing.download_size = download_size;
+        missing.nar_size = nar_size;
+        Ok(missing)
     }
 
-    #[test]
-    fn auto_works() {
-        // This is not actually a given.
-        // Maybe whatever is in NIX_REMOTE or nix.conf is really important.
-        let res = Store::open(Some("auto"), HashMap::new());
-        res.unwrap();
+    /// Get what the store knows about a valid store path: its NAR hash and size, references,
+    /// deriver, registration time, signatures and content address.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// # Parameters
+    /// - `path`: The store path to query
+    ///
+    /// # Returns
+    /// A [`PathInfo`], or an error if the path is not valid in this store.
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_query_path_info")]
+    pub fn query_path_info(&mut self, path: &StorePath) -> Result<PathInfo> {
+        unsafe {
+            let info = check_call!(raw::store_query_path_info(
+                &mut self.context,
+                self.inner.ptr(),
+                path.as_ptr()
+            ))?;
+            let info = NonNull::new(info)
+                .ok_or_else(|| Error::msg("store_query_path_info returned null"))?;
+            let result = PathInfo::from_raw(&mut self.context, info);
+            raw::path_info_free(info.as_ptr());
+            result
+        }
     }
 
-    #[test]
-    fn invalid_uri_fails() {
-        let res = Store::open(Some("invalid://uri"), HashMap::new());
-        assert!(res.is_err());
+    /// Add the contents of a single regular file to the store.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// # Parameters
+    /// - `name`: The name part of the store path
+    /// - `data`: The file contents
+    /// - `options`: How to hash the contents, and what they refer to
+    ///
+    /// # Returns
+    /// The content-addressed store path. Adding the same content again returns the same path.
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_add_from_source")]
+    pub fn add_to_store_bytes(
+        &mut self,
+        name: &str,
+        data: &[u8],
+        options: &AddToStoreOptions,
+    ) -> Result<StorePath> {
+        self.add_to_store_reader(name, data, options)
     }
 
-    #[test]
-    fn get_uri() {
-        let mut store = Store::open(None, HashMap::new()).unwrap();
-        let uri = store.get_uri().unwrap();
-        assert!(!uri.is_empty());
+    /// Add the contents of a single regular file to the store, reading them as they are hashed.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// Unlike [`Store::add_to_store_bytes`], the contents don't have to fit in memory.
+    /// If `reader` fails, nothing is added and its error is returned.
+    ///
+    /// # Parameters
+    /// - `name`: The name part of the store path
+    /// - `reader`: Reads the file contents until EOF
+    /// - `options`: How to hash the contents, and what they refer to
+    ///
+    /// # Returns
+    /// The content-addressed store path.
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_add_from_source")]
+    pub fn add_to_store_reader<R: Read>(
+        &mut self,
+        name: &str,
+        reader: R,
+        options: &AddToStoreOptions,
+    ) -> Result<StorePath> {
+        let name = CString::new(name)?;
+        let expected_hash = options
+            .expected_hash
+            .as_deref()
+            .map(CString::new)
+            .transpose()?;
+        let references = options
+            .references
+            .iter()
+            .map(|path| unsafe { path.as_ptr() as *const raw::StorePath })
+            .collect::<Vec<_>>();
+        let mut source = Source {
+            reader,
+            error: None,
+        };
+
+        let path = unsafe {
+            check_call!(raw::store_add_from_source(
+                &mut self.context,
+                self.inner.ptr(),
+                name.as_ptr(),
+                options.method.as_c_str().as_ptr(),
+                options.hash_algo.as_c_str().as_ptr(),
+                references.as_ptr(),
+                references.len(),
+                expected_hash
+                    .as_ref()
+                    .map_or(std::ptr::null(), |hash| hash.as_ptr()),
+                options.repair,
+                &mut source as *mut Source<R> as *mut std::os::raw::c_void,
+                Some(callback_read::<R>)
+            ))
+        };
+        // Nix only sees that the read failed, so report why.
+        if let Some(err) = source.error {
+            return Err(err.into());
+        }
+        let path =
+            NonNull::new(path?).ok_or_else(|| Error::msg("add_from_source returned null"))?;
+        Ok(unsafe { StorePath::new_raw(path) })
+    }
+
+    /// Add a file, symlink or directory from the filesystem to the store.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// A directory or symlink must be added with [`ContentAddressMethod::Recursive`].
+    ///
+    /// # Parameters
+    /// - `name`: The name part of the store path
+    /// - `path`: The path to add. Symlinks within it are copied as symlinks, not followed.
+    /// - `options`: How to hash the contents, and what they refer to
+    ///
+    /// # Returns
+    /// The content-addressed store path.
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_add_path")]
+    pub fn add_to_store_path(
+        &mut self,
+        name: &str,
+        path: &Path,
+        options: &AddToStoreOptions,
+    ) -> Result<StorePath> {
+        let name = CString::new(name)?;
+        let fs_path = CString::new(path.as_os_str().as_bytes())?;
+        let expected_hash = options
+            .expected_hash
+            .as_deref()
+            .map(CString::new)
+            .transpose()?;
+        let references = options
+            .references
+            .iter()
+            .map(|path| unsafe { path.as_ptr() as *const raw::StorePath })
+            .collect::<Vec<_>>();
+
+        unsafe {
+            let path = check_call!(raw::store_add_path(
+                &mut self.context,
+                self.inner.ptr(),
+                name.as_ptr(),
+                fs_path.as_ptr(),
+                options.method.as_c_str().as_ptr(),
+                options.hash_algo.as_c_str().as_ptr(),
+                references.as_ptr(),
+                references.len(),
+                expected_hash
+                    .as_ref()
+                    .map_or(std::ptr::null(), |hash| hash.as_ptr()),
+                options.repair
+            ))?;
+            let path = NonNull::new(path).ok_or_else(|| Error::msg("add_path returned null"))?;
+            Ok(StorePath::new_raw(path))
+        }
+    }
+
+    /// Serialize a store path as a NAR that can be read as it is produced.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// This is [`Store::nar_from_path`] on its own thread, so the NAR never has to be held in memory
+    /// as a whole. Errors, such as the path not being valid, come out of the reader.
+    ///
+    /// # Parameters
+    /// - `path`: The store path to serialize
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_nar_from_path")]
+    pub fn nar_reader(&self, path: &StorePath) -> NarReader {
+        let (tx, rx) = mpsc::sync_channel(NAR_CHUNKS);
+        let inner = self.inner.clone();
+        let path = Unshared(path.clone());
+        std::thread::spawn(move || {
+            let path = path.into_inner();
+            let mut store = Store {
+                inner,
+                context: Context::new(),
+            };
+            let result = store.nar_from_path(&path, |chunk| {
+                // If the reader is gone, there's nowhere for the rest to go.
+                let _ = tx.send(Ok(chunk.to_vec()));
+            });
+            if let Err(err) = result {
+                let _ = tx.send(Err(err));
+            }
+        });
+        NarReader {
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Import a NAR into the store as the given path.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// This is the other half of [`Store::nar_reader`]: together with [`Store::query_path_info`] on
+    /// the source store, it copies a path between stores. The NAR is checked against
+    /// `info.nar_hash` and `info.nar_size` as it is read, and the path is only registered if they match.
+    ///
+    /// # Parameters
+    /// - `path`: The store path to import as
+    /// - `info`: The path's metadata. Its references must already be valid in this store.
+    /// - `reader`: Reads the NAR until EOF
+    /// - `check_sigs`: Whether to require a trusted signature on input-addressed paths, as for substitution.
+    ///   Without one, the store must trust us, e.g. a local store we can write to directly.
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_add_nar")]
+    pub fn import_nar<R: Read>(
+        &mut self,
+        path: &StorePath,
+        info: &PathInfo,
+        reader: R,
+        check_sigs: bool,
+    ) -> Result<()> {
+        self.add_nar(path, info, reader, check_sigs, false)
+    }
+
+    #[cfg(nix_at_least = "2.33")]
+    fn add_nar<R: Read>(
+        &mut self,
+        path: &StorePath,
+        info: &PathInfo,
+        reader: R,
+        check_sigs: bool,
+        repair: bool,
+    ) -> Result<()> {
+        let nar_hash = CString::new(info.nar_hash.as_str())?;
+        let references = info
+            .references
+            .iter()
+            .map(|path| unsafe { path.as_ptr() as *const raw::StorePath })
+            .collect::<Vec<_>>();
+        let signatures = info
+            .signatures
+            .iter()
+            .map(|sig| CString::new(sig.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let signature_ptrs = signatures
+            .iter()
+            .map(|sig| sig.as_ptr())
+            .collect::<Vec<_>>();
+        let ca = info.ca.as_deref().map(CString::new).transpose()?;
+        let mut source = Source {
+            reader,
+            error: None,
+        };
+
+        let result = unsafe {
+            check_call!(raw::store_add_nar(
+                &mut self.context,
+                self.inner.ptr(),
+                path.as_ptr(),
+                nar_hash.as_ptr(),
+                info.nar_size,
+                references.as_ptr(),
+                references.len(),
+                info.deriver
+                    .as_ref()
+                    .map_or(std::ptr::null(), |deriver| deriver.as_ptr()
+                        as *const raw::StorePath),
+                signature_ptrs.as_ptr(),
+                signature_ptrs.len(),
+                ca.as_ref().map_or(std::ptr::null(), |ca| ca.as_ptr()),
+                check_sigs,
+                repair,
+                &mut source as *mut Source<R> as *mut std::os::raw::c_void,
+                Some(callback_read::<R>)
+            ))
+        };
+        if let Some(err) = source.error {
+            return Err(err.into());
+        }
+        result?;
+        Ok(())
+    }
+
+    /// Import a NAR into the store as the given path, by writing it.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// This is [`Store::import_nar`] on its own thread, for when the NAR is pushed rather than pulled,
+    /// e.g. by an HTTP upload. Call [`NarWriter::finish`] once it's all written.
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_add_nar")]
+    pub fn nar_writer(&self, path: &StorePath, info: &PathInfo, check_sigs: bool) -> NarWriter {
+        let (tx, rx) = mpsc::sync_channel(NAR_CHUNKS);
+        let inner = self.inner.clone();
+        let args = Unshared((path.clone(), info.clone()));
+        let thread = std::thread::spawn(move || {
+            let (path, info) = args.into_inner();
+            let mut store = Store {
+                inner,
+                context: Context::new(),
+            };
+            let reader = NarReader {
+                rx,
+                chunk: Vec::new(),
+                pos: 0,
+            };
+            store.import_nar(&path, &info, reader, check_sigs)
+        });
+        NarWriter {
+            tx: Some(tx),
+            thread: Some(thread),
+        }
+    }
+
+    /// Copy store paths and everything they refer to into another store, like `nix copy --to`.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// The closure is computed with [`Store::get_fs_closure`], and paths are copied as NARs,
+    /// references first, so the destination never has a path without its references.
+    ///
+    /// # Parameters
+    /// - `dst`: The store to copy into
+    /// - `paths`: The store paths whose closures to copy. They must be valid in this store.
+    /// - `options`: See [`CopyClosureOptions`]
+    ///
+    /// # Returns
+    /// The paths that were copied from this store, in the order they were copied.
+    /// Paths the destination already had, or substituted, are left out.
+    #[cfg(nix_at_least = "2.33")]
+    pub fn copy_closure(
+        &mut self,
+        dst: &mut Store,
+        paths: &[StorePath],
+        options: &CopyClosureOptions,
+    ) -> Result<Vec<StorePath>> {
+        // StorePath isn't Ord, so paths are keyed by their hash, which is unique in a store.
+        let mut closure = BTreeMap::new();
+        for path in paths {
+            for path in self.get_fs_closure(path, false, false, false)? {
+                closure.insert(path.hash()?, path);
+            }
+        }
+
+        let mut missing = BTreeMap::new();
+        for (hash, path) in closure {
+            if !options.repair && dst.is_valid_path(&path)? {
+                continue;
+            }
+            // Realising a derivation would build it, rather than substitute the .drv itself.
+            if options.substitute
+                && !options.repair
+                && !path.name()?.ends_with(".drv")
+                && dst.realise(&path).is_ok()
+                && dst.is_valid_path(&path)?
+            {
+                continue;
+            }
+            let info = self.query_path_info(&path)?;
+            missing.insert(hash, (path, info));
+        }
+
+        // Order the paths so each one comes after its references.
+        let mut order = Vec::with_capacity(missing.len());
+        let mut visited = std::collections::BTreeSet::new();
+        let mut stack: Vec<(_, bool)> = missing.keys().rev().map(|hash| (*hash, false)).collect();
+        while let Some((hash, expanded)) = stack.pop() {
+            if expanded {
+                order.push(hash);
+                continue;
+            }
+            if !visited.insert(hash) {
+                continue;
+            }
+            stack.push((hash, true));
+            for reference in &missing[&hash].1.references {
+                let reference = reference.hash()?;
+                if missing.contains_key(&reference) && !visited.contains(&reference) {
+                    stack.push((reference, false));
+                }
+            }
+        }
+
+        let mut copied = Vec::with_capacity(order.len());
+        for hash in order {
+            let (path, info) = missing.remove(&hash).expect("path was ordered twice");
+            dst.add_nar(
+                &path,
+                &info,
+                self.nar_reader(&path),
+                options.check_sigs,
+                options.repair,
+            )?;
+            copied.push(path);
+        }
+        Ok(copied)
+    }
+
+    /// Protect a store path from garbage collection until this process exits.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// Temporary roots can't be removed individually, so use them for paths that are in use
+    /// right now, like one that's being read. For a root that can be released, see [`Store::add_perm_root`].
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_add_temp_root")]
+    pub fn add_temp_root(&mut self, path: &StorePath) -> Result<()> {
+        unsafe {
+            check_call!(raw::store_add_temp_root(
+                &mut self.context,
+                self.inner.ptr(),
+                path.as_ptr()
+            ))
+        }?;
+        Ok(())
+    }
+
+    /// Register a symlink as an indirect garbage collector root.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// Whatever store path the symlink points to is kept alive for as long as the symlink does.
+    /// Deleting or retargeting the symlink releases the root; the registration itself is cleaned up by the next GC.
+    ///
+    /// # Parameters
+    /// - `link`: The symlink, which may be anywhere outside the store. It must be absolute.
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_add_indirect_root")]
+    pub fn add_indirect_root(&mut self, link: &Path) -> Result<()> {
+        let link = CString::new(link.as_os_str().as_bytes())?;
+        unsafe {
+            check_call!(raw::store_add_indirect_root(
+                &mut self.context,
+                self.inner.ptr(),
+                link.as_ptr()
+            ))
+        }?;
+        Ok(())
+    }
+
+    /// Create a symlink to a store path and register it as a garbage collector root, like `nix-store --add-root`.
+    ///
+    /// **Requires Nix 2.33 or later.**
+    ///
+    /// A symlink under the store's `gcroots` directory is a root by itself; anywhere else, it is
+    /// registered as an indirect root (see [`Store::add_indirect_root`]). Any existing symlink at `link` is replaced.
+    ///
+    /// # Parameters
+    /// - `path`: The store path to keep alive. It must be valid.
+    /// - `link`: Where to create the symlink
+    ///
+    /// # Returns
+    /// The absolute path of the symlink.
+    #[cfg(nix_at_least = "2.33")]
+    #[doc(alias = "nix_store_add_perm_root")]
+    pub fn add_perm_root(&mut self, path: &StorePath, link: &Path) -> Result<PathBuf> {
+        let link = CString::new(link.as_os_str().as_bytes())?;
+        let mut r = result_string_init!();
+        unsafe {
+            check_call!(raw::store_add_perm_root(
+                &mut self.context,
+                self.inner.ptr(),
+                path.as_ptr(),
+                link.as_ptr(),
+                Some(callback_get_result_string),
+                callback_get_result_string_data(&mut r)
+            ))
+        }?;
+        r.map(PathBuf::from)
+    }
+
+    pub fn weak_ref(&self) -> StoreWeak {
+        StoreWeak {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+}
+
+impl Clone for Store {
+    fn clone(&self) -> Self {
+        Store {
+            inner: self.inner.clone(),
+            context: Context::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ctor::ctor;
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[ctor]
+    fn test_setup() {
+        // Initialize settings for tests
+        let _ = INIT.as_ref();
+
+        // Enable ca-derivations for all tests
+        nix_bindings_util::settings::set("experimental-features", "ca-derivations").ok();
+
+        // Disable build hooks to prevent test recursion
+        nix_bindings_util::settings::set("build-hook", "").ok();
+
+        // Set custom build dir for sandbox
+        if cfg!(target_os = "linux") {
+            nix_bindings_util::settings::set("sandbox-build-dir", "/custom-build-dir-for-test")
+                .ok();
+        }
+
+        std::env::set_var("_NIX_TEST_NO_SANDBOX", "1");
+
+        // Tests run offline
+        nix_bindings_util::settings::set("substituters", "").ok();
+    }
+
+    #[test]
+    fn none_works() {
+        let res = Store::open(None, HashMap::new());
+        res.unwrap();
+    }
+
+    #[test]
+    fn auto_works() {
+        // This is not actually a given.
+        // Maybe whatever is in NIX_REMOTE or nix.conf is really important.
+        let res = Store::open(Some("auto"), HashMap::new());
+        res.unwrap();
+    }
+
+    #[test]
+    fn invalid_uri_fails() {
+        let res = Store::open(Some("invalid://uri"), HashMap::new());
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn get_uri() {
+        let mut store = Store::open(None, HashMap::new()).unwrap();
+        let uri = store.get_uri().unwrap();
+        assert!(!uri.is_empty());
         // must be ascii
         assert!(uri.is_ascii());
         // usually something like "daemon", but that's not something we can check here.
@@ -680,6 +1806,777 @@ mod tests {
         drop(temp_dir);
     }
 
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn realise_with_progress() {
+        let (mut store, temp_dir) = create_temp_store();
+        let mut drv_json = create_test_derivation_json();
+        drv_json["args"] =
+            serde_json::json!(["-c", "echo hello from the builder; echo $name foo > $out"]);
+        let drv = store.derivation_from_json(&drv_json.to_string()).unwrap();
+        let drv_path = store.add_derivation(&drv).unwrap();
+
+        let mut events = Vec::new();
+        let outputs = store
+            .realise_with_progress(&drv_path, &RealiseOptions::default(), |event| {
+                events.push(event)
+            })
+            .unwrap();
+        assert!(outputs.contains_key("out"));
+
+        let build = events
+            .iter()
+            .find_map(|event| match event {
+                BuildEvent::Start {
+                    activity,
+                    activity_type: ActivityType::Build,
+                    ..
+                } => Some(*activity),
+                _ => None,
+            })
+            .expect("no build activity");
+        assert!(events.contains(&BuildEvent::LogLine {
+            activity: build,
+            line: "hello from the builder".to_string(),
+        }));
+        assert!(events.contains(&BuildEvent::Stop { activity: build }));
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn realise_with_progress_fails() {
+        let (mut store, temp_dir) = create_temp_store();
+        let mut drv_json = create_test_derivation_json();
+        drv_json["args"] = serde_json::json!(["-c", "echo about to fail; exit 1"]);
+        let drv = store.derivation_from_json(&drv_json.to_string()).unwrap();
+        let drv_path = store.add_derivation(&drv).unwrap();
+
+        let mut lines = Vec::new();
+        let result = store.realise_with_progress(&drv_path, &RealiseOptions::default(), |event| {
+            if let BuildEvent::LogLine { line, .. } = event {
+                lines.push(line);
+            }
+        });
+        assert!(result.is_err());
+        assert_eq!(lines, ["about to fail"]);
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn realise_with_options_check() {
+        let (mut store, temp_dir) = create_temp_store();
+        let drv = store
+            .derivation_from_json(&create_test_derivation_json().to_string())
+            .unwrap();
+        let drv_path = store.add_derivation(&drv).unwrap();
+        let built = store.realise(&drv_path).unwrap();
+
+        let options = RealiseOptions {
+            check: true,
+            ..RealiseOptions::default()
+        };
+        let checked = store.realise_with_options(&drv_path, &options).unwrap();
+        assert_eq!(
+            store.real_path(&checked["out"]).unwrap(),
+            store.real_path(&built["out"]).unwrap()
+        );
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn realise_with_options_timeout() {
+        let (mut store, temp_dir) = create_temp_store();
+        let mut drv_json = create_test_derivation_json();
+        drv_json["args"] = serde_json::json!(["-c", "while :; do :; done"]);
+        let drv = store.derivation_from_json(&drv_json.to_string()).unwrap();
+        let drv_path = store.add_derivation(&drv).unwrap();
+
+        let options = RealiseOptions {
+            timeout: Some(Duration::from_secs(1)),
+            ..RealiseOptions::default()
+        };
+        assert!(store.realise_with_options(&drv_path, &options).is_err());
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn realise_with_options_repair_and_check() {
+        let (mut store, temp_dir) = create_temp_store();
+        let drv = store
+            .derivation_from_json(&create_test_derivation_json().to_string())
+            .unwrap();
+        let drv_path = store.add_derivation(&drv).unwrap();
+
+        let options = RealiseOptions {
+            repair: true,
+            check: true,
+            ..RealiseOptions::default()
+        };
+        assert!(store.realise_with_options(&drv_path, &options).is_err());
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn build_log() {
+        let (mut store, temp_dir) = create_temp_store();
+        let mut drv_json = create_test_derivation_json();
+        drv_json["args"] = serde_json::json!(["-c", "echo about to fail; exit 1"]);
+        let drv = store.derivation_from_json(&drv_json.to_string()).unwrap();
+        let drv_path = store.add_derivation(&drv).unwrap();
+        assert!(store.build_log(&drv_path).unwrap().is_none());
+
+        assert!(store.realise(&drv_path).is_err());
+        let log = store.build_log(&drv_path).unwrap().expect("no build log");
+        assert!(log.contains("about to fail"));
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn nar_from_path() {
+        let (mut store, temp_dir) = create_temp_store();
+        let drv_json = create_test_derivation_json();
+        let drv = store.derivation_from_json(&drv_json.to_string()).unwrap();
+        let drv_path = store.add_derivation(&drv).unwrap();
+        let outputs = store.realise(&drv_path).unwrap();
+
+        let mut nar = Vec::new();
+        store
+            .nar_from_path(&outputs["out"], |chunk| nar.extend_from_slice(chunk))
+            .unwrap();
+
+        // A NAR string is its length as a little-endian u64, then the bytes, padded to 8.
+        assert_eq!(&nar[..8], &13u64.to_le_bytes());
+        assert_eq!(&nar[8..21], b"nix-archive-1");
+        let contents = b"myname foo\n";
+        assert!(nar.windows(contents.len()).any(|w| w == contents));
+        assert_eq!(nar.len() % 8, 0);
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn nar_from_path_invalid() {
+        let (mut store, temp_dir) = create_temp_store();
+        let store_dir = store.get_storedir().unwrap();
+        let path = store
+            .parse_store_path(&format!(
+                "{store_dir}/rdd4pnr4x9rqc9wgbibhngv217w2xvxl-bash-interactive-5.2p26"
+            ))
+            .unwrap();
+        let mut called = false;
+        assert!(store.nar_from_path(&path, |_| called = true).is_err());
+        assert!(!called);
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn add_to_store_bytes() {
+        let (mut store, temp_dir) = create_temp_store();
+        let options = AddToStoreOptions::default();
+        let path = store
+            .add_to_store_bytes("hello.txt", b"hello\n", &options)
+            .unwrap();
+        assert_eq!(path.name().unwrap(), "hello.txt");
+        let real_path = store.real_path(&path).unwrap();
+        assert_eq!(std::fs::read(real_path).unwrap(), b"hello\n");
+
+        // Content addressing: the same content gets the same path, and other methods don't.
+        let again = store
+            .add_to_store_bytes("hello.txt", b"hello\n", &options)
+            .unwrap();
+        assert_eq!(again.hash().unwrap(), path.hash().unwrap());
+        let flat = AddToStoreOptions {
+            method: ContentAddressMethod::Flat,
+            ..AddToStoreOptions::default()
+        };
+        let flat_path = store
+            .add_to_store_bytes("hello.txt", b"hello\n", &flat)
+            .unwrap();
+        assert_ne!(flat_path.hash().unwrap(), path.hash().unwrap());
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn add_to_store_bytes_expected_hash() {
+        let (mut store, temp_dir) = create_temp_store();
+        let mut options = AddToStoreOptions {
+            method: ContentAddressMethod::Flat,
+            expected_hash: Some("sha256-WJG1tSLV3whtD/CxEPvZ0hu0/HFjrzTQgoai6Eb2vgM=".to_string()),
+            ..AddToStoreOptions::default()
+        };
+        assert!(store
+            .add_to_store_bytes("hello.txt", b"goodbye\n", &options)
+            .is_err());
+        let added = std::fs::read_dir(temp_dir.path().join("store"))
+            .map(|entries| {
+                entries
+                    .filter(|entry| {
+                        entry.as_ref().is_ok_and(|entry| {
+                            entry.file_name().to_string_lossy().ends_with("-hello.txt")
+                        })
+                    })
+                    .count()
+            })
+            .unwrap_or(0);
+        assert_eq!(added, 0);
+
+        store
+            .add_to_store_bytes("hello.txt", b"hello\n", &options)
+            .unwrap();
+
+        options.hash_algo = HashAlgorithm::Sha512;
+        assert!(store
+            .add_to_store_bytes("hello.txt", b"hello\n", &options)
+            .is_err());
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn add_to_store_bytes_references() {
+        let (mut store, temp_dir) = create_temp_store();
+        let dep = store
+            .add_to_store_bytes("dep", b"dep", &AddToStoreOptions::default())
+            .unwrap();
+        let without = store
+            .add_to_store_bytes("top", b"top", &AddToStoreOptions::default())
+            .unwrap();
+        let with = store
+            .add_to_store_bytes(
+                "top",
+                b"top",
+                &AddToStoreOptions {
+                    references: vec![dep.clone()],
+                    ..AddToStoreOptions::default()
+                },
+            )
+            .unwrap();
+        assert_ne!(with.hash().unwrap(), without.hash().unwrap());
+
+        let closure = store.get_fs_closure(&with, false, false, false).unwrap();
+        assert!(closure
+            .iter()
+            .any(|p| p.hash().unwrap() == dep.hash().unwrap()));
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn add_to_store_reader_error() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("broken reader"))
+            }
+        }
+
+        let (mut store, temp_dir) = create_temp_store();
+        let err = store
+            .add_to_store_reader("broken", Broken, &AddToStoreOptions::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("broken reader"));
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn add_to_store_path_directory() {
+        let (mut store, temp_dir) = create_temp_store();
+        let src = tempfile::tempdir().unwrap();
+        std::fs::create_dir(src.path().join("sub")).unwrap();
+        std::fs::write(src.path().join("sub/file"), "contents").unwrap();
+        std::os::unix::fs::symlink("sub/file", src.path().join("link")).unwrap();
+
+        let path = store
+            .add_to_store_path("src", src.path(), &AddToStoreOptions::default())
+            .unwrap();
+        let real_path = std::path::PathBuf::from(store.real_path(&path).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(real_path.join("sub/file")).unwrap(),
+            "contents"
+        );
+        assert_eq!(
+            std::fs::read_link(real_path.join("link")).unwrap(),
+            std::path::Path::new("sub/file")
+        );
+
+        // Directories can't be hashed flat.
+        let flat = AddToStoreOptions {
+            method: ContentAddressMethod::Flat,
+            ..AddToStoreOptions::default()
+        };
+        assert!(store.add_to_store_path("src", src.path(), &flat).is_err());
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn is_valid_path() {
+        let (mut store, temp_dir) = create_temp_store();
+        let path = store
+            .add_to_store_bytes("valid", b"valid", &AddToStoreOptions::default())
+            .unwrap();
+        assert!(store.is_valid_path(&path).unwrap());
+
+        let store_dir = store.get_storedir().unwrap();
+        let missing = store
+            .parse_store_path(&format!(
+                "{store_dir}/rdd4pnr4x9rqc9wgbibhngv217w2xvxl-bash-interactive-5.2p26"
+            ))
+            .unwrap();
+        assert!(!store.is_valid_path(&missing).unwrap());
+        assert!(store.query_path_info(&missing).is_err());
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn query_missing() {
+        let (mut store, temp_dir) = create_temp_store();
+        let drv = store
+            .derivation_from_json(&create_test_derivation_json().to_string())
+            .unwrap();
+        let drv_path = store.add_derivation(&drv).unwrap();
+
+        let missing = store.query_missing(&[drv_path.clone()]).unwrap();
+        let will_build = missing
+            .will_build
+            .iter()
+            .map(|path| path.hash().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(will_build, vec![drv_path.hash().unwrap()]);
+        assert!(missing.will_substitute.is_empty());
+        assert_eq!(missing.download_size, 0);
+
+        store.realise(&drv_path).unwrap();
+        let missing = store.query_missing(&[drv_path]).unwrap();
+        assert!(missing.will_build.is_empty());
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn query_missing_unknown() {
+        let (mut store, temp_dir) = create_temp_store();
+        let valid = store
+            .add_to_store_bytes("valid", b"valid", &AddToStoreOptions::default())
+            .unwrap();
+        let store_dir = store.get_storedir().unwrap();
+        let unknown = store
+            .parse_store_path(&format!(
+                "{store_dir}/rdd4pnr4x9rqc9wgbibhngv217w2xvxl-bash-interactive-5.2p26"
+            ))
+            .unwrap();
+
+        let missing = store.query_missing(&[valid, unknown]).unwrap();
+        assert!(missing.will_build.is_empty());
+        assert!(missing.will_substitute.is_empty());
+        assert_eq!(missing.unknown.len(), 1);
+        assert_eq!(
+            missing.unknown[0].name().unwrap(),
+            "bash-interactive-5.2p26"
+        );
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn query_path_info_added() {
+        let (mut store, temp_dir) = create_temp_store();
+        let dep = store
+            .add_to_store_bytes("dep", b"dep", &AddToStoreOptions::default())
+            .unwrap();
+        let path = store
+            .add_to_store_bytes(
+                "top",
+                b"top",
+                &AddToStoreOptions {
+                    references: vec![dep.clone()],
+                    ..AddToStoreOptions::default()
+                },
+            )
+            .unwrap();
+
+        let info = store.query_path_info(&path).unwrap();
+        assert!(info.nar_hash.starts_with("sha256-"));
+        assert!(info.nar_size > 0);
+        assert_eq!(info.nar_size % 8, 0);
+        assert_eq!(info.references.len(), 1);
+        assert_eq!(info.references[0].hash().unwrap(), dep.hash().unwrap());
+        assert!(info.deriver.is_none());
+        assert!(info.registration_time.is_some());
+        assert!(info.signatures.is_empty());
+        assert!(info.ca.unwrap().starts_with("fixed:r:sha256:"));
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn query_path_info_built() {
+        let (mut store, temp_dir) = create_temp_store();
+        let drv_json = create_test_derivation_json();
+        let drv = store.derivation_from_json(&drv_json.to_string()).unwrap();
+        let drv_path = store.add_derivation(&drv).unwrap();
+        let outputs = store.realise(&drv_path).unwrap();
+
+        let info = store.query_path_info(&outputs["out"]).unwrap();
+        let mut nar = Vec::new();
+        store
+            .nar_from_path(&outputs["out"], |chunk| nar.extend_from_slice(chunk))
+            .unwrap();
+        assert_eq!(info.nar_size, nar.len() as u64);
+        assert_eq!(
+            info.deriver.unwrap().name().unwrap(),
+            drv_path.name().unwrap()
+        );
+        assert!(info.references.is_empty());
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn nar_reader() {
+        let (mut store, temp_dir) = create_temp_store();
+        let path = store
+            .add_to_store_bytes("hello.txt", b"hello\n", &AddToStoreOptions::default())
+            .unwrap();
+
+        let mut expected = Vec::new();
+        store
+            .nar_from_path(&path, |chunk| expected.extend_from_slice(chunk))
+            .unwrap();
+        let mut nar = Vec::new();
+        store.nar_reader(&path).read_to_end(&mut nar).unwrap();
+        assert_eq!(nar, expected);
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn nar_reader_invalid() {
+        let (mut store, temp_dir) = create_temp_store();
+        let store_dir = store.get_storedir().unwrap();
+        let path = store
+            .parse_store_path(&format!(
+                "{store_dir}/rdd4pnr4x9rqc9wgbibhngv217w2xvxl-bash-interactive-5.2p26"
+            ))
+            .unwrap();
+        let mut nar = Vec::new();
+        assert!(store.nar_reader(&path).read_to_end(&mut nar).is_err());
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    /// Adds a path to one store and returns it with its info, which is made input-addressed
+    /// so that it can be imported into another store with a different store directory.
+    #[cfg(nix_at_least = "2.33")]
+    fn nar_import_source(store: &mut Store) -> (StorePath, PathInfo) {
+        let path = store
+            .add_to_store_bytes("hello.txt", b"hello\n", &AddToStoreOptions::default())
+            .unwrap();
+        let mut info = store.query_path_info(&path).unwrap();
+        info.ca = None;
+        (path, info)
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn import_nar_round_trip() {
+        let (mut src, src_dir) = create_temp_store();
+        let (mut dst, dst_dir) = create_temp_store();
+        let (path, info) = nar_import_source(&mut src);
+
+        dst.import_nar(&path, &info, src.nar_reader(&path), false)
+            .unwrap();
+        assert!(dst.is_valid_path(&path).unwrap());
+        let dst_info = dst.query_path_info(&path).unwrap();
+        assert_eq!(dst_info.nar_hash, info.nar_hash);
+        assert_eq!(dst_info.nar_size, info.nar_size);
+        let real_path = dst.real_path(&path).unwrap();
+        assert_eq!(std::fs::read(real_path).unwrap(), b"hello\n");
+
+        drop(src);
+        drop(dst);
+        drop(src_dir);
+        drop(dst_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn import_nar_bad_hash() {
+        let (mut src, src_dir) = create_temp_store();
+        let (mut dst, dst_dir) = create_temp_store();
+        let (path, mut info) = nar_import_source(&mut src);
+        info.nar_hash = "sha256-WJG1tSLV3whtD/CxEPvZ0hu0/HFjrzTQgoai6Eb2vgM=".to_string();
+
+        assert!(dst
+            .import_nar(&path, &info, src.nar_reader(&path), false)
+            .is_err());
+        assert!(!dst.is_valid_path(&path).unwrap());
+
+        drop(src);
+        drop(dst);
+        drop(src_dir);
+        drop(dst_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn nar_writer() {
+        let (mut src, src_dir) = create_temp_store();
+        let (mut dst, dst_dir) = create_temp_store();
+        let (path, info) = nar_import_source(&mut src);
+
+        let mut writer = dst.nar_writer(&path, &info, false);
+        std::io::copy(&mut src.nar_reader(&path), &mut writer).unwrap();
+        writer.finish().unwrap();
+        assert!(dst.is_valid_path(&path).unwrap());
+
+        drop(src);
+        drop(dst);
+        drop(src_dir);
+        drop(dst_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn nar_writer_dropped() {
+        let (mut src, src_dir) = create_temp_store();
+        let (mut dst, dst_dir) = create_temp_store();
+        let (path, info) = nar_import_source(&mut src);
+
+        let mut nar = Vec::new();
+        src.nar_reader(&path).read_to_end(&mut nar).unwrap();
+        let mut writer = dst.nar_writer(&path, &info, false);
+        writer.write_all(&nar[..nar.len() / 2]).unwrap();
+        drop(writer);
+        assert!(!dst.is_valid_path(&path).unwrap());
+
+        // Finishing a partial NAR reports why the import failed.
+        let mut writer = dst.nar_writer(&path, &info, false);
+        writer.write_all(&nar[..nar.len() / 2]).unwrap();
+        assert!(writer.finish().is_err());
+        assert!(!dst.is_valid_path(&path).unwrap());
+
+        drop(src);
+        drop(dst);
+        drop(src_dir);
+        drop(dst_dir);
+    }
+
+    /// Adds a path that refers to another one, returning the referrer and its reference.
+    #[cfg(nix_at_least = "2.33")]
+    fn copy_closure_source(store: &mut Store) -> (StorePath, StorePath) {
+        let reference = store
+            .add_to_store_bytes("reference", b"reference", &AddToStoreOptions::default())
+            .unwrap();
+        let options = AddToStoreOptions {
+            references: vec![reference.clone()],
+            ..AddToStoreOptions::default()
+        };
+        let referrer = store
+            .add_to_store_bytes("referrer", b"referrer", &options)
+            .unwrap();
+        (referrer, reference)
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn copy_closure() {
+        let (mut src, src_dir) = create_temp_store();
+        let (mut dst, dst_dir) = create_temp_store();
+        let (referrer, reference) = copy_closure_source(&mut src);
+
+        let copied = src
+            .copy_closure(
+                &mut dst,
+                &[referrer.clone()],
+                &CopyClosureOptions::default(),
+            )
+            .unwrap();
+        let names = copied
+            .iter()
+            .map(|path| path.name().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["reference", "referrer"]);
+        assert!(dst.is_valid_path(&reference).unwrap());
+        assert!(dst.is_valid_path(&referrer).unwrap());
+        assert_eq!(
+            dst.query_path_info(&referrer).unwrap().nar_hash,
+            src.query_path_info(&referrer).unwrap().nar_hash
+        );
+
+        // Everything is there now.
+        let copied = src
+            .copy_closure(
+                &mut dst,
+                &[referrer.clone()],
+                &CopyClosureOptions::default(),
+            )
+            .unwrap();
+        assert!(copied.is_empty());
+
+        drop(src);
+        drop(dst);
+        drop(src_dir);
+        drop(dst_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn copy_closure_repair() {
+        let (mut src, src_dir) = create_temp_store();
+        let (mut dst, dst_dir) = create_temp_store();
+        let (referrer, _) = copy_closure_source(&mut src);
+
+        src.copy_closure(
+            &mut dst,
+            &[referrer.clone()],
+            &CopyClosureOptions::default(),
+        )
+        .unwrap();
+        let options = CopyClosureOptions {
+            repair: true,
+            ..CopyClosureOptions::default()
+        };
+        let copied = src.copy_closure(&mut dst, &[referrer], &options).unwrap();
+        assert_eq!(copied.len(), 2);
+
+        drop(src);
+        drop(dst);
+        drop(src_dir);
+        drop(dst_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn copy_closure_invalid() {
+        let (mut src, src_dir) = create_temp_store();
+        let (mut dst, dst_dir) = create_temp_store();
+        let (path, _) = copy_closure_source(&mut dst);
+
+        // The path only exists in the destination.
+        assert!(src
+            .copy_closure(&mut dst, &[path], &CopyClosureOptions::default())
+            .is_err());
+
+        drop(src);
+        drop(dst);
+        drop(src_dir);
+        drop(dst_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn add_temp_root() {
+        let (mut store, temp_dir) = create_temp_store();
+        let path = store
+            .add_to_store_bytes("rooted", b"rooted", &AddToStoreOptions::default())
+            .unwrap();
+        store.add_temp_root(&path).unwrap();
+        // Adding the same root again is harmless.
+        store.add_temp_root(&path).unwrap();
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    /// Returns the targets of the indirect roots registered in a temporary store.
+    #[cfg(nix_at_least = "2.33")]
+    fn auto_roots(temp_dir: &tempfile::TempDir) -> Vec<PathBuf> {
+        match std::fs::read_dir(temp_dir.path().join("state/gcroots/auto")) {
+            Ok(entries) => entries
+                .map(|entry| std::fs::read_link(entry.unwrap().path()).unwrap())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn add_perm_root() {
+        let (mut store, temp_dir) = create_temp_store();
+        let path = store
+            .add_to_store_bytes("rooted", b"rooted", &AddToStoreOptions::default())
+            .unwrap();
+        let link = temp_dir.path().join("result");
+
+        let created = store.add_perm_root(&path, &link).unwrap();
+        assert_eq!(created, link);
+        assert_eq!(
+            std::fs::read_link(&link).unwrap(),
+            PathBuf::from(store.real_path(&path).unwrap())
+        );
+        assert!(auto_roots(&temp_dir).contains(&link));
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33")]
+    fn add_indirect_root() {
+        let (mut store, temp_dir) = create_temp_store();
+        let path = store
+            .add_to_store_bytes("rooted", b"rooted", &AddToStoreOptions::default())
+            .unwrap();
+        let link = temp_dir.path().join("link");
+        std::os::unix::fs::symlink(store.real_path(&path).unwrap(), &link).unwrap();
+
+        store.add_indirect_root(&link).unwrap();
+        assert!(auto_roots(&temp_dir).contains(&link));
+
+        drop(store);
+        drop(temp_dir);
+    }
+
     #[cfg(nix_at_least = "2.33")]
     fn create_multi_output_derivation_json() -> serde_json::Value {
         let system = current_system()
@@ -12,6 +12,8 @@ impl bindgen::callbacks::ParseCallbacks for StripNixPrefix {
 fn main() {
     // Tell cargo to invalidate the built crate whenever the wrapper changes
     println!("cargo:rerun-if-changed=include/nix-c-util.h");
+    println!("cargo:rerun-if-changed=include/nix_api_util_ext.h");
+    println!("cargo:rerun-if-changed=shim/nix_api_util_ext.cc");
     println!("cargo:rustc-link-lib=nixutil");
 
     // https://rust-lang.github.io/rust-bindgen/library-usage.html
@@ -33,6 +35,24 @@ fn main() {
     bindings
         .write_to_file(out_path.join("bindings.rs"))
         .expect("Couldn't write bindings!");
+
+    compile_shim();
+}
+
+/// Compiles the functions declared in include/nix_api_util_ext.h, which the pinned Nix lacks.
+fn compile_shim() {
+    let mut build = cc::Build::new();
+    build.cpp(true).std("c++23").include("include");
+    for lib in ["nix-util-c", "nix-util"] {
+        let lib = pkg_config::Config::new()
+            .cargo_metadata(false)
+            .probe(lib)
+            .unwrap();
+        build.includes(&lib.include_paths);
+    }
+    build
+        .file("shim/nix_api_util_ext.cc")
+        .compile("nixutilcext");
 }
 
 fn c_headers() -> Vec<String> {
@@ -1 +1,2 @@
 #include <nix_api_util.h>
+#include "nix_api_util_ext.h"
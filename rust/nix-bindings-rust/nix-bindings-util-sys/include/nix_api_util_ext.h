@@ -0,0 +1,44 @@
+#ifndef NIX_API_UTIL_EXT_H
+#define NIX_API_UTIL_EXT_H
+/**
+ * @file
+ * @brief Utility functions missing from the pinned Nix C API
+ *
+ * These are implemented in `shim/nix_api_util_ext.cc` against the Nix C++ API, and can be
+ * dropped once Nix exports them itself.
+ */
+
+#include <nix_api_util.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+// cffi start
+
+/**
+ * @brief Called whenever Nix checks for interrupts on the thread that installed it.
+ *
+ * @param[in] user_data the user data passed to nix_set_interrupt_callback
+ * @return nonzero to interrupt the current operation
+ */
+typedef int (*nix_interrupt_callback)(void * user_data);
+
+/**
+ * @brief Installs an interrupt check for the current thread.
+ *
+ * Nix consults the callback alongside the process-wide SIGINT flag, and throws
+ * `nix::Interrupted` from the operation in progress when it returns nonzero.
+ *
+ * @param[out] context Optional, stores error information
+ * @param[in] callback the check to install, or NULL to remove it
+ * @param[in] user_data passed to the callback
+ * @return NIX_OK on success
+ */
+nix_err nix_set_interrupt_callback(nix_c_context * context, nix_interrupt_callback callback, void * user_data);
+
+// cffi end
+#ifdef __cplusplus
+}
+#endif
+
+#endif // NIX_API_UTIL_EXT_H
@@ -0,0 +1,25 @@
+#include <nix_api_util.h>
+#include <nix_api_util_internal.h>
+
+#include "nix/util/signals.hh"
+
+#include "nix_api_util_ext.h"
+
+extern "C" {
+
+nix_err nix_set_interrupt_callback(nix_c_context * context, nix_interrupt_callback callback, void * user_data)
+{
+    if (context)
+        context->last_err_code = NIX_OK;
+    try {
+        using namespace nix;
+        using namespace nix::unix;
+        if (callback)
+            interruptCheck = [callback, user_data]() { return callback(user_data) != 0; };
+        else
+            interruptCheck = nullptr;
+    }
+    NIXC_CATCH_ERRS
+}
+
+} // extern "C"
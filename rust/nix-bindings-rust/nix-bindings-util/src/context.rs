@@ -1,6 +1,6 @@
-use anyhow::{bail, Result};
+use crate::error::NixError;
+use anyhow::Result;
 use nix_bindings_util_sys as raw;
-use std::ptr::null_mut;
 use std::ptr::NonNull;
 
 /// A context for error handling, when interacting directly with the generated bindings for the C API in [nix_bindings_util_sys].
@@ -38,15 +38,13 @@ impl Context {
 
     /// Check the error code and return an error if it's not `NIX_OK`.
     ///
+    /// The error is a [NixError]; use [anyhow::Error::downcast_ref] to get at it.
+    ///
     /// We recommend to use `check_call!` if possible.
     pub fn check_err(&self) -> Result<()> {
         let err = unsafe { raw::err_code(self.inner.as_ptr()) };
         if err != raw::err_NIX_OK {
-            // msgp is a borrowed pointer (pointing into the context), so we don't need to free it
-            let msgp = unsafe { raw::err_msg(null_mut(), self.inner.as_ptr(), null_mut()) };
-            // Turn the i8 pointer into a Rust string by copying
-            let msg: &str = unsafe { core::ffi::CStr::from_ptr(msgp).to_str()? };
-            bail!("{}", msg);
+            return Err(NixError::from_context(self.inner.as_ptr())?.into());
         }
         Ok(())
     }
@@ -133,6 +131,7 @@ pub use check_call_opt_key;
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::NixErrorKind;
 
     #[test]
     fn context_new_and_drop() {
@@ -156,4 +155,23 @@ mod tests {
         assert!(r.is_err());
         assert_eq!(r.unwrap_err().to_string(), "dummy error message");
     }
+
+    fn set_key_err(ctx_ptr: *mut raw::c_context) {
+        unsafe {
+            raw::set_err_msg(ctx_ptr, raw::err_NIX_ERR_KEY, c"no such key".as_ptr());
+        }
+    }
+
+    #[test]
+    fn check_call_nix_error() {
+        let r = check_call!(set_key_err(&mut Context::new()));
+        let err = r.unwrap_err();
+        let nix_err = err.downcast_ref::<NixError>().unwrap();
+        assert_eq!(nix_err.kind, NixErrorKind::Key);
+        assert_eq!(nix_err.code, raw::err_NIX_ERR_KEY);
+        assert_eq!(nix_err.message, "no such key");
+        assert_eq!(nix_err.name, None);
+        assert!(nix_err.trace.is_empty());
+        assert_eq!(nix_err.thrown, None);
+    }
 }
@@ -0,0 +1,323 @@
+//! Structured errors from the Nix C API.
+//!
+//! [Context::check_err](crate::context::Context::check_err) still returns an [anyhow::Error],
+//! but the error inside it is a [NixError], which can be recovered with [anyhow::Error::downcast_ref].
+//!
+//! ```
+//! # use nix_bindings_util::error::{NixError, NixErrorKind};
+//! fn is_throw(err: &anyhow::Error) -> bool {
+//!     err.downcast_ref::<NixError>()
+//!         .is_some_and(|e| e.kind == NixErrorKind::Throw)
+//! }
+//! ```
+
+use crate::context::Context;
+use crate::result_string_init;
+use crate::string_return::{callback_get_result_string, callback_get_result_string_data};
+use anyhow::Result;
+use nix_bindings_util_sys as raw;
+use std::fmt;
+
+/// What went wrong, as far as the C API lets us tell.
+///
+/// For `NIX_ERR_NIX_ERROR`, this is derived from the name of the C++ exception that Nix threw.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NixErrorKind {
+    /// `NIX_ERR_UNKNOWN`: an error that isn't a Nix error, such as a `std::exception`.
+    Unknown,
+    /// `NIX_ERR_OVERFLOW`: a buffer was too small.
+    Overflow,
+    /// `NIX_ERR_KEY`: a key or attribute wasn't found.
+    Key,
+    /// `builtins.throw`.
+    Throw,
+    /// `builtins.abort`.
+    Abort,
+    /// A failed `assert`.
+    Assertion,
+    /// A value had the wrong type.
+    Type,
+    /// A value depended on itself.
+    InfiniteRecursion,
+    /// The evaluator ran out of stack.
+    StackOverflow,
+    /// Any other evaluation error, including parse errors and undefined variables.
+    Eval,
+    /// A derivation failed to build.
+    Build,
+    /// The operation was interrupted.
+    Interrupted,
+    /// A Nix error we don't have a more specific kind for, such as most store errors.
+    Other,
+}
+
+impl NixErrorKind {
+    /// Classifies an error from its code and, for Nix errors, its exception name.
+    pub fn from_code(code: raw::err, name: Option<&str>) -> NixErrorKind {
+        match code {
+            raw::err_NIX_ERR_OVERFLOW => NixErrorKind::Overflow,
+            raw::err_NIX_ERR_KEY => NixErrorKind::Key,
+            raw::err_NIX_ERR_NIX_ERROR => match name.map(|n| n.trim_start_matches("nix::")) {
+                Some("ThrownError") => NixErrorKind::Throw,
+                Some("Abort") => NixErrorKind::Abort,
+                Some("AssertionError") => NixErrorKind::Assertion,
+                Some("TypeError") => NixErrorKind::Type,
+                Some("InfiniteRecursionError") => NixErrorKind::InfiniteRecursion,
+                Some("StackOverflowError") => NixErrorKind::StackOverflow,
+                Some(
+                    "EvalError"
+                    | "EvalBaseError"
+                    | "ParseError"
+                    | "UndefinedVarError"
+                    | "MissingArgumentError"
+                    | "RestrictedPathError"
+                    | "IFDError"
+                    | "InvalidPathError"
+                    | "CachedEvalError",
+                ) => NixErrorKind::Eval,
+                Some("BuildError") => NixErrorKind::Build,
+                Some("Interrupted") => NixErrorKind::Interrupted,
+                _ => NixErrorKind::Other,
+            },
+            _ => NixErrorKind::Unknown,
+        }
+    }
+}
+
+/// A source position in a trace frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Position {
+    /// The file, or a placeholder such as `«string»` for expressions that didn't come from one.
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// One `… while ...` frame of a Nix error trace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceFrame {
+    pub message: String,
+    pub position: Option<Position>,
+}
+
+/// An error returned by the Nix C API.
+///
+/// Displays as the full message that Nix produced, trace included.
+#[derive(Clone, Debug)]
+pub struct NixError {
+    pub kind: NixErrorKind,
+    /// The raw `nix_err` code.
+    pub code: raw::err,
+    /// The name of the C++ exception, e.g. `nix::ThrownError`, if this is a Nix error.
+    pub name: Option<String>,
+    /// The full error message, including the trace.
+    pub message: String,
+    /// The message without the trace, if this is a Nix error.
+    pub info: Option<String>,
+    /// The trace frames, outermost first, as far as Nix printed them.
+    pub trace: Vec<TraceFrame>,
+    /// The string passed to `builtins.throw`, if this is a [NixErrorKind::Throw].
+    pub thrown: Option<String>,
+}
+
+impl NixError {
+    /// Reads the error out of a context whose error code isn't `NIX_OK`.
+    pub(crate) fn from_context(ctx: *const raw::c_context) -> Result<NixError> {
+        let code = unsafe { raw::err_code(ctx) };
+        // msgp is a borrowed pointer (pointing into the context), so we don't need to free it
+        let msgp = unsafe { raw::err_msg(std::ptr::null_mut(), ctx, std::ptr::null_mut()) };
+        // Turn the i8 pointer into a Rust string by copying
+        let message = unsafe { core::ffi::CStr::from_ptr(msgp).to_str()? }.to_string();
+
+        let (name, info) = if code == raw::err_NIX_ERR_NIX_ERROR {
+            (
+                read_string(ctx, raw::err_name).ok(),
+                read_string(ctx, raw::err_info_msg).ok(),
+            )
+        } else {
+            (None, None)
+        };
+
+        let kind = NixErrorKind::from_code(code, name.as_deref());
+        let thrown = if kind == NixErrorKind::Throw {
+            info.as_deref().map(strip_ansi)
+        } else {
+            None
+        };
+
+        Ok(NixError {
+            kind,
+            code,
+            name,
+            trace: parse_trace(&message),
+            message,
+            info,
+            thrown,
+        })
+    }
+}
+
+impl fmt::Display for NixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for NixError {}
+
+type ErrStringFn = unsafe extern "C" fn(
+    *mut raw::c_context,
+    *const raw::c_context,
+    raw::get_string_callback,
+    *mut std::os::raw::c_void,
+) -> raw::err;
+
+/// Reads a string attribute of the error in `read_ctx`, using a scratch context for our own errors.
+fn read_string(read_ctx: *const raw::c_context, f: ErrStringFn) -> Result<String> {
+    let mut ctx = Context::new();
+    let mut r = result_string_init!();
+    unsafe {
+        f(
+            ctx.ptr(),
+            read_ctx,
+            Some(callback_get_result_string),
+            callback_get_result_string_data(&mut r),
+        )
+    };
+    ctx.check_err()?;
+    r
+}
+
+/// Removes the ANSI escapes that Nix puts into its error messages.
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // CSI sequences end with a byte in @..~.
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Parses a position line, e.g. `at /foo/bar.nix:12:5:`.
+fn parse_position(s: &str) -> Option<Position> {
+    let rest = s.strip_prefix("at ")?.strip_suffix(':')?;
+    let mut parts = rest.rsplitn(3, ':');
+    let column = parts.next()?.parse().ok()?;
+    let line = parts.next()?.parse().ok()?;
+    let file = parts.next()?.to_string();
+    Some(Position { file, line, column })
+}
+
+/// Parses the trace frames out of a formatted Nix error message.
+///
+/// Nix prints each frame as a `… message` line, optionally followed by an `at file:line:column:` line.
+fn parse_trace(message: &str) -> Vec<TraceFrame> {
+    let message = strip_ansi(message);
+    let mut frames: Vec<TraceFrame> = Vec::new();
+    let mut last_was_frame = false;
+    for line in message.lines().map(str::trim) {
+        if let Some(frame) = line.strip_prefix('…') {
+            frames.push(TraceFrame {
+                message: frame.trim().to_string(),
+                position: None,
+            });
+            last_was_frame = true;
+        } else {
+            if last_was_frame {
+                frames.last_mut().unwrap().position = parse_position(line);
+            }
+            last_was_frame = false;
+        }
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &str = "\x1b[31;1merror:\x1b[0m
+       … while calling the '\x1b[35;1mthrow\x1b[0m' builtin
+         at \x1b[35;1m/src/app.nix:12:5\x1b[0m:
+           11|   route = req:
+           12|     throw \"nope\";
+             |     \x1b[31;1m^\x1b[0m
+
+       … while evaluating the attribute 'body'
+
+       \x1b[31;1merror:\x1b[0m nope";
+
+    #[test]
+    fn error_kind_from_code() {
+        assert_eq!(
+            NixErrorKind::from_code(raw::err_NIX_ERR_NIX_ERROR, Some("nix::ThrownError")),
+            NixErrorKind::Throw
+        );
+        assert_eq!(
+            NixErrorKind::from_code(raw::err_NIX_ERR_NIX_ERROR, Some("nix::UndefinedVarError")),
+            NixErrorKind::Eval
+        );
+        assert_eq!(
+            NixErrorKind::from_code(raw::err_NIX_ERR_NIX_ERROR, Some("nix::SubstituteGone")),
+            NixErrorKind::Other
+        );
+        assert_eq!(
+            NixErrorKind::from_code(raw::err_NIX_ERR_KEY, None),
+            NixErrorKind::Key
+        );
+        assert_eq!(
+            NixErrorKind::from_code(raw::err_NIX_ERR_UNKNOWN, None),
+            NixErrorKind::Unknown
+        );
+    }
+
+    #[test]
+    fn error_parse_trace() {
+        let trace = parse_trace(MESSAGE);
+        assert_eq!(
+            trace,
+            vec![
+                TraceFrame {
+                    message: "while calling the 'throw' builtin".to_string(),
+                    position: Some(Position {
+                        file: "/src/app.nix".to_string(),
+                        line: 12,
+                        column: 5,
+                    }),
+                },
+                TraceFrame {
+                    message: "while evaluating the attribute 'body'".to_string(),
+                    position: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn error_parse_trace_none() {
+        assert!(parse_trace("dummy error message").is_empty());
+    }
+
+    #[test]
+    fn error_strip_ansi() {
+        assert_eq!(strip_ansi("\x1b[31;1merror:\x1b[0m nope"), "error: nope");
+    }
+}
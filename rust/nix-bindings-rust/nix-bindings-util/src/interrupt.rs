@@ -0,0 +1,168 @@
+//! Cooperative interruption of Nix operations.
+//!
+//! Nix periodically calls `checkInterrupt()` while evaluating and building. Besides the
+//! process-wide `SIGINT` flag, that check consults a thread-local callback, which
+//! `nix_set_interrupt_callback` installs. This module installs one such callback per thread and
+//! points it at whichever [`InterruptHandle`] is currently [entered](InterruptHandle::enter) on
+//! that thread, so that another thread can abort a long-running operation without affecting
+//! unrelated work elsewhere in the process.
+
+use anyhow::Result;
+use nix_bindings_util_sys as raw;
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::os::raw::c_int;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::{check_call, context};
+
+thread_local! {
+    /// Whether [interrupt_check] has been registered with Nix on this thread.
+    static INSTALLED: Cell<bool> = const { Cell::new(false) };
+
+    /// The flag of the innermost entered [InterruptHandle] on this thread, or null.
+    static CURRENT: Cell<*const AtomicBool> = const { Cell::new(std::ptr::null()) };
+}
+
+/// Called by Nix on the current thread whenever it checks for interrupts.
+unsafe extern "C" fn interrupt_check(_user_data: *mut c_void) -> c_int {
+    CURRENT.with(|current| {
+        let flag = current.get();
+        if flag.is_null() {
+            0
+        } else {
+            // SAFETY: the pointer is kept alive by the InterruptScope that set it.
+            unsafe { (*flag).load(Ordering::Acquire) as c_int }
+        }
+    })
+}
+
+fn install_on_current_thread() -> Result<()> {
+    if INSTALLED.get() {
+        return Ok(());
+    }
+    let mut ctx = context::Context::new();
+    unsafe {
+        check_call!(raw::set_interrupt_callback(
+            &mut ctx,
+            Some(interrupt_check),
+            null_mut()
+        ))?;
+    }
+    INSTALLED.set(true);
+    Ok(())
+}
+
+/// A handle that can interrupt Nix operations from any thread.
+///
+/// Cloning the handle shares the underlying flag, so the clone can be moved to a watchdog or
+/// timer thread while the original stays with the evaluating code.
+///
+/// Once [interrupted](InterruptHandle::interrupt), Nix operations running in a scope entered with
+/// this handle fail with an "interrupted" error at the next interrupt check. The handle stays
+/// interrupted until it is [reset](InterruptHandle::reset).
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests that operations running under this handle stop as soon as possible.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Release);
+    }
+
+    /// Whether [interrupt](InterruptHandle::interrupt) has been called since the last reset.
+    pub fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::Acquire)
+    }
+
+    /// Clears the interrupted state, so that the handle can be used again.
+    pub fn reset(&self) {
+        self.flag.store(false, Ordering::Release);
+    }
+
+    /// Makes this handle govern Nix operations on the current thread until the returned scope is dropped.
+    ///
+    /// Scopes nest: dropping a scope restores the handle that was entered before it, which matters
+    /// when Rust primops re-enter the evaluator.
+    pub fn enter(&self) -> Result<InterruptScope> {
+        install_on_current_thread()?;
+        let flag = self.flag.clone();
+        let previous = CURRENT.replace(Arc::as_ptr(&flag));
+        Ok(InterruptScope { flag, previous })
+    }
+}
+
+/// Guard returned by [InterruptHandle::enter].
+pub struct InterruptScope {
+    flag: Arc<AtomicBool>,
+    previous: *const AtomicBool,
+}
+
+impl InterruptScope {
+    /// Whether the handle for this scope has been interrupted.
+    pub fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for InterruptScope {
+    fn drop(&mut self) {
+        CURRENT.set(self.previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ctor::ctor]
+    fn setup() {
+        let mut ctx = context::Context::new();
+        unsafe {
+            check_call!(nix_bindings_util_sys::libutil_init(&mut ctx)).unwrap();
+        }
+    }
+
+    #[test]
+    fn interrupt_check_follows_scopes() {
+        let outer = InterruptHandle::new();
+        let inner = InterruptHandle::new();
+        assert_eq!(unsafe { interrupt_check(null_mut()) }, 0);
+
+        let outer_scope = outer.enter().unwrap();
+        outer.interrupt();
+        assert_eq!(unsafe { interrupt_check(null_mut()) }, 1);
+
+        {
+            let _inner_scope = inner.enter().unwrap();
+            assert_eq!(unsafe { interrupt_check(null_mut()) }, 0);
+        }
+
+        assert!(outer_scope.is_interrupted());
+        assert_eq!(unsafe { interrupt_check(null_mut()) }, 1);
+        outer.reset();
+        assert_eq!(unsafe { interrupt_check(null_mut()) }, 0);
+
+        drop(outer_scope);
+        outer.interrupt();
+        assert_eq!(unsafe { interrupt_check(null_mut()) }, 0);
+    }
+
+    #[test]
+    fn interrupt_from_other_thread() {
+        let handle = InterruptHandle::new();
+        let remote = handle.clone();
+        std::thread::spawn(move || remote.interrupt())
+            .join()
+            .unwrap();
+        assert!(handle.is_interrupted());
+    }
+}
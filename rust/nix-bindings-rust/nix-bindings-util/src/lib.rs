@@ -1,4 +1,6 @@
 pub mod context;
+pub mod error;
+pub mod interrupt;
 pub mod settings;
 #[macro_use]
 pub mod string_return;
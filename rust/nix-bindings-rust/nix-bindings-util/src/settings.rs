@@ -1,6 +1,9 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use nix_bindings_util_sys as raw;
+use std::collections::HashMap;
+use std::marker::PhantomData;
 use std::sync::Mutex;
+use std::thread::ThreadId;
 
 use crate::{
     check_call, context, result_string_init,
@@ -11,6 +14,10 @@ use crate::{
 // See the documentation on `set()` for important thread safety information.
 static SETTINGS_MUTEX: Mutex<()> = Mutex::new(());
 
+/// The settings that [`scoped()`] guards are overriding, with the thread that owns them and
+/// how many guards it has for each.
+static SCOPES: Mutex<Option<HashMap<String, (ThreadId, usize)>>> = Mutex::new(None);
+
 /// Set a Nix setting.
 ///
 /// # Thread Safety
@@ -64,6 +71,208 @@ pub fn get(key: &str) -> Result<String> {
     r
 }
 
+/// Override a Nix setting until the returned guard is dropped, which restores the previous value.
+///
+/// # Thread Safety
+///
+/// The override is global, like [`set()`], so every thread sees it while the guard lives.
+/// A thread may nest overrides of the same setting, which restore correctly when dropped in
+/// reverse order. Overriding a setting that another thread is overriding fails, since
+/// whichever guard was dropped last would restore the wrong value.
+pub fn scoped(key: &str, value: &str) -> Result<Scoped> {
+    let thread = std::thread::current().id();
+    {
+        let mut scopes = SCOPES.lock().unwrap();
+        let (owner, depth) = scopes
+            .get_or_insert_with(HashMap::new)
+            .entry(key.to_string())
+            .or_insert((thread, 0));
+        if *owner != thread {
+            bail!(
+                "Nix setting {} is already overridden by another thread",
+                key
+            );
+        }
+        *depth += 1;
+    }
+
+    // If overriding fails, dropping the guard gives the setting up again.
+    let mut guard = Scoped {
+        key: key.to_string(),
+        previous: None,
+        _not_send: PhantomData,
+    };
+    let previous = get(key)?;
+    set(key, value)?;
+    guard.previous = Some(previous);
+    Ok(guard)
+}
+
+/// Restores a setting that [`scoped()`] or [`Setting::scoped()`] overrode, when dropped.
+///
+/// Guards stay on the thread that made them, so that nested ones are dropped there.
+#[must_use = "the setting is restored as soon as the guard is dropped"]
+pub struct Scoped {
+    key: String,
+
+    /// The value to restore, unless overriding the setting failed.
+    previous: Option<String>,
+
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for Scoped {
+    fn drop(&mut self) {
+        if let Some(previous) = &self.previous {
+            if let Err(err) = set(&self.key, previous) {
+                log::warn!("Couldn't restore Nix setting {}: {}", self.key, err);
+            }
+        }
+
+        let mut scopes = SCOPES.lock().unwrap();
+        if let Some(scopes) = scopes.as_mut() {
+            if let Some((_, depth)) = scopes.get_mut(&self.key) {
+                *depth -= 1;
+                if *depth == 0 {
+                    scopes.remove(&self.key);
+                }
+            }
+        }
+    }
+}
+
+/// A type that a Nix setting's value can be converted to and from.
+pub trait SettingValue: Sized {
+    /// Converts the value to how Nix spells it.
+    fn to_setting(&self) -> String;
+    /// Parses the value from how Nix spells it.
+    fn from_setting(value: &str) -> Result<Self>;
+}
+
+impl SettingValue for String {
+    fn to_setting(&self) -> String {
+        self.clone()
+    }
+    fn from_setting(value: &str) -> Result<Self> {
+        Ok(value.to_string())
+    }
+}
+
+impl SettingValue for bool {
+    fn to_setting(&self) -> String {
+        self.to_string()
+    }
+    fn from_setting(value: &str) -> Result<Self> {
+        match value {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => bail!("expected a Boolean setting, but got `{}`", value),
+        }
+    }
+}
+
+/// Lists, like `experimental-features`, are separated by whitespace.
+impl SettingValue for Vec<String> {
+    fn to_setting(&self) -> String {
+        self.join(" ")
+    }
+    fn from_setting(value: &str) -> Result<Self> {
+        Ok(value.split_whitespace().map(str::to_string).collect())
+    }
+}
+
+macro_rules! setting_value_int {
+    ($($t:ty),*) => {
+        $(
+            impl SettingValue for $t {
+                fn to_setting(&self) -> String {
+                    self.to_string()
+                }
+                fn from_setting(value: &str) -> Result<Self> {
+                    value.parse().map_err(|err| {
+                        anyhow::format_err!("expected a number setting, but got `{}`: {}", value, err)
+                    })
+                }
+            }
+        )*
+    };
+}
+setting_value_int!(u32, u64, usize, i64);
+
+/// A Nix setting, by name, with the type of its value.
+///
+/// The constants in this module cover settings the bindings' users commonly change. Others can
+/// be made with [`Setting::new`].
+pub struct Setting<T> {
+    name: &'static str,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Setting<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Setting<T> {}
+
+impl<T: SettingValue> Setting<T> {
+    /// Names a setting whose values are `T`s.
+    pub const fn new(name: &'static str) -> Self {
+        Setting {
+            name,
+            value: PhantomData,
+        }
+    }
+
+    /// The setting's name, like `substitute`.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Get the setting. See [`get()`].
+    pub fn get(&self) -> Result<T> {
+        T::from_setting(&get(self.name)?)
+    }
+
+    /// Set the setting. See [`set()`].
+    pub fn set(&self, value: &T) -> Result<()> {
+        set(self.name, &value.to_setting())
+    }
+
+    /// Override the setting until the returned guard is dropped. See [`scoped()`].
+    pub fn scoped(&self, value: &T) -> Result<Scoped> {
+        scoped(self.name, &value.to_setting())
+    }
+}
+
+/// Whether to use substituters.
+pub const SUBSTITUTE: Setting<bool> = Setting::new("substitute");
+/// Which experimental features are enabled.
+pub const EXPERIMENTAL_FEATURES: Setting<Vec<String>> = Setting::new("experimental-features");
+/// The system type to build for, like `x86_64-linux`.
+pub const SYSTEM: Setting<String> = Setting::new("system");
+/// Whether to keep building after a derivation fails.
+pub const KEEP_GOING: Setting<bool> = Setting::new("keep-going");
+/// The longest a build may run, in seconds, where 0 is no limit.
+pub const TIMEOUT: Setting<u64> = Setting::new("timeout");
+/// The longest a build may go without output, in seconds, where 0 is no limit.
+pub const MAX_SILENT_TIME: Setting<u64> = Setting::new("max-silent-time");
+/// Whether to print stack traces for evaluation errors.
+pub const SHOW_TRACE: Setting<bool> = Setting::new("show-trace");
+/// Whether `builtins.trace` output is printed with more detail.
+pub const TRACE_VERBOSE: Setting<bool> = Setting::new("trace-verbose");
+/// Whether evaluation is pure. An evaluator setting, best set per state through
+/// `EvalStateBuilder::setting`.
+pub const PURE_EVAL: Setting<bool> = Setting::new("pure-eval");
+/// How many threads evaluate in parallel. An evaluator setting, best set per state.
+pub const EVAL_CORES: Setting<u32> = Setting::new("eval-cores");
+/// How deep function calls may nest. An evaluator setting, best set per state.
+pub const MAX_CALL_DEPTH: Setting<u32> = Setting::new("max-call-depth");
+/// Whether evaluation may build derivations to import from. An evaluator setting, best set per state.
+pub const ALLOW_IMPORT_FROM_DERIVATION: Setting<bool> =
+    Setting::new("allow-import-from-derivation");
+
 #[cfg(test)]
 mod tests {
     use crate::check_call;
@@ -78,8 +287,13 @@ mod tests {
         }
     }
 
+    /// The tests share a setting, so they take turns.
+    static TEST_SETTING: Mutex<()> = Mutex::new(());
+
     #[test]
     fn set_get() {
+        let _lock = TEST_SETTING.lock().unwrap();
+
         // Something that shouldn't matter if it's a different value temporarily
         let key = "json-log-path";
 
@@ -101,4 +315,66 @@ mod tests {
 
         assert_eq!(res, new_value);
     }
+
+    #[test]
+    fn scoped_restores() {
+        let _lock = TEST_SETTING.lock().unwrap();
+        let setting = Setting::<String>::new("json-log-path");
+        let old_value = setting.get().unwrap();
+
+        let outer_value = "/a/path/for/testing/scoped/settings".to_string();
+        let inner_value = "/another/path/for/testing/scoped/settings".to_string();
+        {
+            let _outer = setting.scoped(&outer_value).unwrap();
+            assert_eq!(setting.get().unwrap(), outer_value);
+            {
+                let _inner = setting.scoped(&inner_value).unwrap();
+                assert_eq!(setting.get().unwrap(), inner_value);
+            }
+            assert_eq!(setting.get().unwrap(), outer_value);
+        }
+        assert_eq!(setting.get().unwrap(), old_value);
+    }
+
+    #[test]
+    fn scoped_refuses_other_threads() {
+        let _lock = TEST_SETTING.lock().unwrap();
+        let setting = Setting::<String>::new("json-log-path");
+        let old_value = setting.get().unwrap();
+
+        let value = "/a/path/for/testing/scoped/settings".to_string();
+        {
+            let _scope = setting.scoped(&value).unwrap();
+            std::thread::scope(|s| {
+                s.spawn(|| assert!(setting.scoped(&old_value).is_err()));
+            });
+            assert_eq!(setting.get().unwrap(), value);
+        }
+        assert_eq!(setting.get().unwrap(), old_value);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _scope = setting.scoped(&value).unwrap();
+                assert_eq!(setting.get().unwrap(), value);
+            });
+        });
+        assert_eq!(setting.get().unwrap(), old_value);
+    }
+
+    #[test]
+    fn setting_values() {
+        assert!(bool::from_setting("true").unwrap());
+        assert!(!bool::from_setting("false").unwrap());
+        assert!(bool::from_setting("yes").is_err());
+        assert_eq!(u32::from_setting("8").unwrap(), 8);
+        assert!(u32::from_setting("auto").is_err());
+        assert_eq!(
+            Vec::<String>::from_setting(" nix-command  flakes\n").unwrap(),
+            ["nix-command", "flakes"]
+        );
+        assert_eq!(
+            vec!["nix-command".to_string(), "flakes".to_string()].to_setting(),
+            "nix-command flakes"
+        );
+    }
 }